@@ -1,11 +1,13 @@
 pub mod memory_buffer;
 pub mod source_location;
 pub mod maybe_char;
+pub mod maybe_char_map;
 pub mod source_manager;
 
 pub use memory_buffer::MemoryBuffer;
 pub use source_location::{SourceLocation, SourceRange};
 pub use maybe_char::{MaybeChar, MaybeCharEnumView};
+pub use maybe_char_map::MaybeCharMap;
 pub use source_manager::{SourceManager, FileId, FileEntry};
 
 pub mod prelude {
@@ -12,7 +12,7 @@ use core::fmt;
 ///
 /// However, Rust (1.89.0) is unable to leverage the spare bits in [char] to make it as compact as a 4-byte integer.
 ///
-/// Internally encodes either a Unicode scalar value or a byte with a marker at MSB.
+/// Internally encodes either a Unicode scalar value or a byte with a marker at bit 28 (see [MaybeChar::to_u32]).
 #[repr(transparent)]
 #[derive(Copy, Clone, Eq, PartialEq, Hash)]
 pub struct MaybeChar(u32);
@@ -39,6 +39,17 @@ impl MaybeChar {
         MaybeChar(Self::NON_CHAR_BYTE_TAG | (b as u32))
     }
 
+    /// Creates a [MaybeChar] for a byte: an ASCII byte is the character itself, any other byte is a non-character
+    /// byte since it isn't a character by itself (e.g., a byte of a UTF-8 sequence written in caret notation).
+    #[inline]
+    pub fn from_u8(b: u8) -> Self {
+        if b.is_ascii() {
+            Self::from_char(b as char)
+        } else {
+            Self::from_non_char_byte(b)
+        }
+    }
+
     #[inline]
     pub fn is_char(self) -> bool {
         (self.0 & Self::NON_CHAR_BYTE_TAG) != Self::NON_CHAR_BYTE_TAG
@@ -59,6 +70,48 @@ impl MaybeChar {
         }
     }
 
+    /// Returns whether this is an ASCII character (below U+0080). Non-char bytes are never ASCII.
+    #[inline]
+    pub fn is_ascii(self) -> bool {
+        self.0 < 0x80
+    }
+
+    /// Returns the byte of an ASCII character, or `None` for non-ASCII characters and non-char bytes
+    #[inline]
+    pub fn as_ascii_byte(self) -> Option<u8> {
+        self.is_ascii().then_some(self.0 as u8)
+    }
+
+    /// Returns the internal encoding: the Unicode scalar value for characters or the byte with a marker at bit 28 for
+    /// non-char bytes. Useful for ordering and for serialization with [MaybeChar::from_u32].
+    ///
+    /// The encoding is stable: a character is its scalar value (at most `0x10FFFF`), and a non-char byte `b` is
+    /// `0x1000_0000 | b`, i.e., bit 28 is set and bits 8 to 27 and 29 to 31 are clear.
+    #[inline]
+    pub fn to_u32(self) -> u32 {
+        self.0
+    }
+
+    /// Creates a [MaybeChar] from the encoding returned by [MaybeChar::to_u32]. Returns `None` if `value` isn't a
+    /// valid encoding.
+    pub fn from_u32(value: u32) -> Option<Self> {
+        if value & Self::NON_CHAR_BYTE_TAG == Self::NON_CHAR_BYTE_TAG {
+            (value & !Self::NON_CHAR_BYTE_MASK == Self::NON_CHAR_BYTE_TAG).then_some(MaybeChar(value))
+        } else {
+            char::from_u32(value).map(Self::from_char)
+        }
+    }
+
+    /// Same as [MaybeChar::from_u32] but for values known to be valid encodings, e.g., trusted data serialized with
+    /// [MaybeChar::to_u32].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` isn't a valid encoding.
+    pub fn from_u32_raw(value: u32) -> Self {
+        Self::from_u32(value).unwrap_or_else(|| panic!("invalid MaybeChar encoding {value:#x}"))
+    }
+
     /// Returns if stored a valid Unicode character
     #[inline]
     pub fn as_char(self) -> Option<char> {
@@ -83,6 +136,23 @@ impl MaybeChar {
             }
         }
     }
+
+    /// Decodes the character at the start of `bytes` as UTF-8 and returns it with its length in bytes. This is the
+    /// inverse of [MaybeChar::encode_utf8]: a byte that doesn't start a valid UTF-8 sequence (e.g., a continuation byte
+    /// or a truncated sequence) is returned as a [MaybeChar::NonCharByte] of length 1. Returns `None` if `bytes` is
+    /// empty.
+    ///
+    /// Note that an ASCII non-char byte (e.g., [MaybeChar::from_non_char_byte]`(b'A')`) encodes to a valid character,
+    /// so it decodes to the character rather than the non-char byte.
+    pub fn decode_utf8(bytes: &[u8]) -> Option<(Self, usize)> {
+        let first = *bytes.first()?;
+        // A UTF-8 sequence is at most 4 bytes long; limit the validation to them
+        let chunk = bytes[..bytes.len().min(4)].utf8_chunks().next()?;
+        match chunk.valid().chars().next() {
+            Some(c) => Some((Self::from_char(c), c.len_utf8())),
+            None => Some((Self::from_non_char_byte(first), 1)),
+        }
+    }
 }
 
 impl fmt::Debug for MaybeChar {
@@ -111,6 +181,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_maybe_char_from_u8() {
+        assert_eq!(MaybeChar::from_u8(b'a'), MaybeChar::from_char('a'));
+        assert_eq!(MaybeChar::from_u8(0x7F), MaybeChar::from_char('\u{7F}'));
+        assert_eq!(MaybeChar::from_u8(0x80), MaybeChar::from_non_char_byte(0x80));
+        assert_eq!(MaybeChar::from_u8(0xFF).enum_view(), MaybeCharEnumView::NonCharByte(0xFF));
+    }
+
     #[test]
     fn test_maybe_char_from_non_char_byte() {
         let maybe_char = MaybeChar::from_non_char_byte(0xFF);
@@ -124,6 +202,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_maybe_char_is_ascii() {
+        let ascii = MaybeChar::from_char('a');
+        assert!(ascii.is_ascii());
+        assert_eq!(ascii.as_ascii_byte(), Some(b'a'));
+        assert_eq!(MaybeChar::from_char('\u{7f}').as_ascii_byte(), Some(0x7f));
+
+        let non_ascii = MaybeChar::from_char('α');
+        assert!(!non_ascii.is_ascii());
+        assert_eq!(non_ascii.as_ascii_byte(), None);
+
+        let non_char_byte = MaybeChar::from_non_char_byte(0x41);
+        assert!(!non_char_byte.is_ascii());
+        assert_eq!(non_char_byte.as_ascii_byte(), None);
+    }
+
+    #[test]
+    fn test_maybe_char_u32_round_trip() {
+        for maybe_char in [MaybeChar::from_char('a'), MaybeChar::from_char('🚀'), MaybeChar::from_non_char_byte(0x80)] {
+            assert_eq!(MaybeChar::from_u32(maybe_char.to_u32()), Some(maybe_char));
+            assert_eq!(MaybeChar::from_u32_raw(maybe_char.to_u32()), maybe_char);
+        }
+        assert_eq!(MaybeChar::from_char('é').to_u32(), 0xE9);
+        assert_eq!(MaybeChar::from_non_char_byte(0xE9).to_u32(), 0x1000_00E9);
+
+        // Surrogates and tagged values with more than a byte aren't valid
+        assert_eq!(MaybeChar::from_u32(0xD800), None);
+        assert_eq!(MaybeChar::from_u32(MaybeChar::from_non_char_byte(0x80).to_u32() | 0x100), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid MaybeChar encoding 0xd800")]
+    fn test_maybe_char_from_u32_raw_invalid() {
+        MaybeChar::from_u32_raw(0xD800);
+    }
+
+    #[test]
+    fn test_maybe_char_decode_utf8() {
+        assert_eq!(MaybeChar::decode_utf8(b""), None);
+        assert_eq!(MaybeChar::decode_utf8(b"ab"), Some((MaybeChar::from_char('a'), 1)));
+        assert_eq!(MaybeChar::decode_utf8("αb".as_bytes()), Some((MaybeChar::from_char('α'), 2)));
+        assert_eq!(MaybeChar::decode_utf8("中".as_bytes()), Some((MaybeChar::from_char('中'), 3)));
+        assert_eq!(MaybeChar::decode_utf8("🚀x".as_bytes()), Some((MaybeChar::from_char('🚀'), 4)));
+
+        // Continuation bytes, bytes that never appear in UTF-8 and truncated sequences are single non-char bytes
+        assert_eq!(MaybeChar::decode_utf8(b"\x80a"), Some((MaybeChar::from_non_char_byte(0x80), 1)));
+        assert_eq!(MaybeChar::decode_utf8(b"\xFF"), Some((MaybeChar::from_non_char_byte(0xFF), 1)));
+        assert_eq!(MaybeChar::decode_utf8(b"\xCEb"), Some((MaybeChar::from_non_char_byte(0xCE), 1)));
+        assert_eq!(MaybeChar::decode_utf8(b"\xF0\x9F\x9A"), Some((MaybeChar::from_non_char_byte(0xF0), 1)));
+    }
+
+    #[test]
+    fn test_maybe_char_encode_decode_round_trip() {
+        let mut buffer = [0u8; 4];
+        for maybe_char in [
+            MaybeChar::from_char('a'),
+            MaybeChar::from_char('\0'),
+            MaybeChar::from_char('€'),
+            MaybeChar::from_char('\u{10FFFF}'),
+            MaybeChar::from_non_char_byte(0x80),
+            MaybeChar::from_non_char_byte(0xFF),
+        ] {
+            let encoded = maybe_char.encode_utf8(&mut buffer);
+            assert_eq!(MaybeChar::decode_utf8(encoded), Some((maybe_char, encoded.len())));
+        }
+    }
+
     #[test]
     fn test_maybe_char_unicode_chars() {
         // Test various Unicode characters
@@ -220,6 +365,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_maybe_char_copy_clone() {
         let original = MaybeChar::from_char('Z');
         let copied = original;
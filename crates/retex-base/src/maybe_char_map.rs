@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use crate::MaybeChar;
+
+/// Maps [MaybeChar] to a small value (e.g., category codes and case codes). Lookups of ASCII characters, which
+/// dominate typical input, are served from an array; all other keys go to a [HashMap]. Keys that have never been set
+/// map to the default value given at construction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaybeCharMap<V: Copy> {
+    ascii: [V; 128],
+    overflow: HashMap<MaybeChar, V>,
+    default: V,
+}
+
+impl<V: Copy> MaybeCharMap<V> {
+    pub fn new(default: V) -> Self {
+        Self {
+            ascii: [default; 128],
+            overflow: HashMap::new(),
+            default,
+        }
+    }
+
+    #[inline]
+    fn ascii_index(maybe_char: MaybeChar) -> Option<usize> {
+        maybe_char.as_ascii_byte().map(usize::from)
+    }
+
+    #[inline]
+    pub fn get(&self, maybe_char: MaybeChar) -> V {
+        match Self::ascii_index(maybe_char) {
+            Some(index) => self.ascii[index],
+            None => self.overflow.get(&maybe_char).copied().unwrap_or(self.default),
+        }
+    }
+
+    pub fn set(&mut self, maybe_char: MaybeChar, value: V) {
+        match Self::ascii_index(maybe_char) {
+            Some(index) => self.ascii[index] = value,
+            None => {
+                self.overflow.insert(maybe_char, value);
+            },
+        }
+    }
+
+    /// Maps every key to `value`, which also becomes the default value
+    pub fn fill(&mut self, value: V) {
+        self.ascii = [value; 128];
+        self.overflow.clear();
+        self.default = value;
+    }
+
+    /// Iterates over every ASCII key and every non-ASCII key that has been set, with their values
+    pub fn iter(&self) -> impl Iterator<Item = (MaybeChar, V)> + '_ {
+        let ascii = self.ascii.iter().enumerate()
+            .map(|(index, value)| (MaybeChar::from_char(index as u8 as char), *value));
+        ascii.chain(self.overflow.iter().map(|(maybe_char, value)| (*maybe_char, *value)))
+    }
+
+    /// Returns the value of keys that have never been set
+    pub fn default_value(&self) -> V {
+        self.default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maybe_char_map_default() {
+        let map = MaybeCharMap::new(7u8);
+
+        assert_eq!(map.default_value(), 7);
+        assert_eq!(map.get(MaybeChar::from_char('a')), 7);
+        assert_eq!(map.get(MaybeChar::from_char('\u{7f}')), 7);
+        assert_eq!(map.get(MaybeChar::from_char('α')), 7);
+        assert_eq!(map.get(MaybeChar::from_non_char_byte(0x41)), 7);
+    }
+
+    #[test]
+    fn test_maybe_char_map_ascii() {
+        let mut map = MaybeCharMap::new(0u8);
+        map.set(MaybeChar::from_char('\0'), 1);
+        map.set(MaybeChar::from_char('a'), 2);
+        map.set(MaybeChar::from_char('\u{7f}'), 3);
+
+        assert_eq!(map.get(MaybeChar::from_char('\0')), 1);
+        assert_eq!(map.get(MaybeChar::from_char('a')), 2);
+        assert_eq!(map.get(MaybeChar::from_char('\u{7f}')), 3);
+        assert_eq!(map.get(MaybeChar::from_char('b')), 0);
+
+        map.set(MaybeChar::from_char('a'), 4);
+        assert_eq!(map.get(MaybeChar::from_char('a')), 4);
+    }
+
+    #[test]
+    fn test_maybe_char_map_non_ascii() {
+        let mut map = MaybeCharMap::new(0u8);
+        map.set(MaybeChar::from_char('α'), 1);
+        map.set(MaybeChar::from_char('🚀'), 2);
+        map.set(MaybeChar::from_non_char_byte(0x61), 3);
+
+        assert_eq!(map.get(MaybeChar::from_char('α')), 1);
+        assert_eq!(map.get(MaybeChar::from_char('🚀')), 2);
+        assert_eq!(map.get(MaybeChar::from_non_char_byte(0x61)), 3);
+        assert_eq!(map.get(MaybeChar::from_char('β')), 0);
+
+        // A non-char byte doesn't alias the ASCII character with the same value
+        assert_eq!(map.get(MaybeChar::from_char('a')), 0);
+    }
+
+    #[test]
+    fn test_maybe_char_map_fill() {
+        let mut map = MaybeCharMap::new(0u8);
+        map.set(MaybeChar::from_char('a'), 1);
+        map.set(MaybeChar::from_char('α'), 2);
+
+        map.fill(3);
+        assert_eq!(map.get(MaybeChar::from_char('a')), 3);
+        assert_eq!(map.get(MaybeChar::from_char('α')), 3);
+        assert_eq!(map.get(MaybeChar::from_non_char_byte(0xFF)), 3);
+        assert_eq!(map.default_value(), 3);
+    }
+
+    #[test]
+    fn test_maybe_char_map_iter() {
+        let mut map = MaybeCharMap::new(0u8);
+        map.set(MaybeChar::from_char('a'), 1);
+        map.set(MaybeChar::from_char('α'), 2);
+
+        let entries: Vec<_> = map.iter().collect();
+        assert_eq!(entries.len(), 129);
+        assert!(entries.contains(&(MaybeChar::from_char('a'), 1)));
+        assert!(entries.contains(&(MaybeChar::from_char('b'), 0)));
+        assert!(entries.contains(&(MaybeChar::from_char('α'), 2)));
+    }
+}
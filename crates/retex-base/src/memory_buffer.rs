@@ -26,10 +26,44 @@ impl MemoryBuffer {
         Self::from_string(text.to_string(), buffer_name)
     }
 
+    /// Returns a buffer with the same name where every "\r\n" and "\r" line ending is replaced by "\n". Offsets into
+    /// the new buffer shift relative to the original after each replaced "\r\n", so locations computed on one buffer
+    /// don't apply to the other.
+    pub fn with_normalized_newlines(self) -> MemoryBuffer {
+        let mut data = Vec::with_capacity(self.data.len());
+        let mut bytes = self.data.iter().copied().peekable();
+        while let Some(byte) = bytes.next() {
+            if byte == b'\r' {
+                bytes.next_if_eq(&b'\n');
+                data.push(b'\n');
+            } else {
+                data.push(byte);
+            }
+        }
+
+        Self::from_vec(data, self.buffer_name)
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.data
     }
 
+    /// Returns a shared reference to the bytes of the buffer, which stays valid after the buffer is dropped without
+    /// copying the data
+    pub fn data_arc(&self) -> Arc<Vec<u8>> {
+        Arc::clone(&self.data)
+    }
+
+    /// Returns a hash of the contents of the buffer, ignoring its name, for detecting identical contents (e.g., the same
+    /// file added under different paths). The hash is 64-bit FNV-1a, so it is stable across runs and platforms and can
+    /// be used as a cache key.
+    pub fn content_hash(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        self.data.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| (hash ^ byte as u64).wrapping_mul(FNV_PRIME))
+    }
+
     pub fn buffer_name(&self) -> &str {
         &self.buffer_name
     }
@@ -59,7 +93,7 @@ impl MemoryBuffer {
         let end_ptr = self.get_buffer_end();
 
         if ptr >= start_ptr && ptr <= end_ptr {
-            Some(unsafe { ptr.offset_from(start_ptr) as usize })
+            Some(ptr as usize - start_ptr as usize)
         } else {
             None
         }
@@ -89,6 +123,30 @@ mod tests {
         assert!(!buffer.is_empty());
     }
 
+    #[test]
+    fn test_memory_buffer_data_arc() {
+        let buffer = MemoryBuffer::from_str("Hello", "test.tex".to_string());
+        let data = buffer.data_arc();
+        assert!(Arc::ptr_eq(&data, &buffer.data_arc()));
+        assert_eq!(data.as_ptr(), buffer.get_buffer_start());
+
+        drop(buffer);
+        assert_eq!(data.as_slice(), b"Hello");
+    }
+
+    #[test]
+    fn test_memory_buffer_content_hash() {
+        let a = MemoryBuffer::from_str("\\relax", "a.tex".to_string());
+        let b = MemoryBuffer::from_str("\\relax", "b.tex".to_string());
+        let c = MemoryBuffer::from_str("\\relay", "a.tex".to_string());
+        assert_eq!(a.content_hash(), b.content_hash());
+        assert_ne!(a.content_hash(), c.content_hash());
+
+        // Stable value of FNV-1a
+        assert_eq!(MemoryBuffer::from_str("", "empty".to_string()).content_hash(), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(MemoryBuffer::from_str("a", "a".to_string()).content_hash(), 0xaf63_dc4c_8601_ec8c);
+    }
+
     #[test]
     fn test_memory_buffer_from_string() {
         let text = "Hello, World!".to_string();
@@ -124,6 +182,17 @@ mod tests {
         assert_eq!(buffer.as_str().unwrap(), text);
     }
 
+    #[test]
+    fn test_memory_buffer_with_normalized_newlines() {
+        let buffer = MemoryBuffer::from_str("a\r\nb\rc\nd\r\r\n", "test.tex".to_string());
+        assert_eq!(buffer.size(), 11);
+
+        let normalized = buffer.with_normalized_newlines();
+        assert_eq!(normalized.data(), b"a\nb\nc\nd\n\n");
+        assert_eq!(normalized.buffer_name(), "test.tex");
+        assert_eq!(normalized.size(), 9);
+    }
+
     #[test]
     fn test_memory_buffer_empty() {
         let buffer = MemoryBuffer::from_vec(vec![], "empty.tex".to_string());
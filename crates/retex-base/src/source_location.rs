@@ -1,4 +1,4 @@
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SourceLocation {
     /// Byte offset in the source file.
     ///
@@ -61,6 +61,11 @@ impl SourceRange {
             0
         }
     }
+
+    /// Returns whether `loc` is within the range; the end is excluded, so an empty range contains no location
+    pub fn contains(self, loc: SourceLocation) -> bool {
+        self.is_valid() && loc.is_valid() && self.start.offset <= loc.offset && loc.offset < self.end.offset
+    }
 }
 
 impl Default for SourceRange {
@@ -173,6 +178,19 @@ mod tests {
         assert_eq!(partial_invalid.length(), 0);
     }
 
+    #[test]
+    fn test_source_range_contains() {
+        let range = SourceRange::new(SourceLocation::new(10), SourceLocation::new(12));
+        assert!(!range.contains(SourceLocation::new(9)));
+        assert!(range.contains(SourceLocation::new(10)));
+        assert!(range.contains(SourceLocation::new(11)));
+        assert!(!range.contains(SourceLocation::new(12)));
+        assert!(!range.contains(SourceLocation::invalid()));
+
+        assert!(!SourceRange::new(SourceLocation::new(10), SourceLocation::new(10)).contains(SourceLocation::new(10)));
+        assert!(!SourceRange::invalid().contains(SourceLocation::new(0)));
+    }
+
     #[test]
     fn test_source_range_length_saturating_sub() {
         // Test case where end is before start (should not happen in practice but let's test saturating_sub)
@@ -207,6 +225,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_source_location_clone_copy() {
         let loc = SourceLocation::new(42);
         let cloned = loc.clone();
@@ -217,6 +236,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_source_range_clone_copy() {
         let range = SourceRange::new(SourceLocation::new(10), SourceLocation::new(20));
         let cloned = range.clone();
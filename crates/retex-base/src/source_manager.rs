@@ -1,10 +1,12 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use crate::{MemoryBuffer, SourceLocation};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use crate::{MemoryBuffer, SourceLocation, SourceRange};
 
 /// FileId represents a unique identifier for a file in the SourceManager.
-/// This follows Clang's approach of using an opaque identifier for files.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// This follows Clang's approach of using an opaque identifier for files. FileIds are ordered by the order in which the
+/// files were added to the SourceManager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct FileId(u32);
 
 impl FileId {
@@ -37,6 +39,19 @@ pub struct FileEntry {
     pub start_offset: u32,
     /// Size of the file in bytes
     pub size: u32,
+    /// Store the start of every `line_index_stride`-th line in the line index; 1 stores every line
+    line_index_stride: u32,
+    /// Line index built lazily on the first line query
+    line_index: OnceCell<LineIndex>,
+}
+
+/// Index of the lines of a [FileEntry]
+#[derive(Debug, Clone, PartialEq)]
+struct LineIndex {
+    /// Local offsets of the first byte of lines 1, 1 + stride, 1 + 2 * stride, and so on
+    sampled_line_starts: Vec<u32>,
+    /// Number of lines in the file
+    line_count: u32,
 }
 
 impl FileEntry {
@@ -47,9 +62,19 @@ impl FileEntry {
             buffer,
             start_offset,
             size,
+            line_index_stride: 1,
+            line_index: OnceCell::new(),
         }
     }
 
+    /// Store only the start of every `stride`-th line in the line index, trading the speed of line queries for memory
+    /// on huge files: a query scans up to `stride` lines from the nearest stored line start. A stride of 0 is treated
+    /// as 1, which stores every line (the default). The index is rebuilt on the next line query.
+    pub fn set_line_index_stride(&mut self, stride: u32) {
+        self.line_index_stride = stride.max(1);
+        self.line_index = OnceCell::new();
+    }
+
     /// Get the end offset of this file in the global source location space
     pub fn end_offset(&self) -> u32 {
         self.start_offset + self.size
@@ -78,6 +103,106 @@ impl FileEntry {
             None
         }
     }
+
+    /// Get the bytes of the file covered by a range in the global source location space (e.g., the range of a token),
+    /// or `None` if the range is invalid or not fully contained in this file
+    pub fn slice_local(&self, range: SourceRange) -> Option<&[u8]> {
+        if !range.is_valid() || range.start.offset() < self.start_offset || range.end.offset() > self.end_offset()
+            || range.start.offset() > range.end.offset() {
+            return None;
+        }
+        let start = (range.start.offset() - self.start_offset) as usize;
+        let end = (range.end.offset() - self.start_offset) as usize;
+        Some(&self.buffer.data()[start..end])
+    }
+
+    /// Get the local offset of the line after the line containing `offset`, or `None` if that line is the last one.
+    /// Lines are terminated by "\r\n", "\r" or "\n", following how the lexer identifies input lines.
+    fn next_line_start(&self, offset: u32) -> Option<u32> {
+        let data = self.buffer.data();
+        let mut pos = offset as usize;
+        while pos < data.len() {
+            let ch = data[pos];
+            pos += 1;
+            if ch == b'\r' && pos < data.len() && data[pos] == b'\n' {
+                pos += 1;
+            }
+            if ch == b'\r' || ch == b'\n' {
+                return (pos < data.len()).then_some(pos as u32);
+            }
+        }
+        None
+    }
+
+    fn line_index(&self) -> &LineIndex {
+        self.line_index.get_or_init(|| {
+            let mut sampled_line_starts = vec![0];
+            let mut line_count = 1;
+            let mut line_start = 0;
+            while let Some(next) = self.next_line_start(line_start) {
+                if line_count % self.line_index_stride == 0 {
+                    sampled_line_starts.push(next);
+                }
+                line_count += 1;
+                line_start = next;
+            }
+            LineIndex { sampled_line_starts, line_count }
+        })
+    }
+
+    /// Get the local offsets of the first byte of the lines stored in the line index: every line by default, or lines
+    /// 1, 1 + stride, 1 + 2 * stride, and so on after [FileEntry::set_line_index_stride].
+    pub fn sampled_line_starts(&self) -> &[u32] {
+        &self.line_index().sampled_line_starts
+    }
+
+    /// Get the number of lines in this file
+    pub fn line_count(&self) -> u32 {
+        self.line_index().line_count
+    }
+
+    /// Get the local `[start, end)` offsets of a 1-based line. The range includes the line terminator.
+    pub fn line_range(&self, line: u32) -> Option<(u32, u32)> {
+        let index = line.checked_sub(1)?;
+        if index >= self.line_count() {
+            return None;
+        }
+        let stride = self.line_index_stride;
+        let line_starts = self.sampled_line_starts();
+        let mut start = line_starts[(index / stride) as usize];
+        for _ in 0..index % stride {
+            start = self.next_line_start(start)?;
+        }
+        // The start of the next line is stored if it's sampled, e.g., always with a stride of 1
+        let next_start = if (index + 1) % stride == 0 {
+            line_starts.get(((index + 1) / stride) as usize).copied()
+        } else {
+            self.next_line_start(start)
+        };
+        Some((start, next_start.unwrap_or(self.size)))
+    }
+
+    /// Convert a local offset within this file to a 1-based (line, column) pair. Columns count bytes.
+    pub fn line_and_column(&self, offset: u32) -> Option<(u32, u32)> {
+        if offset > self.size {
+            return None;
+        }
+        let line_starts = self.sampled_line_starts();
+        let sample = line_starts.partition_point(|&start| start <= offset) - 1;
+        let mut line = sample as u32 * self.line_index_stride + 1;
+        let mut start = line_starts[sample];
+        // Only the lines between the sampled one and the next sampled one are scanned, none with a stride of 1
+        for _ in 1..self.line_index_stride {
+            match self.next_line_start(start) {
+                Some(next) if next <= offset => {
+                    line += 1;
+                    start = next;
+                },
+                _ => break,
+            }
+        }
+        Some((line, offset - start + 1))
+    }
 }
 
 /// SourceManager handles loading and caching of source files into memory. This is inspired by Clang's SourceManager.
@@ -85,24 +210,43 @@ impl FileEntry {
 /// This object owns the MemoryBuffer objects for all the loaded files and assigns unique [FileId]'s for each unique
 /// \\input chain.
 ///
+/// Offsets in the global source location space are assigned deterministically: each file added takes the next
+/// `size` offsets starting at [SourceManager::offset_watermark], which then moves past them. The watermark never moves
+/// back and offsets are never reused, so files occupy disjoint ranges in the order they were added (empty files take no
+/// offsets), and adding the same buffers in the same order assigns the same offsets across runs. A buffer deduplicated
+/// by [SourceManager::add_buffer_dedup] reuses the offsets of the existing file and takes none.
+///
 /// TODO: Allow queries for file information about [SourceLocation].
 #[derive(Debug)]
 pub struct SourceManager {
-    /// Map from FileId to FileEntry
-    files: HashMap<FileId, FileEntry>,
+    /// Map from FileId to FileEntry; ordered so iteration is deterministic
+    files: BTreeMap<FileId, FileEntry>,
+    /// Map from the path of a file to its FileId; the latest file added with a path wins
+    file_ids_by_path: HashMap<PathBuf, FileId>,
+    /// Map from the content hash of a buffer to the files with that hash, in the order they were added. Only files
+    /// before `next_unhashed_file_id` are recorded; the rest are hashed by the next [SourceManager::add_buffer_dedup]
+    file_ids_by_content_hash: HashMap<u64, Vec<FileId>>,
+    /// First FileId whose contents aren't recorded in `file_ids_by_content_hash` yet
+    next_unhashed_file_id: u32,
     /// Next available FileId
     next_file_id: u32,
     /// Next available offset in the global source location space
     next_source_offset: u32,
+    /// Stride of the line index of each file; see [FileEntry::set_line_index_stride]
+    line_index_stride: u32,
 }
 
 impl SourceManager {
     /// Create a new SourceManager
     pub fn new() -> Self {
         Self {
-            files: HashMap::new(),
+            files: BTreeMap::new(),
+            file_ids_by_path: HashMap::new(),
+            file_ids_by_content_hash: HashMap::new(),
+            next_unhashed_file_id: 0,
             next_file_id: 0,
             next_source_offset: 0,
+            line_index_stride: 1,
         }
     }
 
@@ -121,15 +265,71 @@ impl SourceManager {
         self.next_file_id += 1;
 
         let path = path.unwrap_or_else(|| PathBuf::from(buffer.buffer_name()));
-        let file_entry = FileEntry::new(path, buffer, self.next_source_offset);
+        let mut file_entry = FileEntry::new(path, buffer, self.next_source_offset);
+        file_entry.set_line_index_stride(self.line_index_stride);
 
         // Update next offset for the next file
         self.next_source_offset = file_entry.end_offset();
 
+        self.file_ids_by_path.insert(file_entry.path.clone(), file_id);
         self.files.insert(file_id, file_entry);
         file_id
     }
 
+    /// Same as [SourceManager::add_buffer] but if a buffer with identical contents is already loaded, returns the
+    /// existing [FileId] instead of allocating new source space. The files then share the same offsets, so locations
+    /// map back to the first file added; `path` is still registered for [SourceManager::get_file_id_by_path].
+    pub fn add_buffer_dedup(&mut self, buffer: MemoryBuffer, path: Option<PathBuf>) -> FileId {
+        // Files are hashed here rather than when added, so only callers that deduplicate pay for hashing
+        for (&file_id, file_entry) in self.files.range(FileId::new(self.next_unhashed_file_id)..) {
+            self.file_ids_by_content_hash.entry(file_entry.buffer.content_hash()).or_default().push(file_id);
+        }
+        self.next_unhashed_file_id = self.next_file_id;
+
+        let content_hash = buffer.content_hash();
+        let existing = self.file_ids_by_content_hash.get(&content_hash)
+            .and_then(|file_ids| file_ids.iter().copied().find(|file_id| {
+                self.files[file_id].buffer.data() == buffer.data()
+            }));
+        let Some(file_id) = existing else {
+            let file_id = self.add_buffer(buffer, path);
+            self.file_ids_by_content_hash.entry(content_hash).or_default().push(file_id);
+            self.next_unhashed_file_id = self.next_file_id;
+            return file_id;
+        };
+
+        let path = path.unwrap_or_else(|| PathBuf::from(buffer.buffer_name()));
+        self.file_ids_by_path.insert(path, file_id);
+        file_id
+    }
+
+    /// Same as [SourceManager::add_buffer] but also returns the range of global offsets assigned to the buffer, which
+    /// spans from the entry's `start_offset` to its `end_offset()`
+    pub fn add_buffer_with_range(&mut self, buffer: MemoryBuffer, path: Option<PathBuf>) -> (FileId, SourceRange) {
+        let file_id = self.add_buffer(buffer, path);
+        let file_entry = &self.files[&file_id];
+        let range = SourceRange::new(
+            SourceLocation::new(file_entry.start_offset),
+            SourceLocation::new(file_entry.end_offset()));
+        (file_id, range)
+    }
+
+    /// Store only the start of every `stride`-th line in the line index of each file, for files already added and
+    /// files added later; see [FileEntry::set_line_index_stride]. Line and column queries stay exact but scan up to
+    /// `stride` lines, which saves memory on files with millions of lines.
+    pub fn set_line_index_stride(&mut self, stride: u32) {
+        self.line_index_stride = stride.max(1);
+        for entry in self.files.values_mut() {
+            entry.set_line_index_stride(stride);
+        }
+    }
+
+    /// Find a file by the path it was added with (or the buffer name if it was added without a path). This finds
+    /// in-memory buffers without touching the file system.
+    pub fn get_file_id_by_path(&self, path: &Path) -> Option<FileId> {
+        self.file_ids_by_path.get(path).copied()
+    }
+
     /// Get a FileEntry by FileId
     pub fn get_file(&self, file_id: FileId) -> Option<&FileEntry> {
         self.files.get(&file_id)
@@ -163,6 +363,54 @@ impl SourceManager {
         }
     }
 
+    /// Get the local `[start, end)` offsets of a 1-based line in a file
+    pub fn get_line_range(&self, file_id: FileId, line: u32) -> Option<(u32, u32)> {
+        self.get_file(file_id)?.line_range(line)
+    }
+
+    /// Get the 1-based numbers of the lines in a file that end with spaces or tabs before the line terminator (or the
+    /// end of the file), e.g., for a linter. Returns nothing if the file isn't loaded.
+    pub fn lines_with_trailing_whitespace(&self, file_id: FileId) -> Vec<u32> {
+        let Some(entry) = self.get_file(file_id) else {
+            return Vec::new();
+        };
+        let data = entry.buffer.data();
+        (1..=entry.line_count())
+            .filter(|&line| {
+                let Some((start, end)) = entry.line_range(line) else {
+                    return false;
+                };
+                let content = &data[start as usize..end as usize];
+                let content = content.strip_suffix(b"\n").unwrap_or(content);
+                let content = content.strip_suffix(b"\r").unwrap_or(content);
+                content.last().is_some_and(|&byte| byte == b' ' || byte == b'\t')
+            })
+            .collect()
+    }
+
+    /// Get the id of the file that a global source location falls within
+    pub fn get_file_id_for_location(&self, loc: SourceLocation) -> Option<FileId> {
+        self.iter_files().find(|(_, entry)| entry.contains_location(loc)).map(|(file_id, _)| file_id)
+    }
+
+    /// Format a global source location as `path:line:column` for messages (e.g., `paper.tex:12:5`). Returns
+    /// `"<invalid>"` if the location is invalid or isn't in any loaded file.
+    pub fn format_location(&self, loc: SourceLocation) -> String {
+        let resolved = self.get_file_id_for_location(loc)
+            .and_then(|file_id| self.get_file(file_id))
+            .and_then(|entry| {
+                let (line, column) = entry.line_and_column(entry.location_to_offset(loc)?)?;
+                Some(format!("{}:{line}:{column}", entry.path.display()))
+            });
+        resolved.unwrap_or_else(|| "<invalid>".to_string())
+    }
+
+    /// Get the offset that the next file added will start at; every offset assigned so far is below it. See
+    /// [SourceManager] for how offsets are assigned.
+    pub fn offset_watermark(&self) -> u32 {
+        self.next_source_offset
+    }
+
     /// Get the number of loaded files
     pub fn file_count(&self) -> usize {
         self.files.len()
@@ -172,6 +420,16 @@ impl SourceManager {
     pub fn is_file_loaded(&self, file_id: FileId) -> bool {
         self.files.contains_key(&file_id)
     }
+
+    /// Iterate over the ids of the loaded files in ascending order
+    pub fn file_ids(&self) -> impl Iterator<Item = FileId> + '_ {
+        self.files.keys().copied()
+    }
+
+    /// Iterate over the loaded files in ascending order of their ids
+    pub fn iter_files(&self) -> impl Iterator<Item = (FileId, &FileEntry)> {
+        self.files.iter().map(|(file_id, entry)| (*file_id, entry))
+    }
 }
 
 impl Default for SourceManager {
@@ -194,6 +452,16 @@ mod tests {
         assert!(!invalid.is_valid());
     }
 
+    #[test]
+    fn test_file_id_ordering() {
+        assert!(FileId::new(1) < FileId::new(2));
+        assert!(FileId::new(10) > FileId::new(2));
+
+        let mut ids = vec![FileId::new(3), FileId::new(0), FileId::new(2)];
+        ids.sort();
+        assert_eq!(ids, vec![FileId::new(0), FileId::new(2), FileId::new(3)]);
+    }
+
     #[test]
     fn test_file_entry() {
         let buffer = MemoryBuffer::from_str("Hello, World!", "test.tex".to_string());
@@ -217,6 +485,59 @@ mod tests {
         assert_eq!(entry.offset_to_location(20), None);
     }
 
+    #[test]
+    fn test_file_entry_lines() {
+        let buffer = MemoryBuffer::from_str("ab\ncd\r\ne\rf", "lines.tex".to_string());
+        let entry = FileEntry::new(PathBuf::from("lines.tex"), buffer, 0);
+
+        assert_eq!(entry.sampled_line_starts(), &[0, 3, 7, 9]);
+        assert_eq!(entry.line_count(), 4);
+        assert_eq!(entry.line_range(1), Some((0, 3)));
+        assert_eq!(entry.line_range(2), Some((3, 7)));
+        assert_eq!(entry.line_range(4), Some((9, 10)));
+        assert_eq!(entry.line_range(0), None);
+        assert_eq!(entry.line_range(5), None);
+
+        assert_eq!(entry.line_and_column(0), Some((1, 1)));
+        assert_eq!(entry.line_and_column(2), Some((1, 3)));
+        assert_eq!(entry.line_and_column(4), Some((2, 2)));
+        assert_eq!(entry.line_and_column(10), Some((4, 2)));
+        assert_eq!(entry.line_and_column(11), None);
+    }
+
+    #[test]
+    fn test_line_index_stride() {
+        let text = "l1\nl2\r\nl3\nl4\rl5\n\nl7\nl8\nl9\nl10";
+        let mut sm = SourceManager::new();
+        let file_id = sm.add_buffer(MemoryBuffer::from_str(text, "lines.tex".to_string()), None);
+        let expected: Vec<_> = (1..=sm.get_file(file_id).unwrap().line_count())
+            .map(|line| sm.get_line_range(file_id, line))
+            .collect();
+        let expected_positions: Vec<_> = (0..=text.len() as u32)
+            .map(|offset| sm.get_file(file_id).unwrap().line_and_column(offset))
+            .collect();
+
+        sm.set_line_index_stride(4);
+        let entry = sm.get_file(file_id).unwrap();
+        assert_eq!(entry.sampled_line_starts(), &[0, 13, 23]);
+        assert_eq!(entry.line_count(), 10);
+        assert_eq!(entry.line_range(6), Some((16, 17)));
+        assert_eq!(entry.line_range(10), Some((26, 29)));
+        assert_eq!(entry.line_range(11), None);
+        assert_eq!(entry.line_and_column(8), Some((3, 2)));
+        assert_eq!(entry.line_and_column(28), Some((10, 3)));
+
+        // The sparse index gives the same results as the full one
+        let actual: Vec<_> = (1..=entry.line_count()).map(|line| entry.line_range(line)).collect();
+        assert_eq!(actual, expected);
+        let actual_positions: Vec<_> = (0..=text.len() as u32).map(|offset| entry.line_and_column(offset)).collect();
+        assert_eq!(actual_positions, expected_positions);
+
+        // Files added later use the stride too
+        let other_id = sm.add_buffer(MemoryBuffer::from_str(text, "other.tex".to_string()), None);
+        assert_eq!(sm.get_file(other_id).unwrap().sampled_line_starts(), &[0, 13, 23]);
+    }
+
     #[test]
     fn test_source_manager_add_buffer() {
         let mut sm = SourceManager::new();
@@ -250,6 +571,28 @@ mod tests {
         assert_eq!(file2.size, 6);
     }
 
+    #[test]
+    fn test_file_entry_slice_local() {
+        let mut sm = SourceManager::new();
+        let file_id1 = sm.add_buffer(MemoryBuffer::from_str("First", "first.tex".to_string()), None);
+        let file_id2 = sm.add_buffer(MemoryBuffer::from_str("\\emph{x}", "second.tex".to_string()), None);
+        let file1 = sm.get_file(file_id1).unwrap();
+        let file2 = sm.get_file(file_id2).unwrap();
+        let range = |start, end| SourceRange::new(SourceLocation::new(start), SourceLocation::new(end));
+
+        // The range of `\emph` in the second file, which starts at global offset 5
+        assert_eq!(file2.slice_local(range(5, 10)), Some(&b"\\emph"[..]));
+        assert_eq!(file2.slice_local(range(13, 13)), Some(&b""[..]));
+        assert_eq!(file1.slice_local(range(0, 5)), Some(&b"First"[..]));
+
+        // Ranges not fully contained in the file
+        assert_eq!(file1.slice_local(range(5, 10)), None);
+        assert_eq!(file2.slice_local(range(3, 7)), None);
+        assert_eq!(file2.slice_local(range(12, 14)), None);
+        assert_eq!(file2.slice_local(range(8, 6)), None);
+        assert_eq!(file2.slice_local(SourceRange::invalid()), None);
+    }
+
     #[test]
     fn test_source_manager_buffer_operations() {
         let mut sm = SourceManager::new();
@@ -269,6 +612,112 @@ mod tests {
         assert_eq!(out_of_range, None);
     }
 
+    #[test]
+    fn test_source_manager_deterministic_iteration() {
+        let mut sm = SourceManager::new();
+        let names = ["c.tex", "a.tex", "d.tex", "b.tex", "e.tex"];
+        let file_ids: Vec<FileId> = names.iter()
+            .map(|name| sm.add_buffer(MemoryBuffer::from_str(name, name.to_string()), None))
+            .collect();
+
+        assert_eq!(sm.file_ids().collect::<Vec<_>>(), file_ids);
+
+        let paths: Vec<_> = sm.iter_files().map(|(_, entry)| entry.path.to_string_lossy().to_string()).collect();
+        assert_eq!(paths, names);
+
+        let offsets: Vec<_> = sm.iter_files().map(|(_, entry)| entry.start_offset).collect();
+        assert!(offsets.is_sorted());
+    }
+
+    #[test]
+    fn test_source_manager_get_file_id_by_path() {
+        let mut sm = SourceManager::new();
+        let unnamed = sm.add_buffer(MemoryBuffer::from_str("a", "macros.tex".to_string()), None);
+        let named = sm.add_buffer(MemoryBuffer::from_str("b", "buffer".to_string()), Some(PathBuf::from("dir/b.tex")));
+
+        assert_eq!(sm.get_file_id_by_path(Path::new("macros.tex")), Some(unnamed));
+        assert_eq!(sm.get_file_id_by_path(Path::new("dir/b.tex")), Some(named));
+        assert_eq!(sm.get_file_id_by_path(Path::new("buffer")), None);
+    }
+
+    #[test]
+    fn test_source_manager_format_location() {
+        let mut sm = SourceManager::new();
+        sm.add_buffer(MemoryBuffer::from_str("abc\n", "main.tex".to_string()), None);
+        let file_id = sm.add_buffer(MemoryBuffer::from_str("x\n  y\n", "paper.tex".to_string()), None);
+
+        let start = sm.get_file(file_id).unwrap().start_offset;
+        assert_eq!(sm.get_file_id_for_location(SourceLocation::new(start + 4)), Some(file_id));
+        assert_eq!(sm.format_location(SourceLocation::new(start + 4)), "paper.tex:2:3");
+        assert_eq!(sm.format_location(SourceLocation::new(0)), "main.tex:1:1");
+        assert_eq!(sm.format_location(SourceLocation::invalid()), "<invalid>");
+    }
+
+    #[test]
+    fn test_source_manager_add_buffer_with_range() {
+        let mut sm = SourceManager::new();
+        sm.add_buffer(MemoryBuffer::from_str("abc\n", "main.tex".to_string()), None);
+        let (file_id, range) = sm.add_buffer_with_range(MemoryBuffer::from_str("hello", "b.tex".to_string()), None);
+
+        let entry = sm.get_file(file_id).unwrap();
+        assert_eq!(range, SourceRange::new(SourceLocation::new(entry.start_offset), SourceLocation::new(entry.end_offset())));
+        assert_eq!(range, SourceRange::new(SourceLocation::new(4), SourceLocation::new(9)));
+    }
+
+    #[test]
+    fn test_source_manager_offsets_are_deterministic() {
+        let load = || {
+            let mut sm = SourceManager::new();
+            let mut offsets = vec![sm.offset_watermark()];
+            for (text, name) in [("\\input b", "a.tex"), ("", "empty.tex"), ("abc", "b.tex"), ("\\input b", "c.tex")] {
+                let file_id = sm.add_buffer_dedup(MemoryBuffer::from_str(text, name.to_string()), None);
+                offsets.push(sm.get_file(file_id).unwrap().start_offset);
+                offsets.push(sm.offset_watermark());
+            }
+            offsets
+        };
+
+        // The deduplicated c.tex reuses the offsets of a.tex without moving the watermark
+        assert_eq!(load(), [0, 0, 8, 8, 8, 8, 11, 0, 11]);
+        assert_eq!(load(), load());
+    }
+
+    #[test]
+    fn test_source_manager_add_buffer_dedup() {
+        let mut sm = SourceManager::new();
+        let first = sm.add_buffer_dedup(MemoryBuffer::from_str("\\relax", "a.tex".to_string()), None);
+        let next_source_offset = sm.next_source_offset;
+
+        let second = sm.add_buffer_dedup(MemoryBuffer::from_str("\\relax", "b.tex".to_string()), None);
+        assert_eq!(second, first);
+        assert_eq!(sm.file_count(), 1);
+        assert_eq!(sm.next_source_offset, next_source_offset);
+        assert_eq!(sm.get_file_id_by_path(Path::new("b.tex")), Some(first));
+
+        // Different contents get their own file
+        let third = sm.add_buffer_dedup(MemoryBuffer::from_str("\\relay", "c.tex".to_string()), None);
+        assert_ne!(third, first);
+        assert_eq!(sm.file_count(), 2);
+
+        // Files added without deduplication aren't hashed until a buffer is deduplicated against them
+        let fourth = sm.add_buffer(MemoryBuffer::from_str("\\par", "d.tex".to_string()), None);
+        assert_eq!(sm.file_ids_by_content_hash.values().flatten().count(), 2);
+        let fifth = sm.add_buffer_dedup(MemoryBuffer::from_str("\\par", "e.tex".to_string()), None);
+        assert_eq!(fifth, fourth);
+        assert_eq!(sm.file_count(), 3);
+    }
+
+    #[test]
+    fn test_source_manager_lines_with_trailing_whitespace() {
+        let mut sm = SourceManager::new();
+        let file_id = sm.add_buffer(MemoryBuffer::from_str("clean\nspaces  \r\n\ntab\t\r\n \nend ", "a.tex".to_string()), None);
+        assert_eq!(sm.lines_with_trailing_whitespace(file_id), vec![2, 4, 5, 6]);
+
+        let file_id = sm.add_buffer(MemoryBuffer::from_str("a\nb\n", "b.tex".to_string()), None);
+        assert!(sm.lines_with_trailing_whitespace(file_id).is_empty());
+        assert!(sm.lines_with_trailing_whitespace(FileId::invalid()).is_empty());
+    }
+
     #[test]
     fn test_source_manager_empty() {
         let sm = SourceManager::new();
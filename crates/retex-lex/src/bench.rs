@@ -0,0 +1,33 @@
+use crate::command_identifier::CommandIdentifierInterner;
+use crate::lexer::Lexer;
+use crate::token::{Token, TokenKind};
+
+/// Lexes `input` from start to end and returns the number of tokens, excluding the final [TokenKind::Eof]. Meant to be
+/// the body of a benchmark iteration (e.g., `b.iter(|| bench_lex(input, &table))`) so benchmarks across the workspace
+/// measure lexing the same way.
+pub fn bench_lex<'idtable>(input: &[u8], table: &'idtable dyn CommandIdentifierInterner<'idtable>) -> usize {
+    let mut lexer = Lexer::from_bytes(input, table);
+    let mut token = Token::default();
+    let mut count = 0;
+    loop {
+        lexer.lex(&mut token);
+        if token.is(TokenKind::Eof) {
+            return count;
+        }
+        count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command_identifier::CommandIdentifierTable;
+    use super::*;
+
+    #[test]
+    fn test_bench_lex() {
+        let table = CommandIdentifierTable::new();
+        // \section, {, T, i, t, l, e, }, space, a, \\, b
+        assert_eq!(bench_lex(b"\\section{Title} a\\\\b", &table), 12);
+        assert_eq!(bench_lex(b"", &table), 0);
+    }
+}
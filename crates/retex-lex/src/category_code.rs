@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use retex_base::MaybeChar;
+use std::fmt;
+use retex_base::{MaybeChar, MaybeCharMap};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
@@ -26,51 +26,169 @@ impl CategoryCode {
     pub fn as_u8(self) -> u8 {
         self as u8
     }
+
+    /// Returns the category code with the given number, or `None` if it is out of the range 0-15
+    pub fn from_u8(value: u8) -> Option<Self> {
+        const CATEGORY_CODES: [CategoryCode; 16] = [
+            CategoryCode::Escape, CategoryCode::BeginGroup, CategoryCode::EndGroup, CategoryCode::MathShift,
+            CategoryCode::AlignmentTab, CategoryCode::EndOfLine, CategoryCode::Parameter, CategoryCode::Superscript,
+            CategoryCode::Subscript, CategoryCode::Ignored, CategoryCode::Space, CategoryCode::Letter,
+            CategoryCode::Other, CategoryCode::Active, CategoryCode::Comment, CategoryCode::Invalid,
+        ];
+        CATEGORY_CODES.get(value as usize).copied()
+    }
+}
+
+/// Error from parsing a serialized [CategoryCodeTable]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The data ends in the middle of a record at the given offset
+    TruncatedRecord { offset: usize },
+    /// The record at the given offset has a value that isn't a valid [MaybeChar] encoding
+    InvalidCharacter { offset: usize, value: u32 },
+    /// The record at the given offset has a category code out of the range 0-15
+    InvalidCategoryCode { offset: usize, value: u8 },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::TruncatedRecord { offset } => write!(f, "truncated record at offset {offset}"),
+            ParseError::InvalidCharacter { offset, value } => {
+                write!(f, "invalid character {value:#x} in record at offset {offset}")
+            },
+            ParseError::InvalidCategoryCode { offset, value } => {
+                write!(f, "invalid category code {value} in record at offset {offset}")
+            },
+        }
+    }
 }
 
+impl std::error::Error for ParseError {}
+
+#[derive(Clone)]
 pub struct CategoryCodeTable {
-    table: HashMap<MaybeChar, CategoryCode>,
+    table: MaybeCharMap<CategoryCode>,
+    /// Tables saved by [CategoryCodeTable::push_group], restored in last-in, first-out order
+    saved_tables: Vec<MaybeCharMap<CategoryCode>>,
 }
 
 impl CategoryCodeTable {
     pub fn new() -> Self {
-        let mut table = HashMap::new();
+        let mut table = MaybeCharMap::new(CategoryCode::Other);
 
         // Set default category codes
-        table.insert(MaybeChar::from_char('\\'), CategoryCode::Escape);
-        table.insert(MaybeChar::from_char('{'), CategoryCode::BeginGroup);
-        table.insert(MaybeChar::from_char('}'), CategoryCode::EndGroup);
-        table.insert(MaybeChar::from_char('$'), CategoryCode::MathShift);
-        table.insert(MaybeChar::from_char('&'), CategoryCode::AlignmentTab);
-        table.insert(MaybeChar::from_char('\r'), CategoryCode::EndOfLine);
-        table.insert(MaybeChar::from_char('\n'), CategoryCode::EndOfLine);
-        table.insert(MaybeChar::from_char('#'), CategoryCode::Parameter);
-        table.insert(MaybeChar::from_char('^'), CategoryCode::Superscript);
-        table.insert(MaybeChar::from_char('_'), CategoryCode::Subscript);
-        table.insert(MaybeChar::from_char('\0'), CategoryCode::Ignored);
-        table.insert(MaybeChar::from_char('\u{7f}'), CategoryCode::Ignored); // DEL
-        table.insert(MaybeChar::from_char(' '), CategoryCode::Space);
-        table.insert(MaybeChar::from_char('\t'), CategoryCode::Space);
-        table.insert(MaybeChar::from_char('~'), CategoryCode::Active);
-        table.insert(MaybeChar::from_char('%'), CategoryCode::Comment);
+        table.set(MaybeChar::from_char('\\'), CategoryCode::Escape);
+        table.set(MaybeChar::from_char('{'), CategoryCode::BeginGroup);
+        table.set(MaybeChar::from_char('}'), CategoryCode::EndGroup);
+        table.set(MaybeChar::from_char('$'), CategoryCode::MathShift);
+        table.set(MaybeChar::from_char('&'), CategoryCode::AlignmentTab);
+        table.set(MaybeChar::from_char('\r'), CategoryCode::EndOfLine);
+        table.set(MaybeChar::from_char('\n'), CategoryCode::EndOfLine);
+        table.set(MaybeChar::from_char('#'), CategoryCode::Parameter);
+        table.set(MaybeChar::from_char('^'), CategoryCode::Superscript);
+        table.set(MaybeChar::from_char('_'), CategoryCode::Subscript);
+        table.set(MaybeChar::from_char('\0'), CategoryCode::Ignored);
+        table.set(MaybeChar::from_char('\u{7f}'), CategoryCode::Ignored); // DEL
+        table.set(MaybeChar::from_char(' '), CategoryCode::Space);
+        table.set(MaybeChar::from_char('\t'), CategoryCode::Space);
+        table.set(MaybeChar::from_char('~'), CategoryCode::Active);
+        table.set(MaybeChar::from_char('%'), CategoryCode::Comment);
 
         // Set letters
         for c in 'a'..='z' {
-            table.insert(MaybeChar::from_char(c), CategoryCode::Letter);
+            table.set(MaybeChar::from_char(c), CategoryCode::Letter);
         }
         for c in 'A'..='Z' {
-            table.insert(MaybeChar::from_char(c), CategoryCode::Letter);
+            table.set(MaybeChar::from_char(c), CategoryCode::Letter);
         }
 
-        Self { table }
+        Self { table, saved_tables: Vec::new() }
     }
 
     pub fn get(&self, maybe_char: MaybeChar) -> CategoryCode {
-        self.table.get(&maybe_char).copied().unwrap_or(CategoryCode::Other)
+        self.table.get(maybe_char)
     }
 
     pub fn set(&mut self, maybe_char: MaybeChar, category_code: CategoryCode) {
-        self.table.insert(maybe_char, category_code);
+        self.table.set(maybe_char, category_code);
+    }
+
+    /// Saves the current category codes so changes made until the matching [CategoryCodeTable::pop_group] are undone,
+    /// like category code assignments local to a TeX group
+    pub fn push_group(&mut self) {
+        self.saved_tables.push(self.table.clone());
+    }
+
+    /// Restores the category codes saved by the last [CategoryCodeTable::push_group]. Returns `false` if there's no
+    /// group to pop.
+    pub fn pop_group(&mut self) -> bool {
+        match self.saved_tables.pop() {
+            Some(table) => {
+                self.table = table;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Sets the category code of every character to `category_code`
+    pub fn clear_all(&mut self, category_code: CategoryCode) {
+        self.table.fill(category_code);
+    }
+
+    /// Changes the category code of every character with category code `from` to `to`
+    pub fn replace(&mut self, from: CategoryCode, to: CategoryCode) {
+        let maybe_chars: Vec<_> = self.table.iter()
+            .filter(|(_, category_code)| *category_code == from)
+            .map(|(maybe_char, _)| maybe_char)
+            .collect();
+        for maybe_char in maybe_chars {
+            self.table.set(maybe_char, to);
+        }
+    }
+
+    /// Returns the characters whose category codes differ from the defaults of [CategoryCodeTable::new], ordered by
+    /// [MaybeChar::to_u32]. Two tables with equal snapshots assign the same category code to every character.
+    pub fn snapshot(&self) -> Vec<(MaybeChar, CategoryCode)> {
+        let defaults = CategoryCodeTable::new();
+        let mut snapshot: Vec<_> = self.table.iter()
+            .filter(|(maybe_char, category_code)| defaults.get(*maybe_char) != *category_code)
+            .collect();
+        snapshot.sort_by_key(|(maybe_char, _)| maybe_char.to_u32());
+        snapshot
+    }
+
+    /// Serializes the table for machine round-tripping with [CategoryCodeTable::from_bytes]. The format is a sequence
+    /// of 5-byte records, one for each entry of [CategoryCodeTable::snapshot]: the [MaybeChar::to_u32] encoding of
+    /// the character as a little-endian `u32` followed by the category code as a `u8`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let snapshot = self.snapshot();
+        let mut data = Vec::with_capacity(snapshot.len() * 5);
+        for (maybe_char, category_code) in snapshot {
+            data.extend_from_slice(&maybe_char.to_u32().to_le_bytes());
+            data.push(category_code.as_u8());
+        }
+        data
+    }
+
+    /// Deserializes a table written by [CategoryCodeTable::to_bytes]. Characters without a record have the default
+    /// category codes.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ParseError> {
+        let mut table = CategoryCodeTable::new();
+        for (index, record) in data.chunks(5).enumerate() {
+            let offset = index * 5;
+            let &[b0, b1, b2, b3, category_code] = record else {
+                return Err(ParseError::TruncatedRecord { offset });
+            };
+            let value = u32::from_le_bytes([b0, b1, b2, b3]);
+            let maybe_char = MaybeChar::from_u32(value)
+                .ok_or(ParseError::InvalidCharacter { offset, value })?;
+            let category_code = CategoryCode::from_u8(category_code)
+                .ok_or(ParseError::InvalidCategoryCode { offset, value: category_code })?;
+            table.set(maybe_char, category_code);
+        }
+        Ok(table)
     }
 
     pub fn is_letter(&self, maybe_char: MaybeChar) -> bool {
@@ -159,6 +277,70 @@ mod tests {
         assert_eq!(table.get(MaybeChar::from_char('@')), CategoryCode::Other);
         table.set(MaybeChar::from_char('@'), CategoryCode::Letter);
         assert_eq!(table.get(MaybeChar::from_char('@')), CategoryCode::Letter);
+
+        // Non-ASCII characters
+        assert_eq!(table.get(MaybeChar::from_char('α')), CategoryCode::Other);
+        table.set(MaybeChar::from_char('α'), CategoryCode::Letter);
+        assert_eq!(table.get(MaybeChar::from_char('α')), CategoryCode::Letter);
+        assert_eq!(table.get(MaybeChar::from_non_char_byte(0xFF)), CategoryCode::Other);
+    }
+
+    #[test]
+    fn test_category_code_table_groups() {
+        let mut table = CategoryCodeTable::new();
+        assert!(!table.pop_group());
+
+        table.push_group();
+        table.set(MaybeChar::from_char('@'), CategoryCode::Letter);
+        table.push_group();
+        table.clear_all(CategoryCode::Other);
+        assert_eq!(table.get(MaybeChar::from_char('\\')), CategoryCode::Other);
+        assert_eq!(table.get(MaybeChar::from_char('α')), CategoryCode::Other);
+
+        assert!(table.pop_group());
+        assert_eq!(table.get(MaybeChar::from_char('\\')), CategoryCode::Escape);
+        assert_eq!(table.get(MaybeChar::from_char('@')), CategoryCode::Letter);
+
+        assert!(table.pop_group());
+        assert_eq!(table.get(MaybeChar::from_char('@')), CategoryCode::Other);
+    }
+
+    #[test]
+    fn test_category_code_from_u8() {
+        for value in 0..16 {
+            assert_eq!(CategoryCode::from_u8(value).map(CategoryCode::as_u8), Some(value));
+        }
+        assert_eq!(CategoryCode::from_u8(16), None);
+    }
+
+    #[test]
+    fn test_category_code_table_bytes_round_trip() {
+        let mut table = CategoryCodeTable::new();
+        assert!(table.snapshot().is_empty());
+        assert!(table.to_bytes().is_empty());
+
+        table.set(MaybeChar::from_char('@'), CategoryCode::Letter);
+        table.set(MaybeChar::from_char('a'), CategoryCode::Other);
+        table.set(MaybeChar::from_char('α'), CategoryCode::Letter);
+        table.set(MaybeChar::from_non_char_byte(0xFF), CategoryCode::Invalid);
+
+        let data = table.to_bytes();
+        assert_eq!(data.len(), 4 * 5);
+
+        let restored = CategoryCodeTable::from_bytes(&data).unwrap();
+        assert_eq!(restored.snapshot(), table.snapshot());
+        assert_eq!(restored.get(MaybeChar::from_char('α')), CategoryCode::Letter);
+    }
+
+    #[test]
+    fn test_category_code_table_from_invalid_bytes() {
+        assert_eq!(CategoryCodeTable::from_bytes(&[0x40, 0, 0, 0]).err(), Some(ParseError::TruncatedRecord { offset: 0 }));
+        assert_eq!(
+            CategoryCodeTable::from_bytes(&[0x40, 0, 0, 0, 11, 0x00, 0xD8, 0, 0, 11]).err(),
+            Some(ParseError::InvalidCharacter { offset: 5, value: 0xD800 }));
+        assert_eq!(
+            CategoryCodeTable::from_bytes(&[0x40, 0, 0, 0, 16]).err(),
+            Some(ParseError::InvalidCategoryCode { offset: 0, value: 16 }));
     }
 
     #[test]
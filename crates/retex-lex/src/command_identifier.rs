@@ -1,6 +1,9 @@
 use std::collections::HashMap;
 use std::cell::RefCell;
+use std::sync::{Mutex, PoisonError};
 use std::string::FromUtf8Error;
+use retex_base::MaybeChar;
+use crate::category_code::CategoryCodeTable;
 
 /// Identifies a command in the document. A command in TeX cannot be typeset directly. It influences typesetting
 /// indirectly by carrying out assignment of a value to an internal states or produces material that can be typeset.
@@ -31,6 +34,12 @@ impl <'idtable> CommandIdentifier<'idtable> {
     pub fn as_utf8(&self) -> Result<String, FromUtf8Error> {
         String::from_utf8(self.bytes.to_vec())
     }
+
+    /// Compares the names of two identifiers. Unlike `==`, which compares interned instances by address, this holds
+    /// for identifiers from different tables (e.g., to detect a redefinition across documents).
+    pub fn content_eq(&self, other: &CommandIdentifier<'_>) -> bool {
+        self.bytes == other.bytes
+    }
 }
 
 impl<'idtable> PartialEq for CommandIdentifier<'idtable> {
@@ -43,6 +52,31 @@ impl<'idtable> PartialEq for CommandIdentifier<'idtable> {
 
 impl<'idtable> Eq for CommandIdentifier<'idtable> {}
 
+// Comparisons against names look at the content instead, e.g., `*id == *b"def"` or `id == "def"`
+impl<'idtable> PartialEq<[u8]> for CommandIdentifier<'idtable> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.bytes == other
+    }
+}
+
+impl<'idtable, const N: usize> PartialEq<[u8; N]> for CommandIdentifier<'idtable> {
+    fn eq(&self, other: &[u8; N]) -> bool {
+        self.bytes == other
+    }
+}
+
+impl<'idtable> PartialEq<str> for CommandIdentifier<'idtable> {
+    fn eq(&self, other: &str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
+impl<'idtable> PartialEq<&str> for CommandIdentifier<'idtable> {
+    fn eq(&self, other: &&str) -> bool {
+        self.bytes == other.as_bytes()
+    }
+}
+
 impl<'idtable> std::hash::Hash for CommandIdentifier<'idtable> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         // Hash the pointer address since identical content always has the same reference
@@ -50,6 +84,16 @@ impl<'idtable> std::hash::Hash for CommandIdentifier<'idtable> {
     }
 }
 
+/// Interns command names into [CommandIdentifier]s. Identifiers returned for the same name are the same instance and
+/// stay valid for the lifetime of the interner (`'idtable`), so they can be compared and hashed by address. [Lexer]
+/// accepts any interner, so lexers can share [SyncCommandIdentifierTable] across threads.
+///
+/// [Lexer]: crate::Lexer
+pub trait CommandIdentifierInterner<'idtable> {
+    /// Get a command identifier by name, or insert a new one if it doesn't exist
+    fn get_or_insert(&'idtable self, name_bytes: &[u8]) -> &'idtable CommandIdentifier<'idtable>;
+}
+
 /// A table for managing command identifiers; This provides a consistent value for mapping command identifier to a value
 /// (e.g., macro definition.)
 pub struct CommandIdentifierTable<'idtable> {
@@ -84,10 +128,97 @@ impl <'idtable> CommandIdentifierTable<'idtable> {
     }
 }
 
+impl<'idtable> Default for CommandIdentifierTable<'idtable> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'idtable> CommandIdentifierInterner<'idtable> for CommandIdentifierTable<'idtable> {
+    fn get_or_insert(&'idtable self, name_bytes: &[u8]) -> &'idtable CommandIdentifier<'idtable> {
+        CommandIdentifierTable::get_or_insert(self, name_bytes)
+    }
+}
+
+struct SyncCommandIdentifierTableInner<'idtable> {
+    arena: bumpalo::Bump,
+    table: HashMap<&'idtable [u8], &'idtable CommandIdentifier<'idtable>>,
+}
+
+/// Thread-safe variant of [CommandIdentifierTable] that can be shared by lexers running on different threads (e.g.,
+/// lexing independent files in parallel). Lookups take a lock, so prefer [CommandIdentifierTable] on a single thread.
+pub struct SyncCommandIdentifierTable<'idtable> {
+    inner: Mutex<SyncCommandIdentifierTableInner<'idtable>>,
+}
+
+impl<'idtable> SyncCommandIdentifierTable<'idtable> {
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(SyncCommandIdentifierTableInner {
+                arena: bumpalo::Bump::new(),
+                table: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Get a command identifier by name, or insert a new one if it doesn't exist
+    pub fn get_or_insert(&'idtable self, name_bytes: &[u8]) -> &'idtable CommandIdentifier<'idtable> {
+        // A panic while holding the lock can't leave the table inconsistent since an identifier is only inserted after
+        // it's fully allocated, so keep using the table rather than propagating the poison.
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(command_identifier) = inner.table.get(name_bytes) {
+            return command_identifier;
+        }
+
+        // SAFETY: The arena is only reachable through the lock, so references into it are bound to the guard. Arena
+        // allocations never move and are only freed when the arena is dropped along with `self`, which can't happen
+        // while `self` is borrowed for `'idtable`. Extending the references to `'idtable` is therefore sound.
+        let arena: &'idtable bumpalo::Bump = unsafe { &*(&inner.arena as *const bumpalo::Bump) };
+        let stable_identifier = arena.alloc_slice_copy(name_bytes);
+        let command_identifier = arena.alloc(CommandIdentifier::new(stable_identifier));
+
+        inner.table.insert(stable_identifier, command_identifier);
+
+        command_identifier
+    }
+}
+
+impl<'idtable> Default for SyncCommandIdentifierTable<'idtable> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'idtable> CommandIdentifierInterner<'idtable> for SyncCommandIdentifierTable<'idtable> {
+    fn get_or_insert(&'idtable self, name_bytes: &[u8]) -> &'idtable CommandIdentifier<'idtable> {
+        SyncCommandIdentifierTable::get_or_insert(self, name_bytes)
+    }
+}
+
+/// Returns true if `bytes` is a name the lexer reads back as a single control word under the default category codes:
+/// a non-empty sequence of ASCII letters. Names built programmatically (e.g., via `\csname`) may contain other
+/// characters; such commands can't be written as `\name` in the input.
+pub fn is_valid_control_word_name(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(u8::is_ascii_alphabetic)
+}
+
+/// Same as [is_valid_control_word_name] but checks the characters of `bytes` against the letters of
+/// `category_code_table`. Bytes that aren't valid UTF-8 are checked as non-character bytes.
+pub fn is_valid_control_word_name_in(bytes: &[u8], category_code_table: &CategoryCodeTable) -> bool {
+    if bytes.is_empty() {
+        return false;
+    }
+    bytes.utf8_chunks().all(|chunk| {
+        chunk.valid().chars().all(|ch| category_code_table.is_letter(MaybeChar::from_char(ch)))
+            && chunk.invalid().iter().all(|&byte| category_code_table.is_letter(MaybeChar::from_non_char_byte(byte)))
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::collections::HashMap;
+    use crate::category_code::CategoryCode;
 
     #[test]
     fn test_command_identifier_as_utf8_valid() {
@@ -126,6 +257,34 @@ mod tests {
         assert!(!std::ptr::eq(id1, id3));
     }
 
+    #[test]
+    fn test_command_identifier_content_eq() {
+        let table = CommandIdentifierTable::new();
+        let other_table = CommandIdentifierTable::new();
+        let id = table.get_or_insert(b"def");
+        let other_id = other_table.get_or_insert(b"def");
+
+        assert!(id.content_eq(other_id));
+        assert!(!std::ptr::eq(id, other_id));
+        assert!(id.content_eq(id));
+        assert!(!id.content_eq(other_table.get_or_insert(b"edef")));
+    }
+
+    #[test]
+    fn test_command_identifier_equality_with_literals() {
+        let table = CommandIdentifierTable::new();
+        let id = table.get_or_insert(b"def");
+
+        assert!(*id == *b"def");
+        assert!(*id != *b"let");
+        assert!(*id == b"def"[..]);
+        assert!(*id != b"de"[..]);
+        assert!(id == "def");
+        assert!(id != "edef");
+        assert!(*id == "def");
+        assert!(*id != "");
+    }
+
     #[test]
     fn test_command_identifier_hash() {
         let table = CommandIdentifierTable::new();
@@ -169,5 +328,76 @@ mod tests {
         assert!(std::ptr::eq(id1, id2));
         assert_eq!(id1.as_bytes(), id2.as_bytes());
     }
-}
 
+    #[test]
+    fn test_is_valid_control_word_name() {
+        assert!(is_valid_control_word_name(b"alpha"));
+        assert!(!is_valid_control_word_name(b"al1pha"));
+        assert!(!is_valid_control_word_name(b""));
+        assert!(!is_valid_control_word_name("λ".as_bytes()));
+    }
+
+    #[test]
+    fn test_is_valid_control_word_name_in() {
+        let mut table = CategoryCodeTable::new();
+        assert!(is_valid_control_word_name_in(b"alpha", &table));
+        assert!(!is_valid_control_word_name_in(b"make@letter", &table));
+
+        table.set(MaybeChar::from_char('@'), CategoryCode::Letter);
+        table.set(MaybeChar::from_char('λ'), CategoryCode::Letter);
+        assert!(is_valid_control_word_name_in(b"make@letter", &table));
+        assert!(is_valid_control_word_name_in("λx".as_bytes(), &table));
+        assert!(!is_valid_control_word_name_in(&[b'a', 0xFF], &table));
+    }
+
+    #[test]
+    fn test_sync_command_identifier_table_across_threads() {
+        let table = SyncCommandIdentifierTable::new();
+        let names: [&[u8]; 4] = [b"alpha", b"beta", b"gamma", b"delta"];
+
+        // Each thread interns the names in a different order; identifiers are compared by address
+        let addresses: Vec<Vec<usize>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4).map(|offset| {
+                let table = &table;
+                scope.spawn(move || {
+                    let mut addresses = vec![0; names.len()];
+                    for i in 0..names.len() {
+                        let index = (i + offset) % names.len();
+                        let identifier = table.get_or_insert(names[index]);
+                        assert_eq!(identifier.as_bytes(), names[index]);
+                        addresses[index] = identifier as *const CommandIdentifier as usize;
+                    }
+                    addresses
+                })
+            }).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+
+        for thread_addresses in &addresses {
+            assert_eq!(thread_addresses, &addresses[0]);
+        }
+        for (name, &address) in names.iter().zip(&addresses[0]) {
+            assert_eq!(table.get_or_insert(name) as *const CommandIdentifier as usize, address);
+        }
+    }
+
+    #[test]
+    fn test_sync_command_identifier_table_shared_by_lexers() {
+        use crate::{Lexer, Token, TokenKind};
+
+        let table = SyncCommandIdentifierTable::new();
+        let inputs = ["\\foo\\bar", "\\bar\\foo"];
+        std::thread::scope(|scope| {
+            for input in inputs {
+                let table = &table;
+                scope.spawn(move || {
+                    let mut lexer = Lexer::from_bytes(input.as_bytes(), table);
+                    let mut token = Token::default();
+                    lexer.lex(&mut token);
+                    assert_eq!(token.kind(), TokenKind::ControlWord);
+                    assert!(std::ptr::eq(token.command_identifier(), table.get_or_insert(&input.as_bytes()[1..4])));
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,143 @@
+use retex_base::SourceLocation;
+
+/// Severity of a [Diagnostic]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DiagnosticLevel {
+    Warning,
+    Error,
+}
+
+/// Identifies what a [Diagnostic] is about. Each kind has a fixed [DiagnosticLevel] and message, following Clang's
+/// approach of diagnostic IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DiagnosticKind {
+    /// A byte order mark (U+FEFF) appears after the start of the input, usually from a bad concatenation of files
+    MidFileByteOrderMark,
+    /// A control word is ended by an ignored character written in caret notation (e.g., `\te^^?st`), which splits
+    /// what looks like a single control word
+    ControlWordEndedByIgnoredChar,
+    /// A control word is ended by a space character written in caret notation (e.g., `\ab^^I`), which is likely an
+    /// error
+    ControlWordEndedByCaretSpace,
+    /// The file named by `\input` can't be found
+    InputFileNotFound(String),
+    /// The file is larger than the maximum size of a file set on the preprocessor, so it isn't read
+    FileTooLarge(String),
+    /// Reading the file would exceed the total size of the files set on the preprocessor, so it isn't read
+    SourceBudgetExceeded(String),
+    /// An escape character is the last character of the input
+    EscapeCharAtEndOfInput,
+    /// Caret notation (`^^`) is cut off by the end of a line or of the input
+    IncompleteCaretNotation,
+    /// A character with [crate::CategoryCode::Invalid] appears in the input
+    InvalidCharacter,
+    /// The tokens following a macro don't match the parameter text of its definition
+    MacroUseMismatch,
+    /// The input ends, or a group is closed, before an argument of a macro is complete
+    RunawayArgument,
+    /// A parameter character in the replacement text of a definition isn't followed by the number of a parameter or
+    /// another parameter character (e.g., `#x` or `#2` in `\def\a#1{#2}`)
+    IllegalParameterNumber,
+    /// A parameter in the parameter text of a definition isn't numbered after the previous one (e.g., `#3` after `#1`)
+    ParametersNotConsecutive,
+    /// The parameter text of a definition has more than nine parameters
+    TooManyParameters,
+    /// A control word or active character without a meaning is read; it's treated as `\relax`
+    UndefinedCommand,
+    /// A token other than a character appears in the name read by `\csname` or `\ifcsname` before `\endcsname`
+    MissingEndCsname,
+    /// The input ends before the replacement text of a definition is complete (e.g., `\def\a#1` without `{`)
+    RunawayDefinition,
+}
+
+impl DiagnosticKind {
+    pub fn level(&self) -> DiagnosticLevel {
+        match self {
+            DiagnosticKind::MidFileByteOrderMark => DiagnosticLevel::Warning,
+            DiagnosticKind::ControlWordEndedByIgnoredChar => DiagnosticLevel::Warning,
+            DiagnosticKind::ControlWordEndedByCaretSpace => DiagnosticLevel::Warning,
+            DiagnosticKind::InputFileNotFound(_)
+            | DiagnosticKind::FileTooLarge(_)
+            | DiagnosticKind::SourceBudgetExceeded(_)
+            | DiagnosticKind::EscapeCharAtEndOfInput
+            | DiagnosticKind::IncompleteCaretNotation
+            | DiagnosticKind::InvalidCharacter
+            | DiagnosticKind::MacroUseMismatch
+            | DiagnosticKind::RunawayArgument
+            | DiagnosticKind::IllegalParameterNumber
+            | DiagnosticKind::ParametersNotConsecutive
+            | DiagnosticKind::TooManyParameters
+            | DiagnosticKind::UndefinedCommand
+            | DiagnosticKind::MissingEndCsname
+            | DiagnosticKind::RunawayDefinition => DiagnosticLevel::Error,
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            DiagnosticKind::MidFileByteOrderMark => "byte order mark in the middle of the input is ignored".to_string(),
+            DiagnosticKind::ControlWordEndedByIgnoredChar =>
+                "control word is ended by an ignored character in caret notation".to_string(),
+            DiagnosticKind::ControlWordEndedByCaretSpace =>
+                "control word is ended by a space character in caret notation".to_string(),
+            DiagnosticKind::InputFileNotFound(name) => format!("file '{name}' not found"),
+            DiagnosticKind::FileTooLarge(name) => format!("file '{name}' is too large"),
+            DiagnosticKind::SourceBudgetExceeded(name) => format!("reading file '{name}' exceeds the source size limit"),
+            DiagnosticKind::EscapeCharAtEndOfInput => "escape character at the end of input".to_string(),
+            DiagnosticKind::IncompleteCaretNotation => "incomplete caret notation at the end of a line".to_string(),
+            DiagnosticKind::InvalidCharacter => "invalid character".to_string(),
+            DiagnosticKind::MacroUseMismatch => "use of macro doesn't match its definition".to_string(),
+            DiagnosticKind::RunawayArgument => "runaway argument of macro".to_string(),
+            DiagnosticKind::IllegalParameterNumber => "illegal parameter number in definition".to_string(),
+            DiagnosticKind::ParametersNotConsecutive => "parameters must be numbered consecutively".to_string(),
+            DiagnosticKind::TooManyParameters => "a macro can't have more than nine parameters".to_string(),
+            DiagnosticKind::UndefinedCommand => "undefined control sequence".to_string(),
+            DiagnosticKind::MissingEndCsname => "missing \\endcsname".to_string(),
+            DiagnosticKind::RunawayDefinition => "runaway definition".to_string(),
+        }
+    }
+}
+
+/// A diagnostic reported by [crate::Lexer] or [crate::Preprocessor] at a location in the input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    kind: DiagnosticKind,
+    location: SourceLocation,
+}
+
+impl Diagnostic {
+    pub fn new(kind: DiagnosticKind, location: SourceLocation) -> Self {
+        Self { kind, location }
+    }
+
+    pub fn kind(&self) -> &DiagnosticKind {
+        &self.kind
+    }
+
+    pub fn location(&self) -> SourceLocation {
+        self.location
+    }
+
+    pub fn level(&self) -> DiagnosticLevel {
+        self.kind.level()
+    }
+
+    pub fn message(&self) -> String {
+        self.kind.message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diagnostic() {
+        let diagnostic = Diagnostic::new(DiagnosticKind::MidFileByteOrderMark, SourceLocation::new(3));
+
+        assert_eq!(diagnostic.kind(), &DiagnosticKind::MidFileByteOrderMark);
+        assert_eq!(diagnostic.location(), SourceLocation::new(3));
+        assert_eq!(diagnostic.level(), DiagnosticLevel::Warning);
+        assert!(diagnostic.message().contains("byte order mark"));
+    }
+}
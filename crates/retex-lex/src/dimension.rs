@@ -0,0 +1,63 @@
+/// Unit of a TeX dimension (e.g., `pt` in `3pt`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DimensionUnit {
+    /// Point
+    Pt,
+    /// Pica (12pt)
+    Pc,
+    /// Inch (72.27pt)
+    In,
+    /// Centimeter (2.54cm = 1in)
+    Cm,
+    /// Millimeter (10mm = 1cm)
+    Mm,
+    /// Big point (72bp = 1in)
+    Bp,
+    /// Width of the quad of the current font
+    Em,
+    /// x-height of the current font
+    Ex,
+}
+
+impl DimensionUnit {
+    /// Returns the unit with the given two-letter keyword, ignoring case as TeX does
+    pub fn from_keyword(keyword: [u8; 2]) -> Option<Self> {
+        match &keyword.map(|byte| byte.to_ascii_lowercase()) {
+            b"pt" => Some(DimensionUnit::Pt),
+            b"pc" => Some(DimensionUnit::Pc),
+            b"in" => Some(DimensionUnit::In),
+            b"cm" => Some(DimensionUnit::Cm),
+            b"mm" => Some(DimensionUnit::Mm),
+            b"bp" => Some(DimensionUnit::Bp),
+            b"em" => Some(DimensionUnit::Em),
+            b"ex" => Some(DimensionUnit::Ex),
+            _ => None,
+        }
+    }
+
+    pub fn keyword(self) -> &'static str {
+        match self {
+            DimensionUnit::Pt => "pt",
+            DimensionUnit::Pc => "pc",
+            DimensionUnit::In => "in",
+            DimensionUnit::Cm => "cm",
+            DimensionUnit::Mm => "mm",
+            DimensionUnit::Bp => "bp",
+            DimensionUnit::Em => "em",
+            DimensionUnit::Ex => "ex",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dimension_unit_from_keyword() {
+        assert_eq!(DimensionUnit::from_keyword(*b"pt"), Some(DimensionUnit::Pt));
+        assert_eq!(DimensionUnit::from_keyword(*b"IN"), Some(DimensionUnit::In));
+        assert_eq!(DimensionUnit::from_keyword(*b"px"), None);
+        assert_eq!(DimensionUnit::Bp.keyword(), "bp");
+    }
+}
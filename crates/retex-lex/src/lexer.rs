@@ -1,8 +1,12 @@
+use std::collections::HashSet;
 use std::num::NonZeroU8;
-use retex_base::{SourceLocation, MaybeChar, MemoryBuffer};
+use retex_base::{SourceLocation, MaybeChar, MaybeCharEnumView, MemoryBuffer, SourceManager, FileId};
 use crate::token::{Token, TokenKind, TokenFlags, TokenData};
 use crate::category_code::{CategoryCode, CategoryCodeTable};
-use crate::command_identifier::CommandIdentifierTable;
+use crate::command_identifier::{CommandIdentifier, CommandIdentifierInterner, CommandIdentifierTable};
+use crate::diagnostic::{Diagnostic, DiagnosticKind, DiagnosticLevel};
+use crate::token_stream::TokenStream;
+use crate::dimension::DimensionUnit;
 
 /// Convert a hexadecimal character to its numeric value
 fn hex_char_to_value(ch: u8) -> u8 {
@@ -14,6 +18,55 @@ fn hex_char_to_value(ch: u8) -> u8 {
     }
 }
 
+/// UTF-8 encoding of the byte order mark (U+FEFF)
+const UTF8_BYTE_ORDER_MARK: &[u8] = b"\xEF\xBB\xBF";
+
+/// Options of a [Lexer] that are set at construction; see [Lexer::from_bytes_with_config]. Each option can also be
+/// changed later through the corresponding setter of [Lexer].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LexerConfig {
+    /// Character that physical line breaks are read as; see [Lexer::set_endline_char]
+    pub endline_char: Option<MaybeChar>,
+    /// Skip spaces at the start of the input, as at the start of every line
+    pub skip_spaces: bool,
+    /// See [Lexer::set_par_blank_line_threshold]
+    pub par_blank_line_threshold: u32,
+    /// See [Lexer::set_strict]
+    pub strict: bool,
+    /// See [Lexer::set_emit_ignored]
+    pub emit_ignored: bool,
+    /// See [Lexer::set_preserve_leading_spaces]
+    pub preserve_leading_spaces: bool,
+    /// See [Lexer::set_span_space_runs]
+    pub span_space_runs: bool,
+    /// See [Lexer::set_tab_width]
+    pub tab_width: u32,
+    /// See [Lexer::set_collect_stats]
+    pub collect_stats: bool,
+    /// See [Lexer::set_unicode_mode]
+    pub unicode_mode: bool,
+    /// See [Lexer::set_obey_spaces]
+    pub obey_spaces: bool,
+}
+
+impl Default for LexerConfig {
+    fn default() -> Self {
+        Self {
+            endline_char: Some(MaybeChar::from_char('\r')),
+            skip_spaces: true,
+            par_blank_line_threshold: 1,
+            strict: false,
+            emit_ignored: false,
+            preserve_leading_spaces: false,
+            span_space_runs: false,
+            tab_width: 1,
+            collect_stats: false,
+            unicode_mode: false,
+            obey_spaces: false,
+        }
+    }
+}
+
 /// Turns a text buffer into a stream of tokens.
 pub struct Lexer<'source, 'idtable> {
     /// The input bytes being lexed
@@ -22,44 +75,391 @@ pub struct Lexer<'source, 'idtable> {
     category_code_table: CategoryCodeTable,
     /// Start position of the next token to be lexed
     next_token_start_pos: usize,
+    /// Offset added to positions in `input` to form the locations of tokens and diagnostics
+    base_offset: u32,
     /// True if we are at the start of a line
     at_start_of_line: bool,
     /// Discard all space tokens
     skip_spaces: bool,
+    /// Number of consecutive blank lines required to emit a [TokenKind::Paragraph] token
+    par_blank_line_threshold: u32,
+    /// Number of consecutive blank lines seen since the last non-paragraph token
+    consecutive_blank_lines: u32,
+    /// Character that physical line breaks are read as (`\endlinechar`); `None` if line breaks produce no character
+    endline_char: Option<MaybeChar>,
+    /// End-of-line character set by [Lexer::set_endline_char_from_next_line] and the position of the line start from
+    /// which it applies
+    pending_endline_char: Option<(usize, Option<MaybeChar>)>,
     /// Reference to preprocessor for command identifier management
-    command_identifier_table: &'idtable CommandIdentifierTable<'idtable>,
+    command_identifier_table: &'idtable dyn CommandIdentifierInterner<'idtable>,
+    /// Diagnostics reported while lexing
+    diagnostics: Vec<Diagnostic>,
+    /// Report diagnostics for input that TeX accepts silently but is likely a mistake
+    strict: bool,
+    /// Emit a [TokenKind::Unknown] token for each ignored character instead of skipping it silently
+    emit_ignored: bool,
+    /// Emit spaces at the start of a line as [TokenKind::Space] tokens instead of skipping them
+    preserve_leading_spaces: bool,
+    /// `[start, end)` positions of the last run of spaces scanned by [Lexer::has_content_after_spaces] and whether
+    /// content follows it, so the run is scanned once rather than once per space. Cleared when category codes change.
+    scanned_space_run: Option<(usize, usize, bool)>,
+    /// Make a [TokenKind::Space] token span the whole run of spaces it stands for
+    span_space_runs: bool,
+    /// Distance between tab stops used by [Lexer::column]
+    tab_width: u32,
+    /// Collect statistics about the input while lexing
+    collect_stats: bool,
+    /// Number of bytes discarded as ignored or invalid characters; counted only when `collect_stats` is set
+    discarded_byte_count: usize,
+    /// Called on each token just before [Lexer::lex] returns it
+    token_rewriter: Option<TokenRewriter<'idtable>>,
+    /// Category code changes to apply at positions in `input`, ordered by descending position so the next change is
+    /// last; see [Lexer::set_catcode_schedule]
+    catcode_schedule: Vec<(usize, MaybeChar, CategoryCode)>,
+    /// File of the input, recorded on each token; see [Lexer::set_file_id]
+    file_id: Option<FileId>,
+    /// Category codes from before [Lexer::set_plain_text_mode] enabled plain-text mode; `Some` while it's in effect
+    plain_text_mode_saved_table: Option<CategoryCodeTable>,
+    /// Decode UTF-8 sequences in the input into characters instead of reading each byte as a character
+    unicode_mode: bool,
+    /// Emit a [TokenKind::Space] token for every space instead of collapsing and skipping spaces
+    obey_spaces: bool,
 }
 
+/// Rewrites a token lexed by [Lexer]; see [Lexer::set_token_rewriter]
+pub type TokenRewriter<'idtable> = Box<dyn FnMut(&mut Token<'idtable>) + 'idtable>;
+
 impl<'source, 'idtable, 'token> Lexer<'source, 'idtable>
 where
-    'source: 'token,
     'idtable: 'token {
-    pub fn from_bytes(input: &'source [u8], command_identifier_table: &'idtable CommandIdentifierTable<'idtable>) -> Self {
+    pub fn from_bytes(input: &'source [u8], command_identifier_table: &'idtable dyn CommandIdentifierInterner<'idtable>) -> Self {
+        Self::from_bytes_with_config(input, command_identifier_table, LexerConfig::default())
+    }
+
+    pub fn from_bytes_with_config(
+        input: &'source [u8],
+        command_identifier_table: &'idtable dyn CommandIdentifierInterner<'idtable>,
+        config: LexerConfig) -> Self {
+
         Self {
             input,
             category_code_table: CategoryCodeTable::new(),
             next_token_start_pos: 0,
+            base_offset: 0,
             at_start_of_line: true,
-            skip_spaces: true,
+            skip_spaces: config.skip_spaces,
+            par_blank_line_threshold: config.par_blank_line_threshold,
+            consecutive_blank_lines: 0,
+            endline_char: config.endline_char,
+            pending_endline_char: None,
             command_identifier_table,
+            diagnostics: Vec::new(),
+            strict: config.strict,
+            emit_ignored: config.emit_ignored,
+            preserve_leading_spaces: config.preserve_leading_spaces,
+            scanned_space_run: None,
+            span_space_runs: config.span_space_runs,
+            tab_width: config.tab_width.max(1),
+            collect_stats: config.collect_stats,
+            discarded_byte_count: 0,
+            token_rewriter: None,
+            catcode_schedule: Vec::new(),
+            file_id: None,
+            plain_text_mode_saved_table: None,
+            unicode_mode: config.unicode_mode,
+            obey_spaces: config.obey_spaces,
         }
     }
 
-    pub fn from_memory_buffer(buffer: &'source MemoryBuffer, command_identifier_table: &'idtable CommandIdentifierTable<'idtable>) -> Self {
+    /// Creates a lexer for `full_input[start..end]` that lexes the window as if it were the whole input (e.g., starting
+    /// at the start of a line and ending with [TokenKind::Eof] at `end`), but locates tokens at `base_offset + start`
+    /// onwards as if lexing `full_input` at `base_offset`. This lets chunks of a file be lexed independently (e.g., in
+    /// parallel) with the locations of the whole file. Panics if `start..end` isn't a range of `full_input`.
+    pub fn from_bytes_window(
+        full_input: &'source [u8],
+        start: usize,
+        end: usize,
+        base_offset: u32,
+        command_identifier_table: &'idtable dyn CommandIdentifierInterner<'idtable>) -> Self {
+        let mut lexer = Self::from_bytes(&full_input[start..end], command_identifier_table);
+        lexer.base_offset = base_offset + start as u32;
+        lexer
+    }
+
+    pub fn from_memory_buffer(buffer: &'source MemoryBuffer, command_identifier_table: &'idtable dyn CommandIdentifierInterner<'idtable>) -> Self {
         Self::from_bytes(buffer.data(), command_identifier_table)
     }
 
+    pub fn category_code(&self, maybe_char: MaybeChar) -> CategoryCode {
+        self.category_code_table.get(maybe_char)
+    }
+
     pub fn set_category_code(&mut self, maybe_char: MaybeChar, category_code: CategoryCode) {
         self.category_code_table.set(maybe_char, category_code);
+        self.scanned_space_run = None;
+    }
+
+    /// Returns the category code table in use, e.g., to take a [CategoryCodeTable::snapshot] of it
+    pub fn category_code_table(&self) -> &CategoryCodeTable {
+        &self.category_code_table
+    }
+
+    /// Replaces the category code table in use, e.g., to carry the category codes of one lexer over to another
+    pub fn set_category_code_table(&mut self, category_code_table: CategoryCodeTable) {
+        self.category_code_table = category_code_table;
+        self.scanned_space_run = None;
+    }
+
+    /// Runs `f` with the category code of every character set to [CategoryCode::Other], then restores the category
+    /// codes. This scopes verbatim lexing (e.g., for `\verb`) to the closure.
+    pub fn with_all_other<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        self.category_code_table.push_group();
+        self.category_code_table.clear_all(CategoryCode::Other);
+        let result = f(self);
+        self.category_code_table.pop_group();
+        result
+    }
+
+    /// Enables plain-text mode, a preset for extracting text: the category codes of characters that give structure to
+    /// TeX input (groups, math shifts, alignment tabs, parameters, superscripts, subscripts, active characters and
+    /// comments) are changed to [CategoryCode::Other], so only letters, others, spaces and line breaks remain while
+    /// control sequences are still lexed as such. Disabling it restores the category codes from before it was enabled,
+    /// undoing changes made in the meantime. Disabled by default.
+    pub fn set_plain_text_mode(&mut self, on: bool) {
+        if !on {
+            if let Some(saved_table) = self.plain_text_mode_saved_table.take() {
+                self.category_code_table = saved_table;
+            }
+            return;
+        }
+        if self.plain_text_mode_saved_table.is_some() {
+            return;
+        }
+        self.plain_text_mode_saved_table = Some(self.category_code_table.clone());
+        for category_code in [
+            CategoryCode::BeginGroup,
+            CategoryCode::EndGroup,
+            CategoryCode::MathShift,
+            CategoryCode::AlignmentTab,
+            CategoryCode::Parameter,
+            CategoryCode::Superscript,
+            CategoryCode::Subscript,
+            CategoryCode::Active,
+            CategoryCode::Comment,
+        ] {
+            self.category_code_table.replace(category_code, CategoryCode::Other);
+        }
+    }
+
+    /// Sets the character that physical line breaks ("\r\n", "\r" or "\n") are read as, like TeX's `\endlinechar`.
+    /// `None` makes line breaks produce no character at all (`\endlinechar=-1`). Defaults to `\r`.
+    pub fn set_endline_char(&mut self, endline_char: Option<MaybeChar>) {
+        self.endline_char = endline_char;
+        self.pending_endline_char = None;
+    }
+
+    /// Same as [Lexer::set_endline_char] but only from the next line read, like an assignment to `\endlinechar` in TeX,
+    /// which appends the end-of-line character to a line when reading it. The line whose characters are being read
+    /// keeps its end-of-line character; if its line break has been read already, the character applies to the line
+    /// after it.
+    pub fn set_endline_char_from_next_line(&mut self, endline_char: Option<MaybeChar>) {
+        let pos = self.next_token_start_pos;
+        let line_break_read = pos == 0 || (pos <= self.input.len() && matches!(self.input[pos - 1], b'\r' | b'\n'));
+        if line_break_read {
+            self.set_endline_char(endline_char);
+            return;
+        }
+        let next_line_start = (pos..self.input.len())
+            .find_map(|line_break| self.line_break_size(line_break).map(|size| line_break + size))
+            .unwrap_or(self.input.len());
+        self.pending_endline_char = Some((next_line_start, endline_char));
+    }
+
+    pub fn endline_char(&self) -> Option<MaybeChar> {
+        self.endline_char
+    }
+
+    /// Sets the file that the input belongs to, which is recorded on each token formed (see [Token::file_id]). `None`
+    /// by default.
+    pub fn set_file_id(&mut self, file_id: Option<FileId>) {
+        self.file_id = file_id;
+    }
+
+    /// Sets how many consecutive blank lines are required to emit a [TokenKind::Paragraph] token. Defaults to 1,
+    /// matching TeX where every blank line produces a `\par`. Above 1, a run of blank lines produces a single
+    /// [TokenKind::Paragraph] token at its `n`-th line however long it is.
+    pub fn set_par_blank_line_threshold(&mut self, n: u32) {
+        self.par_blank_line_threshold = n;
+    }
+
+    /// Enables strict mode, in which the lexer reports diagnostics for input that TeX accepts silently but is likely a
+    /// mistake. Malformed constructs (an escape character at the end of input, incomplete caret notation and invalid
+    /// characters) are reported as errors and their tokens carry [TokenFlags::ERROR]; invalid characters, which are
+    /// otherwise discarded, produce [TokenKind::Unknown] tokens.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Enables emitting a [TokenKind::Unknown] token with the location and length of each ignored character (e.g., NUL
+    /// and DEL) instead of skipping it silently. Meant for teaching and debugging; disabled by default.
+    pub fn set_emit_ignored(&mut self, emit_ignored: bool) {
+        self.emit_ignored = emit_ignored;
+    }
+
+    /// Enables emitting a [TokenKind::Space] token for each space at the start of a line instead of skipping them as
+    /// TeX does, for formats where indentation is significant. Lines with nothing but spaces (and comments) are still
+    /// treated as blank. Disabled by default.
+    pub fn set_preserve_leading_spaces(&mut self, on: bool) {
+        self.preserve_leading_spaces = on;
+    }
+
+    /// Makes a [TokenKind::Space] token for a run of spaces (e.g., spaces and tabs) span the whole run instead of just
+    /// its first space, for tools that need the original extent of whitespace. Spaces are still collapsed into one
+    /// token and dropped before the end of a line; only the length of the token changes. Disabled by default.
+    pub fn set_span_space_runs(&mut self, on: bool) {
+        self.span_space_runs = on;
+    }
+
+    /// Enables obey-spaces mode, like TeX's `\obeyspaces`: every character with the category code
+    /// [CategoryCode::Space] produces a [TokenKind::Space] token of its own. Spaces are neither collapsed nor skipped,
+    /// whether at the start or end of a line or after a control word. Tabs follow their category code as usual (they
+    /// are spaces by default). Disabled by default.
+    pub fn set_obey_spaces(&mut self, on: bool) {
+        self.obey_spaces = on;
+    }
+
+    /// Returns whether the spaces starting at `pos` are followed by content on the same line, i.e., something other
+    /// than the end of the line, a comment or the end of input
+    fn has_content_after_spaces(&mut self, pos: usize) -> bool {
+        if let Some((start, end, has_content)) = self.scanned_space_run
+            && (start..end).contains(&pos) {
+            return has_content;
+        }
+
+        let mut end = pos;
+        let mut has_content = false;
+        while let Some(ch) = self.peek_char(end) {
+            if !self.category_code_table.is_space_or_ignored(ch) {
+                has_content = self.line_break_size(end).is_none()
+                    && !matches!(self.category_code_table.get(ch), CategoryCode::EndOfLine | CategoryCode::Comment);
+                break;
+            }
+            self.consume_char(&mut end);
+        }
+        self.scanned_space_run = Some((pos, end, has_content));
+        has_content
+    }
+
+    /// Enables unicode mode, in which a UTF-8 sequence in the input is read as a single character spanning all of its
+    /// bytes (e.g., `α` is one [TokenKind::Other] token of length 2), like XeTeX does. A byte that doesn't start a
+    /// valid sequence, including a sequence truncated by the end of input, is read as a [MaybeChar::NonCharByte].
+    /// Disabled by default, in which case each byte is read as a character of its own, like TeX does.
+    pub fn set_unicode_mode(&mut self, on: bool) {
+        self.unicode_mode = on;
+    }
+
+    pub fn is_unicode_mode(&self) -> bool {
+        self.unicode_mode
+    }
+
+    /// Sets the distance between tab stops used when reporting columns with [Lexer::column]. The default of 1 counts a
+    /// tab as a single column, matching byte offsets; 8 gives visual columns. This only affects column reporting, not
+    /// tokenization: a tab is still read as a space.
+    pub fn set_tab_width(&mut self, tab_width: u32) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// Returns the 1-based column of a location in the input of this lexer, e.g., of a token. Columns count bytes
+    /// except that a tab advances to the next tab stop (see [Lexer::set_tab_width]). Returns `None` if the location
+    /// isn't in the input.
+    pub fn column(&self, location: SourceLocation) -> Option<u32> {
+        let pos = location.offset().checked_sub(self.base_offset)? as usize;
+        if pos > self.input.len() {
+            return None;
+        }
+
+        let line_start = self.input[..pos].iter().rposition(|&ch| ch == b'\r' || ch == b'\n').map_or(0, |i| i + 1);
+        let mut column = 1;
+        for &ch in &self.input[line_start..pos] {
+            if ch == b'\t' {
+                column = (column - 1) / self.tab_width * self.tab_width + self.tab_width + 1;
+            } else {
+                column += 1;
+            }
+        }
+        Some(column)
+    }
+
+    /// Enables collecting statistics about the input (e.g., [Lexer::discarded_byte_count]) while lexing. Disabled by
+    /// default to keep lexing lean.
+    pub fn set_collect_stats(&mut self, collect_stats: bool) {
+        self.collect_stats = collect_stats;
+    }
+
+    /// Returns the number of input bytes discarded as ignored or invalid characters so far. Only counted while
+    /// statistics are collected (see [Lexer::set_collect_stats]).
+    pub fn discarded_byte_count(&self) -> usize {
+        self.discarded_byte_count
+    }
+
+    fn count_discarded_bytes(&mut self, size: usize) {
+        if self.collect_stats {
+            self.discarded_byte_count += size;
+        }
+    }
+
+    /// Returns whether any diagnostic of [DiagnosticLevel::Error] has been reported
+    pub fn had_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diagnostic| diagnostic.level() == DiagnosticLevel::Error)
+    }
+
+    /// Get the diagnostics reported so far
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    fn report(&mut self, kind: DiagnosticKind, pos: usize) {
+        self.diagnostics.push(Diagnostic::new(kind, self.location_of(pos)));
+    }
+
+    /// Moves the lexer to the given byte offset in the input. The lexer state is reset as if a new line starts at the
+    /// offset, so this is meant to be used with offsets of line starts.
+    pub fn seek(&mut self, pos: usize) {
+        self.next_token_start_pos = pos.min(self.input.len());
+        self.at_start_of_line = true;
+        self.skip_spaces = true;
+    }
+
+    /// Lexes the tokens on the given 1-based line of `file_id` and appends them to `out`. The lexer must be lexing the
+    /// buffer of `file_id`. Tokens starting past the end of the line (including the line terminator) are not collected
+    /// and the lexer is left positioned after the last token read.
+    pub fn tokens_on_line(&mut self, line: u32, sm: &SourceManager, file_id: FileId, out: &mut Vec<Token<'token>>) {
+        let Some((line_start, line_end)) = sm.get_line_range(file_id, line) else {
+            return;
+        };
+
+        self.seek(line_start as usize);
+
+        let mut token = Token::default();
+        loop {
+            self.lex(&mut token);
+            if token.is(TokenKind::Eof) || token.location().offset() >= self.base_offset + line_end {
+                break;
+            }
+            out.push(token.clone());
+        }
     }
 
 
     /// Reads a "logical" character from input. This applies transformation on the input that lexer sees.
     /// This includes: skipping \n next to \r and reducing expanded character like ^^A. Returns a 3-tuple: the byte
     /// being read, number of bytes occupied by the returning byte in the input and a boolean flag indicating if any
-    /// transformed have been applied on the input while reading the returning byte.
-    ///
-    /// TODO: Validate and turn bytes into Unicode char when possible like XeTeX to support unicode:
+    /// transformed have been applied on the input while reading the returning byte. In unicode mode (see
+    /// [Lexer::set_unicode_mode]), a UTF-8 sequence is read as a whole like XeTeX does:
     /// https://github.com/TeX-Live/texlive-source/blob/2ebb86c/texk/web2c/lib/texmfmp.c#L2657-L2658
     fn get_char_and_size(&self, current_pos: usize) -> Option<(MaybeChar, usize, bool)> {
         if current_pos >= self.input.len() {
@@ -78,7 +478,7 @@ where
                 let hex2 = self.input[current_pos + 3];
                 if hex1.is_ascii_hexdigit() && hex2.is_ascii_hexdigit() {
                     let decoded = (hex_char_to_value(hex1) << 4) | hex_char_to_value(hex2);
-                    return Some((MaybeChar::from_char(decoded as char), 4, true));
+                    return Some((MaybeChar::from_u8(decoded), 4, true));
                 }
             }
 
@@ -88,17 +488,69 @@ where
             } else {
                 third_char + 64  // ^^? becomes 127, etc.
             };
-            return Some((MaybeChar::from_char(decoded as char), 3, true));
+            return Some((MaybeChar::from_u8(decoded), 3, true));
+        }
+
+        // Read a physical line break as the end-of-line character. Skip \n next to \r. This follows logic in current
+        // TeX engine.
+        if let Some(size) = self.line_break_size(current_pos)
+            && let Some(endline_char) = self.endline_char {
+            let is_transformed = size > 1 || endline_char != MaybeChar::from_char(ch as char);
+            return Some((endline_char, size, is_transformed));
         }
 
-        // Skip \n next to \r. This follows logic in current TeX engine, for example:
-        if ch == b'\r' && current_pos + 1 < self.input.len() && self.input[current_pos + 1] == b'\n' {
-            return Some((MaybeChar::from_char('\r'), 2, true));
+        if self.unicode_mode && !ch.is_ascii() {
+            return MaybeChar::decode_utf8(&self.input[current_pos..])
+                .map(|(maybe_char, size)| (maybe_char, size, false));
         }
 
         Some((MaybeChar::from_char(ch as char), 1, false))
     }
 
+    /// Returns whether caret notation at the position is cut off by the end of the line or the input: `^^` followed by
+    /// a line break (which is then read as the character in caret notation) or by nothing, or `^^` and a single
+    /// lowercase hex digit at the end of the input, which is likely the start of `^^` and two hex digits. As in TeX,
+    /// uppercase letters aren't hex digits here, so `^^A` is complete.
+    fn is_incomplete_caret_notation(&self, current_pos: usize) -> bool {
+        let rest = &self.input[current_pos..];
+        if !rest.starts_with(b"^^") {
+            return false;
+        }
+        match rest.get(2) {
+            None | Some(b'\r' | b'\n') => true,
+            Some(digit) => matches!(digit, b'0'..=b'9' | b'a'..=b'f') && rest.len() == 3,
+        }
+    }
+
+    /// Returns the number of bytes of the physical line break ("\r\n", "\r" or "\n") at the position if any.
+    fn line_break_size(&self, current_pos: usize) -> Option<usize> {
+        match self.input.get(current_pos) {
+            Some(b'\r') if self.input.get(current_pos + 1) == Some(&b'\n') => Some(2),
+            Some(b'\r') | Some(b'\n') => Some(1),
+            _ => None,
+        }
+    }
+
+    /// Returns the character read at `location` (e.g., the start of a token), decoding caret notation. Returns `None`
+    /// if the location isn't in the input of this lexer.
+    pub fn char_at(&self, location: SourceLocation) -> Option<MaybeChar> {
+        let pos = location.offset().checked_sub(self.base_offset)?;
+        self.peek_char(pos as usize)
+    }
+
+    /// Iterates over the characters of the whole input as the lexer reads them, independent of category codes and the
+    /// position of the lexer. Each item is the location of a character, the character after caret notation decoding and
+    /// line break translation (see [Lexer::set_endline_char]), and the number of source bytes it spans.
+    pub fn logical_chars(&self) -> impl Iterator<Item = (SourceLocation, MaybeChar, usize)> + '_ {
+        let mut pos = 0;
+        std::iter::from_fn(move || {
+            let (maybe_char, size, _) = self.get_char_and_size(pos)?;
+            let location = self.location_of(pos);
+            pos += size;
+            Some((location, maybe_char, size))
+        })
+    }
+
     fn peek_char(&self, current_pos: usize) -> Option<MaybeChar> {
         self.get_char_and_size(current_pos).map(|(maybe_char, _, _)| maybe_char)
     }
@@ -110,6 +562,80 @@ where
         *current_pos
     }
 
+    /// Scans a dimension at the current position: optional spaces, an optional sign, a decimal number (with `.` or `,`
+    /// as the decimal separator) and a unit keyword (e.g., `3pt` or `-1.5 in`), followed by one optional space. The
+    /// input is read as characters rather than tokens, bypassing category codes. Returns `None` without consuming
+    /// anything if no dimension is found.
+    pub fn scan_dimension(&mut self) -> Option<(f64, DimensionUnit)> {
+        let ascii_at = |pos: usize| self.peek_char(pos).and_then(|ch| ch.as_ascii_byte());
+        let mut pos = self.next_token_start_pos;
+        let skip_spaces = |pos: &mut usize| {
+            while ascii_at(*pos).is_some_and(|byte| byte == b' ' || byte == b'\t') {
+                self.consume_char(pos);
+            }
+        };
+
+        skip_spaces(&mut pos);
+        let mut number = String::new();
+        while let Some(sign @ (b'+' | b'-')) = ascii_at(pos) {
+            if sign == b'-' {
+                number = if number.is_empty() { "-".to_string() } else { String::new() };
+            }
+            self.consume_char(&mut pos);
+            skip_spaces(&mut pos);
+        }
+
+        let mut has_digits = false;
+        let mut has_separator = false;
+        while let Some(byte) = ascii_at(pos) {
+            match byte {
+                b'0'..=b'9' => {
+                    has_digits = true;
+                    number.push(byte as char);
+                },
+                b'.' | b',' if !has_separator => {
+                    has_separator = true;
+                    number.push('.');
+                },
+                _ => break,
+            }
+            self.consume_char(&mut pos);
+        }
+        if !has_digits {
+            return None;
+        }
+        let value: f64 = number.parse().ok()?;
+
+        skip_spaces(&mut pos);
+        let first = ascii_at(pos)?;
+        self.consume_char(&mut pos);
+        let second = ascii_at(pos)?;
+        self.consume_char(&mut pos);
+        let unit = DimensionUnit::from_keyword([first, second])?;
+        if ascii_at(pos).is_some_and(|byte| byte == b' ') {
+            self.consume_char(&mut pos);
+        }
+
+        self.next_token_start_pos = pos;
+        self.at_start_of_line = false;
+        self.skip_spaces = false;
+        Some((value, unit))
+    }
+
+    /// Switches the lexer to `input` so a configured lexer can be reused (e.g., across included files) without
+    /// reconstruction. Locations of tokens read from `input` start at `base_offset`. The category codes, other options
+    /// and the command identifier table are kept; the lexer starts at the start of a line as a new lexer does.
+    pub fn rebind(&mut self, input: &'source [u8], base_offset: u32) {
+        self.input = input;
+        self.base_offset = base_offset;
+        self.consecutive_blank_lines = 0;
+        self.seek(0);
+    }
+
+    fn location_of(&self, pos: usize) -> SourceLocation {
+        SourceLocation::new(self.base_offset + pos as u32)
+    }
+
     /// Forms a token with the given kind using the current token's start and end positions.
     /// Updates next_token_start_pos to prepare for the next token.
     fn form_token_with_data<'a>(
@@ -119,12 +645,17 @@ where
         token_data: TokenData<'a>,
         cur_token_end_pos: usize) {
 
-        let start_location = SourceLocation::new(self.next_token_start_pos as u32);
+        let start_location = self.location_of(self.next_token_start_pos);
 
         token.set_kind(kind);
         token.set_location(start_location);
         token.set_length((cur_token_end_pos - self.next_token_start_pos) as u32);
         token.set_token_data(token_data);
+        token.set_file_id(self.file_id);
+
+        if kind != TokenKind::Paragraph {
+            self.consecutive_blank_lines = 0;
+        }
 
         // Update start position for next token
         self.next_token_start_pos = cur_token_end_pos;
@@ -144,7 +675,10 @@ where
         self.form_token_with_data(
             token,
             kind,
-            TokenData::Char(ch.as_char().unwrap_or(char::REPLACEMENT_CHARACTER)),
+            match ch.enum_view() {
+                MaybeCharEnumView::Char(ch) => TokenData::Char(ch),
+                MaybeCharEnumView::NonCharByte(byte) => TokenData::NonCharByte(byte),
+            },
             cur_token_end_pos);
     }
 
@@ -189,11 +723,18 @@ where
                 // Control symbol: read one character and skip subsequence spaces after a control space (an escape char
                 // followed by a space: "\ ").
                 self.skip_spaces = self.category_code_table.is_space(maybe_char);
+                if is_transformed {
+                    token.set_flag(TokenFlags::TRANSFORMED);
+                }
                 let symbol_data = TokenData::Symbol(Some(maybe_char));
                 self.form_token_with_data(token, TokenKind::ControlSymbol, symbol_data, *current_pos);
             }
         } else {
             // End of input after backslash - treat as control symbol with no symbol
+            if self.strict {
+                token.set_flag(TokenFlags::ERROR);
+                self.report(DiagnosticKind::EscapeCharAtEndOfInput, self.next_token_start_pos);
+            }
             self.form_token_with_data(token, TokenKind::ControlSymbol, TokenData::Symbol(None), *current_pos);
         }
     }
@@ -221,12 +762,16 @@ where
             None
         };
 
-        while owned_name_bytes.is_none() {
+        // A line break read as a letter (see [Lexer::set_endline_char]) is the last letter of the control word
+        let mut at_end_of_line = false;
+
+        while owned_name_bytes.is_none() && !at_end_of_line {
             if let Some((ch, _, is_transformed)) = self.get_char_and_size(*current_pos) {
-                if !self.category_code_table.is_letter(ch) {
+                if !self.continues_control_word(*current_pos, ch) {
                     break
                 }
 
+                at_end_of_line = self.line_break_size(*current_pos).is_some();
                 if is_transformed {
                     let control_word_bytes = &self.input[control_word_start..*current_pos];
                     owned_name_bytes = Some(control_word_bytes.to_vec());
@@ -240,8 +785,9 @@ where
 
         // Continue collecting letters if we have owned bytes
         if let Some(ref mut owned_bytes) = owned_name_bytes {
-            while let Some((ch, _, _)) = self.get_char_and_size(*current_pos) {
-                if self.category_code_table.is_letter(ch) {
+            while !at_end_of_line && let Some((ch, _, _)) = self.get_char_and_size(*current_pos) {
+                if self.continues_control_word(*current_pos, ch) {
+                    at_end_of_line = self.line_break_size(*current_pos).is_some();
                     owned_bytes.extend_from_slice(ch.encode_utf8(&mut utf8_buffer));
                     self.consume_char(current_pos);
                 } else {
@@ -262,6 +808,28 @@ where
 
         // After reading a control word, switch to skipping spaces state
         self.skip_spaces = true;
+        if at_end_of_line {
+            self.at_start_of_line = true;
+        }
+
+        // A space character in caret notation (e.g., `^^I`) ends the control word like a space does, but it's unlikely
+        // to have been written on purpose; line breaks are read as transformed characters too but end lines as usual
+        if self.strict
+            && let Some((ch, _, true)) = self.get_char_and_size(*current_pos)
+            && self.line_break_size(*current_pos).is_none() {
+            match self.category_code_table.get(ch) {
+                CategoryCode::Ignored => self.report(DiagnosticKind::ControlWordEndedByIgnoredChar, *current_pos),
+                CategoryCode::Space => self.report(DiagnosticKind::ControlWordEndedByCaretSpace, *current_pos),
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns whether the character `ch` read at `pos` continues a control word. Like in TeX, a control word never
+    /// spans lines: a physical line break only continues it when the end-of-line character it's read as is a letter
+    /// (and then ends it), even if the line break itself has the category code of a letter.
+    fn continues_control_word(&self, pos: usize, ch: MaybeChar) -> bool {
+        self.category_code_table.is_letter(ch) && (self.endline_char.is_some() || self.line_break_size(pos).is_none())
     }
 
     /// We just read a parameter character (#) that may start a parameter token.
@@ -272,26 +840,140 @@ where
 
         // Check if followed by a digit
         let mut parameter_data = TokenData::ParameterIndex(None);
-        if let Some(ch) = self.peek_char(*current_pos) {
-            if let Some(c) = ch.as_char().filter(|c| c.is_ascii_digit()) {
-                parameter_data = TokenData::ParameterIndex(NonZeroU8::new(c as u8 - b'0'));
-                self.consume_char(current_pos);
-            }
+        if let Some(ch) = self.peek_char(*current_pos)
+            && let Some(c) = ch.as_char().filter(|c| c.is_ascii_digit()) {
+            parameter_data = TokenData::ParameterIndex(NonZeroU8::new(c as u8 - b'0'));
+            self.consume_char(current_pos);
         }
 
         self.form_token_with_data(token, TokenKind::Parameter, parameter_data, *current_pos);
     }
 
-    pub fn lex(&mut self, token: &mut Token<'token>) {
+    /// Lexes the next token into a newly created [Token]. This is a convenience for one-off calls where performance
+    /// isn't critical; [Lexer::lex] allows reusing a token instead.
+    pub fn next_token(&mut self) -> Token<'token> {
+        let mut token = Token::default();
+        self.lex(&mut token);
+        token
+    }
+
+    /// Lexes the rest of the input into a columnar [TokenStream]. The final [TokenKind::Eof] token is not included.
+    pub fn lex_into_stream(&mut self) -> TokenStream {
+        let mut stream = TokenStream::new();
+        let mut token = Token::default();
+        loop {
+            self.lex(&mut token);
+            if token.is(TokenKind::Eof) {
+                break;
+            }
+            stream.push(&token);
+        }
+        stream
+    }
+
+    /// Lexes the rest of the input and returns the distinct identifiers of its [TokenKind::ControlWord] and
+    /// [TokenKind::ActiveChar] tokens in the order they first appear, e.g., to list the commands a document uses
+    pub fn collect_control_words(&mut self) -> Vec<&'idtable CommandIdentifier<'idtable>> {
+        let mut seen = HashSet::new();
+        let mut identifiers = Vec::new();
+        let mut token = Token::default();
+        loop {
+            self.lex(&mut token);
+            match token.kind() {
+                TokenKind::Eof => return identifiers,
+                TokenKind::ControlWord | TokenKind::ActiveChar => {
+                    let id = token.command_identifier();
+                    if seen.insert(id) {
+                        identifiers.push(id);
+                    }
+                },
+                _ => (),
+            }
+        }
+    }
+
+    /// Installs a hook that is called on each token just before [Lexer::lex] returns it, for transformation pipelines
+    /// (e.g., mapping deprecated commands to their replacements). The rewriter may change the kind and data of the
+    /// token, but lexing continues from where the original token ended.
+    pub fn set_token_rewriter(&mut self, rewriter: TokenRewriter<'idtable>) {
+        self.token_rewriter = Some(rewriter);
+    }
+
+    /// Schedules category code changes, e.g., to replay a document that changes category codes mid-stream without a
+    /// preprocessor. Each `(offset, character, category code)` entry is applied once the next token starts at or after
+    /// `offset` (a position in the input). Replaces previously scheduled changes that haven't been applied yet.
+    pub fn set_catcode_schedule(&mut self, mut schedule: Vec<(usize, MaybeChar, CategoryCode)>) {
+        // Changes at the same offset are applied in the given order
+        schedule.sort_by_key(|(offset, _, _)| *offset);
+        schedule.reverse();
+        self.catcode_schedule = schedule;
+    }
+
+    /// Applies the end-of-line character set by [Lexer::set_endline_char_from_next_line] once the next token starts on
+    /// the line it applies from
+    fn apply_pending_endline_char(&mut self) {
+        if let Some((line_start, endline_char)) = self.pending_endline_char
+            && self.next_token_start_pos >= line_start {
+            self.set_endline_char(endline_char);
+        }
+    }
+
+    /// Applies the scheduled category code changes up to the start of the next token
+    fn apply_catcode_schedule(&mut self) {
+        while let Some(&(offset, maybe_char, category_code)) = self.catcode_schedule.last()
+            && offset <= self.next_token_start_pos {
+            self.category_code_table.set(maybe_char, category_code);
+            self.scanned_space_run = None;
+            self.catcode_schedule.pop();
+        }
+    }
+
+    pub fn lex(&mut self, token: &mut Token<'idtable>) {
+        self.lex_token(token);
+        if let Some(rewriter) = self.token_rewriter.as_mut() {
+            rewriter(token);
+        }
+    }
+
+    fn lex_token(&mut self, token: &mut Token<'token>) {
         token.reset();
 
         loop {
+            self.apply_pending_endline_char();
+            self.apply_catcode_schedule();
             let mut current_pos = self.next_token_start_pos;
 
+            // Whether the lexer is in TeX's state S (skipping blanks), e.g., after a control word
+            let skipping_spaces = self.skip_spaces;
             if self.skip_spaces {
                 while let Some(ch) = self.peek_char(current_pos) {
+                    if self.emit_ignored && self.category_code_table.is_ignored(ch) {
+                        // Spaces after the ignored character are still skipped on the next call
+                        self.next_token_start_pos = current_pos;
+                        self.form_token(token, TokenKind::Unknown, self.consume_char(&mut current_pos));
+                        return;
+                    }
+                    if self.preserve_leading_spaces
+                        && self.category_code_table.is_space(ch)
+                        && self.has_content_after_spaces(current_pos) {
+                        // Emit each leading space by itself; the remaining spaces are read by the next calls
+                        self.next_token_start_pos = current_pos;
+                        if self.at_start_of_line {
+                            token.set_flag(TokenFlags::START_OF_LINE);
+                            self.at_start_of_line = false;
+                        }
+                        self.form_token(token, TokenKind::Space, self.consume_char(&mut current_pos));
+                        return;
+                    }
+                    if self.obey_spaces && self.category_code_table.is_space(ch) {
+                        break;
+                    }
                     if self.category_code_table.is_space_or_ignored(ch) {
+                        let start_pos = current_pos;
                         self.consume_char(&mut current_pos);
+                        if self.category_code_table.is_ignored(ch) {
+                            self.count_discarded_bytes(current_pos - start_pos);
+                        }
                     } else {
                         break;
                     }
@@ -302,7 +984,14 @@ where
             // Skip any ignored character.
             while let Some(ch) = self.peek_char(current_pos) {
                 if self.category_code_table.is_ignored(ch) {
+                    if self.emit_ignored {
+                        self.next_token_start_pos = current_pos;
+                        self.form_token(token, TokenKind::Unknown, self.consume_char(&mut current_pos));
+                        return;
+                    }
+                    let start_pos = current_pos;
                     self.consume_char(&mut current_pos);
+                    self.count_discarded_bytes(current_pos - start_pos);
                 } else {
                     break;
                 }
@@ -319,9 +1008,40 @@ where
 
             let mut current_pos = self.next_token_start_pos;
 
+            // A byte order mark is treated as zero-width. It is expected at the very start of the input, anywhere else
+            // it is likely a result of concatenating files.
+            if self.input[current_pos..].starts_with(UTF8_BYTE_ORDER_MARK) {
+                if current_pos != 0 {
+                    self.report(DiagnosticKind::MidFileByteOrderMark, current_pos);
+                }
+                self.next_token_start_pos = current_pos + UTF8_BYTE_ORDER_MARK.len();
+                continue;
+            }
+
+            let line_break_size = self.line_break_size(current_pos);
+            if let Some(size) = line_break_size && self.endline_char.is_none() {
+                // Without an end-of-line character, a line break just starts a new line.
+                self.next_token_start_pos = current_pos + size;
+                self.at_start_of_line = true;
+                self.skip_spaces = true;
+                continue;
+            }
+
             if let Some(ch) = self.peek_char(current_pos) {
                 let category_code = self.category_code_table.get(ch);
 
+                if self.strict && self.is_incomplete_caret_notation(current_pos) {
+                    token.set_flag(TokenFlags::ERROR);
+                    self.report(DiagnosticKind::IncompleteCaretNotation, current_pos);
+                }
+
+                if line_break_size.is_some() && category_code != CategoryCode::EndOfLine {
+                    // The end-of-line character has been reconfigured to something else; the next token still begins
+                    // a new line.
+                    self.at_start_of_line = true;
+                    self.skip_spaces = true;
+                }
+
                 // Process the character based on its category code and current state
                 match category_code {
                     CategoryCode::Escape => {
@@ -352,9 +1072,30 @@ where
                             // Insert space token when encountering a newline in the middle of line.
                             TokenKind::Space
                         };
-                        self.form_token(token, token_kind, self.consume_char(&mut current_pos));
+                        let end_pos = self.consume_char(&mut current_pos);
 
-                        if ch != MaybeChar::from_char('\r') && ch != MaybeChar::from_char('\n') {
+                        // Only emit a \par once enough consecutive blank lines have been seen, and only once per run
+                        // above the default threshold. Like in TeX, the end of a line where spaces are being skipped
+                        // (e.g., after a control word) produces nothing.
+                        let mut emit_token = !skipping_spaces || token_kind == TokenKind::Paragraph;
+                        if token_kind == TokenKind::Paragraph {
+                            self.consecutive_blank_lines += 1;
+                            emit_token = if self.par_blank_line_threshold <= 1 {
+                                true
+                            } else {
+                                self.consecutive_blank_lines == self.par_blank_line_threshold
+                            };
+                        }
+                        if emit_token {
+                            self.form_token(token, token_kind, end_pos);
+                            if token_kind == TokenKind::Space {
+                                token.set_flag(TokenFlags::SOFT_BREAK);
+                            }
+                        } else {
+                            self.next_token_start_pos = end_pos;
+                        }
+
+                        if line_break_size.is_none() {
                             // This follows how existing TeX engine works where input line is identified by \r and \n
                             // and bytes in the line after CategoryCode::EndOfLine are discarded.
                             self.finish_line();
@@ -362,6 +1103,10 @@ where
                             self.at_start_of_line = true;
                             self.skip_spaces = true;
                         }
+
+                        if !emit_token {
+                            continue;
+                        }
                         return
                     },
                     CategoryCode::Parameter => {
@@ -380,12 +1125,17 @@ where
                         // Ignored characters have been skipped at the beginning of the loop.
                         unreachable!()
                     },
+                    CategoryCode::Space if self.obey_spaces => {
+                        self.form_token(token, TokenKind::Space, self.consume_char(&mut current_pos));
+                        return;
+                    },
                     CategoryCode::Space => {
                         // Skip spaces before EOL or EOF according to TeX rules - only emit a space token if we hit
                         // bytes other than space, EOL and EOF
 
                         // Form a token so in the case where we need to emit a space token for this space, the output
                         // token refers to the first space
+                        let start_pos = current_pos;
                         self.form_token(token, TokenKind::Space, self.consume_char(&mut current_pos));
 
                         // Skip all subsequent spaces
@@ -397,7 +1147,8 @@ where
                             }
 
                             // Only emit a space token if encountering a non-EOL bytes
-                            emit_space_token = !self.category_code_table.is_eol(next_ch);
+                            emit_space_token = !self.category_code_table.is_eol(next_ch)
+                                && self.line_break_size(current_pos).is_none();
                             break;
                         }
 
@@ -408,6 +1159,10 @@ where
                             continue;
                         }
 
+                        if self.span_space_runs {
+                            token.set_length((current_pos - start_pos) as u32);
+                        }
+
                         // Note the token has been formed at the beginning of the case, so just return
                         return;
                     },
@@ -434,10 +1189,17 @@ where
                         continue;
                     },
                     CategoryCode::Invalid => {
+                        if self.strict {
+                            token.set_flag(TokenFlags::ERROR);
+                            self.report(DiagnosticKind::InvalidCharacter, current_pos);
+                            self.form_token(token, TokenKind::Unknown, self.consume_char(&mut current_pos));
+                            return;
+                        }
+
                         // Skip invalid char.
-                        //
-                        // TODO: Add diagnosis instead of discarding silently.
+                        let start_pos = current_pos;
                         self.consume_char(&mut current_pos);
+                        self.count_discarded_bytes(current_pos - start_pos);
                         self.next_token_start_pos = current_pos;
                         continue;
                     },
@@ -450,3 +1212,77 @@ where
         }
     }
 }
+
+/// Splits `input` into contiguous `(start, end)` byte ranges of about `approx_chunk_bytes` each (at least 1) that end
+/// at line boundaries, i.e., after a line terminator ("\r\n", "\r" or "\n") or at the end of the input. The ranges
+/// cover the whole input in order; a line longer than `approx_chunk_bytes` is kept in one range. Meant for lexing large
+/// files in parallel with [Lexer::from_bytes_window].
+///
+/// Since a token never spans lines, each range can be lexed on its own. However, a lexer for a range starts with the
+/// default state, so the ranges only lex like the whole input if nothing carries over from one line to the next: a
+/// category code change (e.g., with [Lexer::set_catcode_schedule]) or a line break read as a letter (with a letter as
+/// the end-of-line character, which continues a control word onto the next line) makes the result differ, as does a
+/// run of blank lines split between ranges when [Lexer::set_par_blank_line_threshold] is above 1.
+pub fn split_into_chunks(input: &[u8], approx_chunk_bytes: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < input.len() {
+        // Find the first line terminator ending at or after the target size
+        let target = start + approx_chunk_bytes.max(1) - 1;
+        let end = match input.get(target..).and_then(|rest| rest.iter().position(|&b| b == b'\r' || b == b'\n')) {
+            Some(index) => {
+                let terminator = target + index;
+                if input[terminator] == b'\r' && input.get(terminator + 1) == Some(&b'\n') {
+                    terminator + 2
+                } else {
+                    terminator + 1
+                }
+            },
+            None => input.len(),
+        };
+        chunks.push((start, end));
+        start = end;
+    }
+    chunks
+}
+
+/// Lexes `input` with the default configuration and a fresh command identifier table and returns the kind of each
+/// token, excluding the final [TokenKind::Eof], with a readable spelling of it: the character of a character token, the
+/// name of a control word (without the escape character), `"{"` for a [TokenKind::BeginGroup], `"#1"` for a parameter,
+/// and so on. Non-character bytes are spelled in caret notation (e.g., `"^^ff"`). Meant for quick experiments rather
+/// than as input to other passes, since different tokens can have the same spelling.
+pub fn lex_to_strings(input: &str) -> Vec<(TokenKind, String)> {
+    let spell_maybe_char = |ch: MaybeChar| match ch.enum_view() {
+        MaybeCharEnumView::Char(ch) => ch.to_string(),
+        MaybeCharEnumView::NonCharByte(byte) => format!("^^{byte:02x}"),
+    };
+
+    let table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &table);
+    let mut token = Token::default();
+    let mut tokens = Vec::new();
+    loop {
+        lexer.lex(&mut token);
+        let spelling = match token.kind() {
+            TokenKind::Eof => return tokens,
+            TokenKind::Unknown => String::new(),
+            TokenKind::ControlWord | TokenKind::ActiveChar =>
+                String::from_utf8_lossy(token.command_identifier().as_bytes()).into_owned(),
+            TokenKind::ControlSymbol => token.symbol().map(spell_maybe_char).unwrap_or_default(),
+            TokenKind::BeginGroup => "{".to_string(),
+            TokenKind::EndGroup => "}".to_string(),
+            TokenKind::MathShift => "$".to_string(),
+            TokenKind::AlignmentTab => "&".to_string(),
+            TokenKind::Parameter => match token.parameter_index() {
+                Some(index) => format!("#{index}"),
+                None => "#".to_string(),
+            },
+            TokenKind::Superscript => "^".to_string(),
+            TokenKind::Subscript => "_".to_string(),
+            TokenKind::Space => " ".to_string(),
+            TokenKind::Letter | TokenKind::Other => spell_maybe_char(token.maybe_char()),
+            TokenKind::Paragraph => "par".to_string(),
+        };
+        tokens.push((token.kind(), spelling));
+    }
+}
@@ -1,10 +1,48 @@
 pub mod token;
+pub mod token_stream;
 pub mod category_code;
 pub mod lexer;
 pub mod command_identifier;
 pub mod preprocessor;
+pub mod diagnostic;
+pub mod dimension;
+pub mod bench;
 
-pub use token::{Token, TokenKind, TokenFlags};
+pub use token::{Token, TokenKind, TokenFlags, token_at};
+pub use token_stream::TokenStream;
 pub use category_code::CategoryCode;
-pub use lexer::Lexer;
+pub use lexer::{Lexer, LexerConfig, lex_to_strings, split_into_chunks};
 pub use preprocessor::Preprocessor;
+pub use diagnostic::{Diagnostic, DiagnosticKind, DiagnosticLevel};
+pub use dimension::DimensionUnit;
+
+/// Commonly used types for lexing and preprocessing, including the [retex_base::prelude] and [retex_base::MaybeChar]
+/// so lexers and preprocessors can be set up with a single import:
+///
+/// ```
+/// use retex_lex::prelude::*;
+///
+/// let table = CommandIdentifierTable::new();
+/// let mut lexer = Lexer::from_bytes(b"\\make@title", &table);
+/// lexer.set_category_code(MaybeChar::from_char('@'), CategoryCode::Letter);
+/// let mut token = Token::default();
+/// lexer.lex(&mut token);
+/// assert!(token.is(TokenKind::ControlWord));
+/// assert_eq!(token.location(), SourceLocation::new(0));
+///
+/// let mut source_manager = SourceManager::new();
+/// let file_id = source_manager.add_buffer(MemoryBuffer::from_str("x", "x.tex".to_string()), None);
+/// let mut preprocessor = Preprocessor::new(&mut source_manager);
+/// preprocessor.enter_file(file_id);
+/// let mut token = Token::default();
+/// assert!(preprocessor.lex(&mut token) && token.is(TokenKind::Letter));
+/// assert!(preprocessor.diagnostics().is_empty());
+/// ```
+pub mod prelude {
+    pub use retex_base::prelude::*;
+    pub use retex_base::MaybeChar;
+    pub use crate::{Lexer, LexerConfig, Token, TokenKind, TokenFlags, CategoryCode, Preprocessor};
+    pub use crate::{Diagnostic, DiagnosticKind, DiagnosticLevel};
+    pub use crate::category_code::CategoryCodeTable;
+    pub use crate::command_identifier::{CommandIdentifier, CommandIdentifierTable};
+}
@@ -1,8 +1,13 @@
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU8;
+use std::rc::Rc;
 use std::path::PathBuf;
-use retex_base::{SourceManager, FileId, MemoryBuffer};
+use retex_base::{SourceManager, FileId, MemoryBuffer, MaybeChar, MaybeCharEnumView, SourceLocation};
 use crate::lexer::Lexer;
-use crate::token::Token;
-use crate::command_identifier::CommandIdentifierTable;
+use crate::category_code::{CategoryCode, CategoryCodeTable};
+use crate::token::{Token, TokenKind, TokenData, TokenFlags};
+use crate::command_identifier::{CommandIdentifier, CommandIdentifierTable};
+use crate::diagnostic::{Diagnostic, DiagnosticKind};
 
 /// Entry in the include stack representing a lexer for a particular file
 struct IncludeStackEntry<'source, 'idtable> {
@@ -10,6 +15,255 @@ struct IncludeStackEntry<'source, 'idtable> {
     lexer: Lexer<'source, 'idtable>,
     /// The file ID in the source manager
     file_id: FileId,
+    /// Location of the `\input` in the including file that entered this file; invalid for files entered directly
+    include_location: SourceLocation,
+    /// Tokens pushed back when the file above this one was entered (e.g., read ahead while scanning the name after
+    /// `\input`), read again once that file ends
+    pushback: Vec<Token<'idtable>>,
+}
+
+/// A conditional primitive, as reported by [TraceEvent::ConditionalTaken]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConditionalKind {
+    Ifx,
+    IfTrue,
+    IfFalse,
+    IfDefined,
+    IfCsname,
+}
+
+/// A step taken by the [Preprocessor], reported to the hook set with [Preprocessor::set_trace_hook]
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceEvent {
+    /// A macro is expanded. `name` is the name of the command without the escape character (e.g., `foo` for `\foo`)
+    /// and `call_site` is the location of the command.
+    MacroExpanded { name: String, call_site: SourceLocation },
+    /// A conditional is evaluated; `result` tells whether its true branch is read
+    ConditionalTaken { kind: ConditionalKind, result: bool },
+    /// A file is entered, either as the main file or by `\input`
+    FileEntered { file_id: FileId },
+}
+
+/// Receives the steps taken by a [Preprocessor]; see [Preprocessor::set_trace_hook]
+pub type TraceHook<'pp> = Box<dyn FnMut(&TraceEvent) + 'pp>;
+
+/// Internal integer parameters of TeX (e.g., `\escapechar`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IntegerParameter {
+    EscapeChar,
+    EndLineChar,
+}
+
+/// Primitive commands understood by the preprocessor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Primitive {
+    /// `\the`: expands to the value of an internal quantity
+    The,
+    /// `\let`: gives a command the current meaning of a token
+    Let,
+    /// `\futurelet`: gives a command the meaning of the token after the next one, leaving both tokens to be read
+    FutureLet,
+    /// `\global`: makes the following assignment global rather than local to the current group
+    Global,
+    /// `\long`: allows the arguments of the macro defined by the following definition to contain `\par`
+    Long,
+    /// `\def`: defines a macro
+    Def,
+    /// `\edef`: defines a macro whose replacement text is expanded at definition time
+    Edef,
+    /// `\noexpand`: keeps the next token from being expanded
+    NoExpand,
+    /// `\ifx`: compares the meanings of the next two tokens
+    Ifx,
+    /// `\iftrue`: a conditional that is always true
+    IfTrue,
+    /// `\iffalse`: a conditional that is always false
+    IfFalse,
+    /// `\ifdefined`: tests whether the next token has a meaning
+    IfDefined,
+    /// `\ifcsname`: tests whether the command named by the tokens up to `\endcsname` has a meaning, without defining it
+    IfCsname,
+    /// `\csname`: expands to the command named by the tokens up to `\endcsname`, which means `\relax` if undefined
+    Csname,
+    /// `\endcsname`: ends the name read by `\csname` or `\ifcsname`
+    EndCsname,
+    /// `\else`: ends the true branch of a conditional
+    Else,
+    /// `\fi`: ends a conditional
+    Fi,
+    /// `\input`: reads a file
+    Input,
+    /// `\string`: expands to the characters of the next token
+    String,
+    /// `\catcode`: the category code of a character; assigns it when read outside of `\the`
+    Catcode,
+    /// `\toks`: a token list register; assigns it when read outside of `\the`
+    Toks,
+    /// An internal integer parameter; assigns it when read outside of `\the`
+    IntegerParameter(IntegerParameter),
+    /// `\relax`: does nothing, but isn't expandable so it ends a number or stops a search for a keyword
+    Relax,
+    /// `\afterassignment`: saves a token to be read right after the next assignment
+    AfterAssignment,
+    /// `\aftergroup`: saves a token to be read right after the current group ends
+    AfterGroup,
+}
+
+impl Primitive {
+    fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"the" => Some(Primitive::The),
+            b"let" => Some(Primitive::Let),
+            b"futurelet" => Some(Primitive::FutureLet),
+            b"global" => Some(Primitive::Global),
+            b"long" => Some(Primitive::Long),
+            b"def" => Some(Primitive::Def),
+            b"edef" => Some(Primitive::Edef),
+            b"noexpand" => Some(Primitive::NoExpand),
+            b"ifx" => Some(Primitive::Ifx),
+            b"iftrue" => Some(Primitive::IfTrue),
+            b"iffalse" => Some(Primitive::IfFalse),
+            b"ifdefined" => Some(Primitive::IfDefined),
+            b"ifcsname" => Some(Primitive::IfCsname),
+            b"csname" => Some(Primitive::Csname),
+            b"endcsname" => Some(Primitive::EndCsname),
+            b"else" => Some(Primitive::Else),
+            b"fi" => Some(Primitive::Fi),
+            b"input" => Some(Primitive::Input),
+            b"string" => Some(Primitive::String),
+            b"catcode" => Some(Primitive::Catcode),
+            b"toks" => Some(Primitive::Toks),
+            b"escapechar" => Some(Primitive::IntegerParameter(IntegerParameter::EscapeChar)),
+            b"endlinechar" => Some(Primitive::IntegerParameter(IntegerParameter::EndLineChar)),
+            b"relax" => Some(Primitive::Relax),
+            b"afterassignment" => Some(Primitive::AfterAssignment),
+            b"aftergroup" => Some(Primitive::AfterGroup),
+            _ => None,
+        }
+    }
+
+    /// Returns whether the primitive begins a conditional (e.g., `\ifx`)
+    fn is_conditional(self) -> bool {
+        matches!(self,
+            Primitive::Ifx | Primitive::IfTrue | Primitive::IfFalse | Primitive::IfDefined | Primitive::IfCsname)
+    }
+
+    /// Returns whether the primitive begins an assignment, after which the token saved by `\afterassignment` is read
+    fn is_assignment(self) -> bool {
+        matches!(self,
+            Primitive::Let | Primitive::FutureLet | Primitive::Global | Primitive::Long | Primitive::Def
+            | Primitive::Edef | Primitive::Catcode | Primitive::Toks | Primitive::IntegerParameter(_))
+    }
+}
+
+/// Values of internal quantities changed by local assignments in a group, saved to be restored when the group ends.
+/// Each key is saved at most once, with the value it had before the first assignment in the group.
+#[derive(Default)]
+struct SavedValues<'pp> {
+    /// Category codes assigned by `\catcode`
+    category_codes: Vec<(MaybeChar, CategoryCode)>,
+    /// Contents of token list registers assigned by `\toks`
+    toks_registers: Vec<(u16, Vec<Token<'pp>>)>,
+    /// Values of integer parameters (e.g., `\escapechar`)
+    integer_parameters: Vec<(IntegerParameter, i32)>,
+}
+
+/// Identifies a command whose meaning can be changed: a control word, a control symbol or an active character
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum CommandKey<'pp> {
+    ControlWord(&'pp CommandIdentifier<'pp>),
+    ControlSymbol(Option<MaybeChar>),
+    ActiveChar(&'pp CommandIdentifier<'pp>),
+}
+
+impl<'pp> CommandKey<'pp> {
+    fn from_token(token: &Token<'pp>) -> Option<Self> {
+        match token.kind() {
+            TokenKind::ControlWord => Some(CommandKey::ControlWord(token.command_identifier())),
+            TokenKind::ControlSymbol => Some(CommandKey::ControlSymbol(token.symbol())),
+            TokenKind::ActiveChar => Some(CommandKey::ActiveChar(token.command_identifier())),
+            _ => None,
+        }
+    }
+}
+
+/// A macro defined by `\def`
+#[derive(Debug)]
+struct Macro<'pp> {
+    /// Tokens between the command and the replacement text: [TokenKind::Parameter] tokens mark the parameters and
+    /// other tokens delimit them
+    parameter_text: Vec<Token<'pp>>,
+    /// What the macro expands to
+    replacement_text: Vec<ReplacementItem<'pp>>,
+    /// The macro was defined with `\long`, so its arguments can contain `\par`
+    long: bool,
+}
+
+/// Part of the replacement text of a [Macro]
+#[derive(Debug)]
+enum ReplacementItem<'pp> {
+    /// A token output as it is. A parameter character written as `##` in the definition is a single
+    /// [TokenKind::Parameter] token here (e.g., `##1` becomes `#1`), which is output rather than substituted.
+    Token(Token<'pp>),
+    /// `#n`, replaced by the n-th argument
+    Argument(NonZeroU8),
+}
+
+impl ReplacementItem<'_> {
+    fn is_same_as(&self, other: &ReplacementItem<'_>) -> bool {
+        match (self, other) {
+            (ReplacementItem::Token(a), ReplacementItem::Token(b)) => a.is_same_token(b),
+            (ReplacementItem::Argument(a), ReplacementItem::Argument(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Meaning assigned to a command
+#[derive(Debug, Clone)]
+enum Meaning<'pp> {
+    Primitive(Primitive),
+    /// The command is a macro. Definitions are shared since `\let` copies the meaning of a macro.
+    Macro(Rc<Macro<'pp>>),
+    /// The command stands for the token (e.g., after `\let\bgroup={`). Commands are given a copy of a meaning rather
+    /// than a reference to another command, so a later change of the other command doesn't affect it. The token is
+    /// never a command, so meanings never refer to each other and can't form cycles.
+    Token(Token<'pp>),
+    /// The command was `\let` to an undefined command, so it is undefined too (even if it's named like a primitive)
+    Undefined,
+}
+
+impl Meaning<'_> {
+    /// Returns whether a command with this meaning is expanded rather than carried out
+    fn is_expandable(&self) -> bool {
+        matches!(self,
+            Meaning::Macro(_)
+            | Meaning::Primitive(
+                Primitive::The | Primitive::Input | Primitive::String | Primitive::NoExpand | Primitive::Csname
+                | Primitive::Ifx | Primitive::IfTrue | Primitive::IfFalse | Primitive::IfDefined | Primitive::IfCsname
+                | Primitive::Else | Primitive::Fi))
+    }
+
+    /// Returns whether two meanings are the same for `\ifx`: the same primitive, the same character, or macros with
+    /// the same parameter and replacement texts
+    fn is_same_as(&self, other: &Meaning<'_>) -> bool {
+        let same_tokens = |a: &[Token], b: &[Token]| {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.is_same_token(b))
+        };
+        match (self, other) {
+            (Meaning::Primitive(a), Meaning::Primitive(b)) => a == b,
+            (Meaning::Macro(a), Meaning::Macro(b)) => {
+                Rc::ptr_eq(a, b)
+                    || (a.long == b.long
+                        && same_tokens(&a.parameter_text, &b.parameter_text)
+                        && a.replacement_text.len() == b.replacement_text.len()
+                        && a.replacement_text.iter().zip(&b.replacement_text).all(|(a, b)| a.is_same_as(b)))
+            },
+            (Meaning::Token(a), Meaning::Token(b)) => a.is_same_token(b),
+            (Meaning::Undefined, Meaning::Undefined) => true,
+            _ => false,
+        }
+    }
 }
 
 /// Preprocessor handles expansion in TeX. It accepts a stream of tokens from [Lexer] and expands each token in the
@@ -23,6 +277,49 @@ pub struct Preprocessor<'source, 'pp> {
     include_stack: Vec<IncludeStackEntry<'source, 'pp>>,
     /// Command identifier table for managing command names
     command_identifier_table: CommandIdentifierTable<'pp>,
+    /// Tokens pushed back to be read again before lexing more input; the last token is read first
+    pushback: Vec<Token<'pp>>,
+    /// Value of `\escapechar`
+    escape_char: i32,
+    /// Value of `\endlinechar`
+    endline_char: i32,
+    /// Category codes assigned by `\catcode`, which apply to every file read; the lexer of each file reads with a copy
+    category_code_table: CategoryCodeTable,
+    /// Meanings assigned to commands; commands without an entry are primitives looked up by name or mean themselves
+    meanings: HashMap<CommandKey<'pp>, Meaning<'pp>>,
+    /// Meanings assigned locally in the groups being read, innermost last; they take precedence over `meanings`, which
+    /// holds the meanings outside of all groups
+    meaning_scopes: Vec<HashMap<CommandKey<'pp>, Meaning<'pp>>>,
+    /// Token saved by `\afterassignment` to be read after the next assignment
+    after_assignment_token: Option<Token<'pp>>,
+    /// Tokens saved by `\aftergroup` in each group being read, innermost last, to be read in order after the group ends
+    after_group_tokens: Vec<Vec<Token<'pp>>>,
+    /// Values saved by local assignments in each group being read, innermost last
+    saved_values: Vec<SavedValues<'pp>>,
+    /// Resolve `\input` file names relative to the directory of the current file first
+    relative_input_resolution: bool,
+    /// Directories searched for `\input` files, in order
+    search_paths: Vec<PathBuf>,
+    /// Contents of the token list registers (`\toks`); registers without an entry are empty
+    toks_registers: HashMap<u16, Vec<Token<'pp>>>,
+    /// Number of conditionals whose true branch (or `\else` branch) is being read
+    open_conditionals: usize,
+    /// Evaluate conditionals; otherwise they are passed through as ordinary tokens
+    process_conditionals: bool,
+    /// Report control words and active characters without a meaning when they're read
+    report_undefined_commands: bool,
+    /// Maximum size in bytes of a file that can be entered
+    max_file_size: Option<usize>,
+    /// Maximum total size in bytes of the files entered, counting a file each time it's entered
+    source_budget: Option<usize>,
+    /// Total size in bytes of the files entered so far
+    source_bytes_entered: usize,
+    /// Diagnostics reported while preprocessing
+    diagnostics: Vec<Diagnostic>,
+    /// Kinds and locations of the diagnostics reported for undefined commands, so each is reported once
+    reported_undefined_commands: HashSet<(DiagnosticKind, SourceLocation)>,
+    /// Receives the steps taken while preprocessing
+    trace_hook: Option<TraceHook<'pp>>,
 }
 
 impl<'source, 'pp> Preprocessor<'source, 'pp>
@@ -33,9 +330,86 @@ where
             source_manager,
             include_stack: Vec::new(),
             command_identifier_table: CommandIdentifierTable::new(),
+            pushback: Vec::new(),
+            escape_char: '\\' as i32,
+            endline_char: '\r' as i32,
+            category_code_table: CategoryCodeTable::new(),
+            meanings: HashMap::new(),
+            meaning_scopes: Vec::new(),
+            after_assignment_token: None,
+            after_group_tokens: Vec::new(),
+            saved_values: Vec::new(),
+            relative_input_resolution: false,
+            search_paths: Vec::new(),
+            toks_registers: HashMap::new(),
+            open_conditionals: 0,
+            process_conditionals: true,
+            report_undefined_commands: false,
+            max_file_size: None,
+            source_budget: None,
+            source_bytes_entered: 0,
+            diagnostics: Vec::new(),
+            reported_undefined_commands: HashSet::new(),
+            trace_hook: None,
         }
     }
 
+    /// When enabled, `\input` resolves file names relative to the directory of the file being read before falling back
+    /// to the search paths. Disabled by default, in which case file names are resolved like TeX does: relative to the
+    /// working directory, then the search paths.
+    pub fn set_relative_input_resolution(&mut self, enabled: bool) {
+        self.relative_input_resolution = enabled;
+    }
+
+    /// When disabled, conditionals (e.g., `\ifx`, `\else` and `\fi`) are output as ordinary tokens and both branches
+    /// are read, for passes over the source that don't evaluate it (e.g., syntax highlighting). Enabled by default.
+    pub fn set_process_conditionals(&mut self, enabled: bool) {
+        self.process_conditionals = enabled;
+    }
+
+    /// When enabled, a control word or active character without a meaning is reported when it's read, like TeX's
+    /// "Undefined control sequence" error, and is then output as it is, as if it were `\relax`. Disabled by default,
+    /// since the preprocessor only knows a few primitives and passes the others (and commands defined by formats it
+    /// hasn't read) through.
+    pub fn set_report_undefined_commands(&mut self, enabled: bool) {
+        self.report_undefined_commands = enabled;
+    }
+
+    /// Limits the size of each file entered (e.g., by `\input`) to `max_bytes`. Larger files are reported and not
+    /// entered. Unlimited by default.
+    pub fn set_max_file_size(&mut self, max_bytes: Option<usize>) {
+        self.max_file_size = max_bytes;
+    }
+
+    /// Limits the total size of the files entered to `max_bytes`, counting a file each time it's entered. A file that
+    /// would exceed the budget is reported and not entered. Unlimited by default.
+    pub fn set_source_budget(&mut self, max_bytes: Option<usize>) {
+        self.source_budget = max_bytes;
+    }
+
+    /// Calls `hook` with each step taken while preprocessing (e.g., each macro expanded), for tools that trace
+    /// expansion. Events are only built while a hook is set.
+    pub fn set_trace_hook(&mut self, hook: impl FnMut(&TraceEvent) + 'pp) {
+        self.trace_hook = Some(Box::new(hook));
+    }
+
+    /// Reports an event to the trace hook, if one is set. The event is built only when it's reported.
+    fn trace(&mut self, event: impl FnOnce() -> TraceEvent) {
+        if let Some(hook) = self.trace_hook.as_mut() {
+            hook(&event());
+        }
+    }
+
+    /// Adds a directory to search for files read by `\input`
+    pub fn add_search_path(&mut self, path: PathBuf) {
+        self.search_paths.push(path);
+    }
+
+    /// Get the diagnostics reported so far
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
     /// Enter the main input file. This is the entry point for starting lexing.
     /// Following Clang's Preprocessor::EnterMainSourceFile pattern.
     pub fn enter_main_file(&mut self, path: PathBuf) -> Result<(), std::io::Error> {
@@ -45,15 +419,26 @@ where
     }
 
     /// Enter a file by creating a new lexer and switching to it.
-    /// If there's a current lexer, it gets pushed onto the include stack.
+    /// If there's a current lexer, it gets pushed onto the include stack. Like TeX's `\input`, the new file starts in the
+    /// start-of-line state, so its leading spaces are skipped. Tokens pushed back before are read after the file ends.
     pub fn enter_file(&mut self, file_id: FileId) {
+        self.enter_file_at(file_id, SourceLocation::invalid());
+    }
+
+    /// Enter a file like [Preprocessor::enter_file], recording `include_location` as the location in the current file
+    /// where it was included (e.g., of `\input`), as reported by [Preprocessor::include_chain]
+    pub fn enter_file_at(&mut self, file_id: FileId, include_location: SourceLocation) {
         // First check if file exists
         if !self.source_manager.is_file_loaded(file_id) {
             return;
         }
+        if !self.check_file_size(file_id, include_location) {
+            return;
+        }
 
         // Get buffer reference through raw pointer
-        if let Some(buffer) = self.source_manager.get_buffer_data(file_id) {
+        if let Some(buffer) = self.source_manager.get_buffer_data(file_id)
+            && let Some(start_offset) = self.source_manager.get_file(file_id).map(|entry| entry.start_offset) {
             // SAFETY: Rust can’t allow a struct to contain a field that borrows another field of the same struct.
             // Passing buffer from SourceManager to Lexer creates a self-referential relationship between Preprocessor’s
             // fields (between self.source_manager and self.include_stack that holds Lexer.) The borrow checker
@@ -61,18 +446,81 @@ where
             // We bypass that by using raw pointers. This is sound only if:
             // 1. `self.source_manager` outlives all Lexers in `self.include_stack`
             // 2. `Preprocessor` is never moved after a Lexer is created (or else the references would dangle).
-            let lexer = unsafe {
+            // Loading more files into the source manager doesn't invalidate the buffer since its data lives on the heap
+            // behind an `Arc`.
+            let mut lexer = unsafe {
                 // Get raw pointers to avoid borrow checker issues
                 let command_table_ptr = &self.command_identifier_table as *const CommandIdentifierTable<'pp>;
 
-                Lexer::from_memory_buffer(
-                    &*(buffer as *const MemoryBuffer),
-                    &*command_table_ptr
-                )
+                // Tokens are located in the global source location space, at the file's offsets in the source manager
+                let data = (*(buffer as *const MemoryBuffer)).data();
+                Lexer::from_bytes_window(data, 0, data.len(), start_offset, &*command_table_ptr)
             };
 
-            self.include_stack.push(IncludeStackEntry { lexer, file_id });
+            lexer.set_file_id(Some(file_id));
+            // Tokens read ahead in the including file come after the entered file, as in TeX
+            if let Some(including) = self.include_stack.last_mut() {
+                including.pushback = std::mem::take(&mut self.pushback);
+            }
+            self.include_stack.push(IncludeStackEntry { lexer, file_id, include_location, pushback: Vec::new() });
+            self.sync_current_lexer();
+            self.trace(|| TraceEvent::FileEntered { file_id });
+        }
+    }
+
+    /// Checks the size of a file about to be entered against the limits and counts it towards the source budget.
+    /// Returns `false` after reporting a diagnostic at `include_location` if the file can't be entered.
+    fn check_file_size(&mut self, file_id: FileId, include_location: SourceLocation) -> bool {
+        let Some(buffer) = self.source_manager.get_buffer_data(file_id) else {
+            return false;
+        };
+        let size = buffer.size();
+        let name = || buffer.buffer_name().to_string();
+        if self.max_file_size.is_some_and(|max_bytes| size > max_bytes) {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::FileTooLarge(name()), include_location));
+            return false;
+        }
+        let source_bytes_entered = self.source_bytes_entered + size;
+        if self.source_budget.is_some_and(|max_bytes| source_bytes_entered > max_bytes) {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::SourceBudgetExceeded(name()), include_location));
+            return false;
+        }
+        self.source_bytes_entered = source_bytes_entered;
+        true
+    }
+
+    /// Resolves a file name given to `\input` into a file, loading it if needed. The directories are tried in order:
+    /// the directory of the current file (with relative resolution), the working directory, then the search paths. In
+    /// each directory, the name is tried as it is, then with `.tex` appended (e.g., `foo` then `foo.tex`, or `a.b`
+    /// then `a.b.tex`). Each candidate path is looked up in the source manager first, so in-memory buffers can be read
+    /// by name without file system access.
+    fn resolve_input(&mut self, name: &str) -> Option<FileId> {
+        let mut directories = Vec::new();
+        if self.relative_input_resolution
+            && let Some(file_id) = self.current_file_id()
+            && let Some(directory) = self.source_manager.get_file_path(file_id).and_then(|path| path.parent()) {
+            directories.push(directory.to_path_buf());
+        }
+        directories.push(PathBuf::new());
+        directories.extend(self.search_paths.iter().cloned());
+
+        let mut candidates = vec![PathBuf::from(name)];
+        if !name.ends_with(".tex") {
+            candidates.push(PathBuf::from(format!("{name}.tex")));
+        }
+
+        let paths: Vec<_> = directories.iter()
+            .flat_map(|directory| candidates.iter().map(move |candidate| directory.join(candidate)))
+            .collect();
+        for path in paths {
+            if let Some(file_id) = self.source_manager.get_file_id_by_path(&path) {
+                return Some(file_id);
+            }
+            if path.is_file() {
+                return self.source_manager.load_file(path).ok();
+            }
         }
+        None
     }
 
     /// Get the current active lexer (top of include stack)
@@ -80,23 +528,1218 @@ where
         self.include_stack.last_mut().map(|entry| &mut entry.lexer)
     }
 
+    /// Returns the category code of a character, reflecting `\catcode` assignments in any file read so far
+    pub fn catcode_of(&self, ch: MaybeChar) -> CategoryCode {
+        self.category_code_table.get(ch)
+    }
+
+    /// Returns the source manager the preprocessor reads files from, e.g., to look up the source of tokens with
+    /// [Token::raw_bytes]
+    pub fn source_manager(&self) -> &SourceManager {
+        self.source_manager
+    }
+
+    /// Get the [FileId] of the file being lexed (top of include stack)
+    pub fn current_file_id(&self) -> Option<FileId> {
+        self.include_stack.last().map(|entry| entry.file_id)
+    }
+
+    /// Returns the files on the include stack, from the main file to the current file, each with the location in the
+    /// including file where it was included ([SourceLocation::invalid] for files not included by another file)
+    pub fn include_chain(&self) -> Vec<(FileId, SourceLocation)> {
+        self.include_stack.iter().map(|entry| (entry.file_id, entry.include_location)).collect()
+    }
+
+    /// Formats the include stack for messages, Clang-style, starting from the file that included the current file (e.g.,
+    /// `"In file included from b.tex:5:\n                 from a.tex:3:\n"`). Returns an empty string if the current
+    /// file wasn't included by another file.
+    pub fn format_include_stack(&self) -> String {
+        let mut text = String::new();
+        for (parent, entry) in self.include_stack.iter().zip(self.include_stack.iter().skip(1)).rev() {
+            if !entry.include_location.is_valid() {
+                continue;
+            }
+            let path = self.source_manager.get_file_path(parent.file_id)
+                .map_or_else(|| "<unknown>".to_string(), |path| path.display().to_string());
+            let line = self.source_manager.get_file(parent.file_id)
+                .and_then(|file| file.line_and_column(file.location_to_offset(entry.include_location)?))
+                .map_or(0, |(line, _)| line);
+            let prefix = if text.is_empty() { "In file included from" } else { "                 from" };
+            text.push_str(&format!("{prefix} {path}:{line}:\n"));
+        }
+        text
+    }
+
+    /// Push a token back to the input stream so it is the next token returned by [Preprocessor::lex]. Tokens pushed
+    /// back are read in last-in, first-out order.
+    pub fn push_back(&mut self, token: Token<'pp>) {
+        self.pushback.push(token);
+    }
+
     /// Main interface that shares the same prototype as Lexer's lex method.
-    /// Calls into Lexer to get stream of tokens and produces tokens that cannot be expanded further.
-    pub fn lex<'token>(&mut self, token: &'token mut Token<'token>) -> bool
-    where
-        'pp: 'token {
+    /// Calls into Lexer to get stream of tokens and produces tokens that cannot be expanded further. Assignments to
+    /// internal quantities (e.g., `\escapechar=-1`) are carried out and don't appear in the output.
+    ///
+    /// A [TokenKind::BeginGroup] token returned here begins a group and the matching [TokenKind::EndGroup] token ends
+    /// it: assignments in the group (e.g., `\def`, `\let` and `\catcode`) are undone at its end unless prefixed with
+    /// `\global`. A group token that is pushed back and returned again doesn't begin or end another group.
+    pub fn lex(&mut self, token: &mut Token<'pp>) -> bool {
+        if !self.lex_expanded(token, true) {
+            return false;
+        }
+        if token.is_one_of(&[TokenKind::BeginGroup, TokenKind::EndGroup]) && !token.is_group_counted() {
+            token.set_group_counted();
+            if token.is(TokenKind::BeginGroup) {
+                self.begin_group();
+            } else {
+                self.end_group();
+            }
+        }
+        true
+    }
+
+    fn begin_group(&mut self) {
+        self.meaning_scopes.push(HashMap::new());
+        self.after_group_tokens.push(Vec::new());
+        self.saved_values.push(SavedValues::default());
+    }
+
+    /// Ends the innermost group: restores the values assigned locally in it and reads the tokens saved by
+    /// `\aftergroup`. Does nothing outside of groups.
+    fn end_group(&mut self) {
+        self.meaning_scopes.pop();
+        if let Some(saved) = self.saved_values.pop() {
+            for (maybe_char, category_code) in saved.category_codes {
+                self.store_category_code(maybe_char, category_code);
+            }
+            for (register, tokens) in saved.toks_registers {
+                self.store_toks_register(register, tokens);
+            }
+            for (parameter, value) in saved.integer_parameters {
+                self.store_integer_parameter(parameter, value);
+            }
+        }
+        if let Some(tokens) = self.after_group_tokens.pop() {
+            self.push_back_tokens(tokens);
+        }
+    }
+
+    /// Reads the next token, expanding expandable tokens. Other commands are carried out if `carry_out_assignments`;
+    /// otherwise they are returned as they are, as in the replacement text of `\edef`, where the output of `\the` is
+    /// also returned as it is rather than expanded again.
+    fn lex_expanded(&mut self, token: &mut Token<'pp>, carry_out_assignments: bool) -> bool {
+        loop {
+            if !self.lex_unexpanded(token) {
+                return false;
+            }
+            if token.take_expansion_suppressed() && !carry_out_assignments {
+                return true;
+            }
+
+            let meaning = self.meaning(token);
+            if matches!(meaning, None | Some(Meaning::Undefined)) {
+                self.report_undefined_command(token);
+            }
+            if !carry_out_assignments && !meaning.as_ref().is_some_and(Meaning::is_expandable) {
+                return true;
+            }
+            if !self.process_conditionals
+                && let Some(Meaning::Primitive(primitive)) = &meaning
+                && (primitive.is_conditional() || matches!(primitive, Primitive::Else | Primitive::Fi)) {
+                return true;
+            }
+            let is_assignment = matches!(meaning, Some(Meaning::Primitive(primitive)) if primitive.is_assignment());
+            match meaning {
+                Some(Meaning::Primitive(Primitive::The)) => {
+                    let mut expansion = self.expand_the(token);
+                    if !carry_out_assignments {
+                        expansion.iter_mut().for_each(Token::set_expansion_suppressed);
+                    }
+                    self.push_back_tokens(expansion);
+                },
+                Some(Meaning::Primitive(Primitive::Let)) => self.assign_let(false),
+                Some(Meaning::Primitive(Primitive::FutureLet)) => self.assign_futurelet(false),
+                Some(Meaning::Primitive(Primitive::Global)) => self.assign_prefixed(true, false),
+                Some(Meaning::Primitive(Primitive::Long)) => self.assign_prefixed(false, true),
+                Some(Meaning::Primitive(Primitive::Def)) => self.assign_def(token.location(), false, false, false),
+                Some(Meaning::Primitive(Primitive::Edef)) => self.assign_def(token.location(), true, false, false),
+                Some(Meaning::Primitive(Primitive::NoExpand)) => {
+                    // The next token is returned without expansion; for a token that isn't expandable, `\noexpand`
+                    // does nothing
+                    if !self.lex_unexpanded(token) {
+                        return false;
+                    }
+                    if self.meaning(token).is_some_and(|meaning| meaning.is_expandable()) {
+                        return true;
+                    }
+                    self.push_back(token.clone());
+                },
+                Some(Meaning::Primitive(Primitive::Input)) => self.expand_input(token.location()),
+                Some(Meaning::Primitive(Primitive::String)) => {
+                    let expansion = self.expand_string(token);
+                    self.push_back_tokens(expansion);
+                },
+                Some(Meaning::Primitive(Primitive::Catcode)) => self.assign_catcode(false),
+                Some(Meaning::Primitive(Primitive::Toks)) => self.assign_toks(false),
+                Some(Meaning::Primitive(Primitive::IntegerParameter(parameter))) => {
+                    self.assign_integer_parameter(parameter, false)
+                },
+                Some(Meaning::Primitive(Primitive::Ifx)) => self.expand_ifx(),
+                Some(Meaning::Primitive(Primitive::IfTrue)) => self.begin_conditional(ConditionalKind::IfTrue, true),
+                Some(Meaning::Primitive(Primitive::IfFalse)) => {
+                    self.begin_conditional(ConditionalKind::IfFalse, false)
+                },
+                Some(Meaning::Primitive(Primitive::IfDefined)) => self.expand_ifdefined(),
+                Some(Meaning::Primitive(Primitive::IfCsname)) => {
+                    let command = self.read_csname(token.location());
+                    let is_defined = self.is_defined(&command);
+                    self.begin_conditional(ConditionalKind::IfCsname, is_defined);
+                },
+                Some(Meaning::Primitive(Primitive::Csname)) => self.expand_csname(token.location()),
+                Some(Meaning::Primitive(Primitive::Else)) => {
+                    // The true branch ends here; skip the rest of the conditional
+                    if self.open_conditionals > 0 {
+                        self.skip_conditional_branch(false);
+                        self.open_conditionals -= 1;
+                    }
+                },
+                Some(Meaning::Primitive(Primitive::Fi)) => {
+                    self.open_conditionals = self.open_conditionals.saturating_sub(1);
+                },
+                Some(Meaning::Macro(definition)) => {
+                    self.trace(|| TraceEvent::MacroExpanded {
+                        name: Self::command_name(token),
+                        call_site: token.location(),
+                    });
+                    let expansion = self.expand_macro(token.location(), &definition);
+                    self.push_back_tokens(expansion);
+                },
+                Some(Meaning::Primitive(Primitive::AfterAssignment)) => {
+                    let mut saved = Token::default();
+                    if self.lex_unexpanded(&mut saved) && saved.is_not(TokenKind::Eof) {
+                        self.after_assignment_token = Some(saved);
+                    }
+                },
+                Some(Meaning::Primitive(Primitive::AfterGroup)) => {
+                    let mut saved = Token::default();
+                    // Outside of groups the token is dropped, as there is no group end to read it after
+                    if self.lex_unexpanded(&mut saved) && saved.is_not(TokenKind::Eof)
+                        && let Some(tokens) = self.after_group_tokens.last_mut() {
+                        tokens.push(saved);
+                    }
+                },
+                Some(Meaning::Primitive(Primitive::Relax | Primitive::EndCsname)) | Some(Meaning::Undefined) => {
+                    return true
+                },
+                Some(Meaning::Token(meaning)) => {
+                    Self::replace_with_meaning(token, meaning);
+                    return true;
+                },
+                None => return true,
+            }
+            if is_assignment && let Some(saved) = self.after_assignment_token.take() {
+                self.push_back(saved);
+            }
+        }
+    }
+
+    /// Reports `token` as undefined if it's a control word or an active character and undefined commands are reported.
+    /// A token read again after being pushed back (e.g., by [Preprocessor::consume_if]) is only reported once, even if
+    /// other tokens are read in between.
+    fn report_undefined_command(&mut self, token: &Token<'pp>) {
+        if !self.report_undefined_commands || !matches!(token.kind(), TokenKind::ControlWord | TokenKind::ActiveChar) {
+            return;
+        }
+        if self.reported_undefined_commands.insert((DiagnosticKind::UndefinedCommand, token.location())) {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::UndefinedCommand, token.location()));
+        }
+    }
+
+    /// Returns the tokens `token` expands to in one step, for inspecting expansion (e.g., when debugging macros): the
+    /// replacement text of a macro with its arguments substituted, or the result of `\the` or `\string`. Arguments
+    /// are read from the input, but the expansion is returned rather than read again, so nested expandable tokens are
+    /// left as they are. A token that isn't expandable is returned by itself.
+    pub fn expand_once_trace(&mut self, token: &Token<'pp>) -> Vec<Token<'pp>> {
+        match self.meaning(token) {
+            Some(Meaning::Macro(definition)) => self.expand_macro(token.location(), &definition),
+            Some(Meaning::Primitive(Primitive::The)) => self.expand_the(token),
+            Some(Meaning::Primitive(Primitive::String)) => self.expand_string(token),
+            _ => vec![token.clone()],
+        }
+    }
+
+    /// Reads the next token from the pushed back tokens or the current lexer without expanding it or carrying out
+    /// assignments
+    fn lex_unexpanded(&mut self, token: &mut Token<'pp>) -> bool {
+        if let Some(pushed_back) = self.pushback.pop() {
+            *token = pushed_back;
+            return true;
+        }
 
         // Get the current lexer from the include stack
-        if let Some(lexer) = self.current_lexer() {
+        while let Some(lexer) = self.current_lexer() {
             lexer.lex(token);
 
-            // TODO: Check if the token is a command that needs expansion
-            // TODO: If expandable, perform expansion and return expanded tokens
-            // TODO: If not expandable, return the token as-is
+            // Continue with the including file at the end of an included file, which may have changed the category
+            // codes and the end-of-line character
+            if token.is(TokenKind::Eof) && self.include_stack.len() > 1 {
+                self.include_stack.pop();
+                self.sync_current_lexer();
+                if let Some(including) = self.include_stack.last_mut() {
+                    self.pushback = std::mem::take(&mut including.pushback);
+                }
+                if let Some(pushed_back) = self.pushback.pop() {
+                    *token = pushed_back;
+                    return true;
+                }
+                continue;
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Returns the meaning of `token`, or `None` if the token means itself (e.g., a character or an unknown command)
+    fn meaning(&self, token: &Token<'pp>) -> Option<Meaning<'pp>> {
+        let key = CommandKey::from_token(token)?;
+        let meaning = self.meaning_scopes.iter().rev()
+            .find_map(|scope| scope.get(&key))
+            .or_else(|| self.meanings.get(&key));
+        if let Some(meaning) = meaning {
+            return Some(meaning.clone());
+        }
+
+        match key {
+            CommandKey::ControlWord(id) => Primitive::from_name(id.as_bytes()).map(Meaning::Primitive),
+            _ => None,
+        }
+    }
 
-            true
+    /// Assigns a meaning to a command, locally to the current group or, if `global`, outside of all groups so it stays
+    /// after the groups end
+    fn set_meaning(&mut self, key: CommandKey<'pp>, meaning: Meaning<'pp>, global: bool) {
+        if global {
+            for scope in &mut self.meaning_scopes {
+                scope.remove(&key);
+            }
+            self.meanings.insert(key, meaning);
+        } else if let Some(scope) = self.meaning_scopes.last_mut() {
+            scope.insert(key, meaning);
         } else {
-            false
+            self.meanings.insert(key, meaning);
+        }
+    }
+
+    /// Reads the assignment following the prefixes `\global` and `\long` and carries it out globally if `global`, and
+    /// defines a `\long` macro if `long`. Expandable tokens after the prefix are expanded. The prefixes are ignored
+    /// before anything other than an assignment, which is read again as it is. `\long` is ignored before assignments
+    /// other than definitions.
+    fn assign_prefixed(&mut self, global: bool, long: bool) {
+        let mut token = Token::default();
+        if !self.lex_expanded(&mut token, false) {
+            return;
         }
+        match self.meaning(&token) {
+            Some(Meaning::Primitive(Primitive::Let)) => self.assign_let(global),
+            Some(Meaning::Primitive(Primitive::FutureLet)) => self.assign_futurelet(global),
+            Some(Meaning::Primitive(Primitive::Def)) => self.assign_def(token.location(), false, global, long),
+            Some(Meaning::Primitive(Primitive::Edef)) => self.assign_def(token.location(), true, global, long),
+            Some(Meaning::Primitive(Primitive::Catcode)) => self.assign_catcode(global),
+            Some(Meaning::Primitive(Primitive::Toks)) => self.assign_toks(global),
+            Some(Meaning::Primitive(Primitive::IntegerParameter(parameter))) => {
+                self.assign_integer_parameter(parameter, global)
+            },
+            Some(Meaning::Primitive(Primitive::Global)) => self.assign_prefixed(true, long),
+            Some(Meaning::Primitive(Primitive::Long)) => self.assign_prefixed(global, true),
+            _ => self.push_back(token),
+        }
+    }
+
+    /// Replaces a command token with the token it was `\let` to, keeping the command's file and location. Characters
+    /// become implicit characters.
+    fn replace_with_meaning(token: &mut Token<'pp>, mut meaning: Token<'pp>) {
+        meaning.set_location(token.location());
+        meaning.set_file_id(token.file_id());
+        meaning.set_length(token.length());
+        meaning.clear_flag(TokenFlags::START_OF_LINE);
+        if token.at_start_of_line() {
+            meaning.set_flag(TokenFlags::START_OF_LINE);
+        }
+        if CommandKey::from_token(&meaning).is_none() {
+            meaning.set_flag(TokenFlags::IMPLICIT);
+        }
+        *token = meaning;
+    }
+
+    /// Reads `⟨command⟩⟨equals⟩⟨one optional space⟩⟨token⟩` after `\let` and gives the command the current meaning of
+    /// the token. Nothing is assigned if no command follows `\let`.
+    fn assign_let(&mut self, global: bool) {
+        let mut command = Token::default();
+        if !self.lex_unexpanded(&mut command) {
+            return;
+        }
+        let Some(key) = CommandKey::from_token(&command) else {
+            self.push_back(command);
+            return;
+        };
+
+        while self.consume_unexpanded_if(|token| token.is(TokenKind::Space)).is_some() {}
+        self.consume_unexpanded_if(|token| token.is(TokenKind::Other) && token.char() == '=');
+        self.consume_unexpanded_if(|token| token.is(TokenKind::Space));
+
+        let mut target = Token::default();
+        if !self.lex_unexpanded(&mut target) || target.is(TokenKind::Eof) {
+            return;
+        }
+
+        let meaning = self.canonical_meaning(&target);
+        self.set_meaning(key, meaning, global);
+    }
+
+    /// Reads `⟨command⟩⟨token⟩⟨token⟩` after `\futurelet` and gives the command the current meaning of the second
+    /// token. Both tokens are pushed back, so they are read next in their original order.
+    fn assign_futurelet(&mut self, global: bool) {
+        let mut command = Token::default();
+        if !self.lex_unexpanded(&mut command) {
+            return;
+        }
+        let Some(key) = CommandKey::from_token(&command) else {
+            self.push_back(command);
+            return;
+        };
+
+        let mut next = Token::default();
+        if !self.lex_unexpanded(&mut next) || next.is(TokenKind::Eof) {
+            return;
+        }
+        let mut target = Token::default();
+        if !self.lex_unexpanded(&mut target) || target.is(TokenKind::Eof) {
+            self.push_back(next);
+            return;
+        }
+
+        let meaning = self.canonical_meaning(&target);
+        self.set_meaning(key, meaning, global);
+        self.push_back(target);
+        self.push_back(next);
+    }
+
+    /// Returns the meaning of a token as `\let` assigns it and `\ifx` compares it: characters mean themselves and
+    /// commands without a meaning are undefined
+    fn canonical_meaning(&self, token: &Token<'pp>) -> Meaning<'pp> {
+        match self.meaning(token) {
+            Some(meaning) => meaning,
+            None if CommandKey::from_token(token).is_some() => Meaning::Undefined,
+            None => Meaning::Token(token.clone()),
+        }
+    }
+
+    /// Expands `\ifx` by comparing the meanings of the next two tokens, read without expansion. If they differ, the
+    /// true branch is skipped.
+    fn expand_ifx(&mut self) {
+        let mut first = Token::default();
+        let mut second = Token::default();
+        if !self.lex_unexpanded(&mut first) || !self.lex_unexpanded(&mut second) {
+            return;
+        }
+
+        let is_same = self.canonical_meaning(&first).is_same_as(&self.canonical_meaning(&second));
+        self.begin_conditional(ConditionalKind::Ifx, is_same);
+    }
+
+    /// Expands `\ifdefined` by testing whether the next token, read without expansion, has a meaning
+    fn expand_ifdefined(&mut self) {
+        let mut token = Token::default();
+        if !self.lex_unexpanded(&mut token) {
+            return;
+        }
+        let is_defined = self.is_defined(&token);
+        self.begin_conditional(ConditionalKind::IfDefined, is_defined);
+    }
+
+    /// Returns whether a token has a meaning, as tested by `\ifdefined`: characters other than active characters always
+    /// do
+    fn is_defined(&self, token: &Token<'pp>) -> bool {
+        !matches!(self.canonical_meaning(token), Meaning::Undefined)
+    }
+
+    /// Reads the tokens up to `\endcsname`, expanding them, and returns the command they name, located at `location`.
+    /// Like TeX, a name of a single non-letter forms a control symbol (e.g., `\csname\string&\endcsname` is `\&`). A
+    /// token other than a character ends the name and is read again after reporting the missing `\endcsname`.
+    fn read_csname(&mut self, location: SourceLocation) -> Token<'pp> {
+        let mut name = Vec::new();
+        let mut token = Token::default();
+        while self.lex_expanded(&mut token, false) && token.is_not(TokenKind::Eof) {
+            match token.kind() {
+                TokenKind::Letter | TokenKind::Other => {
+                    let mut buffer = [0u8; 4];
+                    name.extend_from_slice(token.maybe_char().encode_utf8(&mut buffer));
+                },
+                TokenKind::Space => name.push(b' '),
+                _ if matches!(self.meaning(&token), Some(Meaning::Primitive(Primitive::EndCsname))) => break,
+                _ => {
+                    self.diagnostics.push(Diagnostic::new(DiagnosticKind::MissingEndCsname, token.location()));
+                    self.push_back(token.clone());
+                    break;
+                },
+            }
+        }
+
+        let mut command = Token::default();
+        command.set_location(location);
+        let single_char = MaybeChar::decode_utf8(&name).filter(|(_, length)| *length == name.len());
+        match single_char {
+            Some((ch, _)) if self.catcode_of(ch) != CategoryCode::Letter => {
+                command.set_kind(TokenKind::ControlSymbol);
+                command.set_token_data(TokenData::Symbol(Some(ch)));
+            },
+            _ => {
+                command.set_kind(TokenKind::ControlWord);
+                command.set_token_data(TokenData::CommandIdentifier(self.intern(&name)));
+            },
+        }
+        command
+    }
+
+    /// Expands `\csname` to the command named by the tokens up to `\endcsname`. As a side effect, an undefined command
+    /// is defined as `\relax` locally to the current group, unlike with `\ifcsname`.
+    fn expand_csname(&mut self, location: SourceLocation) {
+        let command = self.read_csname(location);
+        if !self.is_defined(&command)
+            && let Some(key) = CommandKey::from_token(&command) {
+            self.set_meaning(key, Meaning::Primitive(Primitive::Relax), false);
+        }
+        self.push_back(command);
+    }
+
+    /// Returns the [CommandIdentifier] for a command name, e.g., one built by `\csname`
+    fn intern(&self, name: &[u8]) -> &'pp CommandIdentifier<'pp> {
+        // SAFETY: `get_or_insert` needs the table borrowed for `'pp`, which outlives `&self`. The identifiers are
+        // allocated in the chunks of the table's bumpalo arena, which live on the heap, so moving the Preprocessor
+        // doesn't move them. This is sound only if the table outlives every `Token<'pp>` holding an identifier it
+        // returned, i.e., the Preprocessor must not be dropped while such a token is in use.
+        //
+        // Known issue: `'pp` isn't tied to a borrow of the Preprocessor, so the compiler doesn't enforce this and safe
+        // code can keep a token after dropping the Preprocessor, reading freed memory through its identifier.
+        let table = unsafe { &*(&self.command_identifier_table as *const CommandIdentifierTable<'pp>) };
+        table.get_or_insert(name)
+    }
+
+    /// Enters a conditional whose condition is `condition`, skipping the true branch if it's false
+    fn begin_conditional(&mut self, kind: ConditionalKind, condition: bool) {
+        self.trace(|| TraceEvent::ConditionalTaken { kind, result: condition });
+        // The conditional stays open while its true branch, or its `\else` branch, is read
+        if condition || self.skip_conditional_branch(true) {
+            self.open_conditionals += 1;
+        }
+    }
+
+    /// Skips tokens without expanding them until the `\fi` that ends the current conditional, or until its `\else` if
+    /// `stop_at_else` is set. Nested conditionals are skipped as a whole. Returns `true` if it stopped at `\else`.
+    fn skip_conditional_branch(&mut self, stop_at_else: bool) -> bool {
+        let mut depth = 0usize;
+        let mut token = Token::default();
+        loop {
+            if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+                return false;
+            }
+            match self.meaning(&token) {
+                Some(Meaning::Primitive(primitive)) if primitive.is_conditional() => depth += 1,
+                Some(Meaning::Primitive(Primitive::Fi)) if depth == 0 => return false,
+                Some(Meaning::Primitive(Primitive::Fi)) => depth -= 1,
+                Some(Meaning::Primitive(Primitive::Else)) if depth == 0 && stop_at_else => return true,
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads `⟨command⟩⟨parameter text⟩{⟨replacement text⟩}` after `\def` and defines the command as a macro. If
+    /// `expand` is set, as for `\edef`, the replacement text is expanded first. Nothing is defined if no command
+    /// follows `\def` or the input ends first; the latter is reported at the `\def` at `location`.
+    fn assign_def(&mut self, location: SourceLocation, expand: bool, global: bool, long: bool) {
+        let mut command = Token::default();
+        if !self.lex_unexpanded(&mut command) {
+            return;
+        }
+        let Some(key) = CommandKey::from_token(&command) else {
+            self.push_back(command);
+            return;
+        };
+
+        let mut parameter_text = Vec::new();
+        let mut token = Token::default();
+        loop {
+            if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+                self.diagnostics.push(Diagnostic::new(DiagnosticKind::RunawayDefinition, location));
+                return;
+            }
+            if token.is(TokenKind::BeginGroup) {
+                self.push_back(token);
+                break;
+            }
+            if token.is(TokenKind::Parameter) && !self.check_parameter_number(&token, &parameter_text) {
+                continue;
+            }
+            parameter_text.push(token.clone());
+        }
+        let replacement_text = if expand { self.read_expanded_group() } else { self.read_group() };
+        let Some(replacement_text) = replacement_text else {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::RunawayDefinition, location));
+            return;
+        };
+        let parameter_count = parameter_text.iter().filter(|token| token.is(TokenKind::Parameter)).count();
+        let replacement_text = self.parse_replacement_text(replacement_text, parameter_count);
+
+        self.set_meaning(key, Meaning::Macro(Rc::new(Macro { parameter_text, replacement_text, long })), global);
+    }
+
+    /// Checks that a parameter in the parameter text of a definition is numbered after the parameters before it (e.g.,
+    /// `#2` after `#1`) and reports it otherwise. Like TeX, a misnumbered parameter is kept as if numbered correctly,
+    /// but one after the ninth parameter is dropped. Returns whether the parameter is kept.
+    fn check_parameter_number(&mut self, token: &Token<'pp>, parameter_text: &[Token<'pp>]) -> bool {
+        let expected = parameter_text.iter().filter(|token| token.is(TokenKind::Parameter)).count() + 1;
+        if expected > 9 {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::TooManyParameters, token.location()));
+            return false;
+        }
+        if token.parameter_index().is_none_or(|index| index.get() as usize != expected) {
+            self.diagnostics.push(Diagnostic::new(DiagnosticKind::ParametersNotConsecutive, token.location()));
+        }
+        true
+    }
+
+    /// Interprets the parameter characters in the replacement text of a definition with `parameter_count` parameters:
+    /// `##` stands for a single parameter character and `#n` for the n-th argument. Like TeX, a parameter character
+    /// followed by anything else (or by the number of a parameter that doesn't exist) is reported and kept as if it
+    /// were written as `##`.
+    fn parse_replacement_text(&mut self, tokens: Vec<Token<'pp>>, parameter_count: usize) -> Vec<ReplacementItem<'pp>> {
+        let mut replacement_text = Vec::with_capacity(tokens.len());
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            if token.is_not(TokenKind::Parameter) {
+                replacement_text.push(ReplacementItem::Token(token));
+                continue;
+            }
+            match token.parameter_index() {
+                Some(index) if index.get() as usize <= parameter_count => {
+                    replacement_text.push(ReplacementItem::Argument(index));
+                    continue;
+                },
+                // The lexer reads `##1` as `#` followed by `#1`; `#0` has no index either but is longer
+                None if token.length() == 1
+                    && let Some(next) = tokens.next_if(|next| next.is(TokenKind::Parameter)) => {
+                    replacement_text.push(ReplacementItem::Token(next));
+                    continue;
+                },
+                _ => self.diagnostics.push(Diagnostic::new(DiagnosticKind::IllegalParameterNumber, token.location())),
+            }
+            replacement_text.push(ReplacementItem::Token(token));
+        }
+        replacement_text
+    }
+
+    /// Reads the arguments of a macro used at `location` and returns its replacement text with the arguments
+    /// substituted. Returns no tokens if the input doesn't match the parameter text.
+    fn expand_macro(&mut self, location: SourceLocation, definition: &Macro<'pp>) -> Vec<Token<'pp>> {
+        let is_parameter = |token: &Token| token.is(TokenKind::Parameter);
+
+        // Tokens before the first parameter must follow the macro as they are
+        let prefix_length = definition.parameter_text.iter().position(is_parameter)
+            .unwrap_or(definition.parameter_text.len());
+        let (prefix, mut parameter_text) = definition.parameter_text.split_at(prefix_length);
+        for expected in prefix {
+            if self.consume_unexpanded_if(|token| token.is_same_token(expected)).is_none() {
+                self.diagnostics.push(Diagnostic::new(DiagnosticKind::MacroUseMismatch, location));
+                return Vec::new();
+            }
+        }
+
+        let mut arguments = Vec::new();
+        while let Some((_, rest)) = parameter_text.split_first() {
+            let delimiter_length = rest.iter().position(is_parameter).unwrap_or(rest.len());
+            let (delimiter, rest) = rest.split_at(delimiter_length);
+            let argument = if delimiter.is_empty() {
+                self.read_undelimited_argument(definition.long)
+            } else {
+                self.read_delimited_argument(delimiter, definition.long)
+            };
+            let Some(argument) = argument else {
+                self.diagnostics.push(Diagnostic::new(DiagnosticKind::RunawayArgument, location));
+                return Vec::new();
+            };
+            arguments.push(argument);
+            parameter_text = rest;
+        }
+
+        let mut expansion = Vec::new();
+        for item in &definition.replacement_text {
+            match item {
+                ReplacementItem::Token(token) => expansion.push(token.clone()),
+                ReplacementItem::Argument(index) => {
+                    expansion.extend(arguments.get(index.get() as usize - 1).into_iter().flatten().cloned());
+                },
+            }
+        }
+        expansion
+    }
+
+    /// Returns whether `token` ends a paragraph: `\par` or the [TokenKind::Paragraph] formed by the lexer from a blank
+    /// line. Like TeX, this goes by the name of the command rather than its meaning.
+    fn is_par(token: &Token<'pp>) -> bool {
+        token.is(TokenKind::Paragraph) || token.is_control_word(b"par")
+    }
+
+    /// Reads an undelimited macro argument: the next non-space token, or the tokens of a group without its braces.
+    /// Returns `None` if the input ends or a group is closed instead. Unless `long`, also returns `None` if a paragraph
+    /// ends first: a `\par` before the argument is left to be read again, while a group containing `\par` is dropped.
+    fn read_undelimited_argument(&mut self, long: bool) -> Option<Vec<Token<'pp>>> {
+        while self.consume_unexpanded_if(|token| token.is(TokenKind::Space)).is_some() {}
+
+        let mut token = Token::default();
+        if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+            return None;
+        }
+        if !long && Self::is_par(&token) {
+            self.push_back(token);
+            return None;
+        }
+        match token.kind() {
+            TokenKind::BeginGroup => {
+                self.push_back(token);
+                self.read_group().filter(|tokens| long || !tokens.iter().any(Self::is_par))
+            },
+            TokenKind::EndGroup => {
+                self.push_back(token);
+                None
+            },
+            _ => Some(vec![token]),
+        }
+    }
+
+    /// Reads a macro argument delimited by `delimiter`: the shortest balanced text followed by the delimiter, which is
+    /// consumed. Braces around the whole argument are removed. Returns `None` if the input ends or a group is closed
+    /// first, or, unless `long`, if a `\par` comes first, which is left to be read again.
+    fn read_delimited_argument(&mut self, delimiter: &[Token<'pp>], long: bool) -> Option<Vec<Token<'pp>>> {
+        let mut argument: Vec<Token<'pp>> = Vec::new();
+        let mut depth = 0usize;
+        let mut token = Token::default();
+        loop {
+            if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+                return None;
+            }
+            if !long && Self::is_par(&token) {
+                self.push_back(token);
+                return None;
+            }
+            match token.kind() {
+                TokenKind::BeginGroup => depth += 1,
+                TokenKind::EndGroup if depth == 0 => {
+                    self.push_back(token);
+                    return None;
+                },
+                TokenKind::EndGroup => depth -= 1,
+                _ => (),
+            }
+            argument.push(token.clone());
+
+            if depth == 0
+                && let Some(start) = argument.len().checked_sub(delimiter.len())
+                && argument[start..].iter().zip(delimiter).all(|(token, expected)| token.is_same_token(expected)) {
+                argument.truncate(start);
+                break;
+            }
+        }
+
+        // Remove the braces if the whole argument is a single group
+        if argument.first().is_some_and(|token| token.is(TokenKind::BeginGroup))
+            && argument.last().is_some_and(|token| token.is(TokenKind::EndGroup)) {
+            let mut depth = 0usize;
+            let closes_at_end = argument.iter().enumerate().all(|(i, token)| {
+                match token.kind() {
+                    TokenKind::BeginGroup => depth += 1,
+                    TokenKind::EndGroup => depth -= 1,
+                    _ => (),
+                }
+                depth > 0 || i == argument.len() - 1
+            });
+            if closes_at_end {
+                argument.pop();
+                argument.remove(0);
+            }
+        }
+        Some(argument)
+    }
+
+    fn integer_parameter(&self, parameter: IntegerParameter) -> i32 {
+        match parameter {
+            IntegerParameter::EscapeChar => self.escape_char,
+            IntegerParameter::EndLineChar => self.endline_char,
+        }
+    }
+
+    /// Reads `⟨optional spaces⟩⟨optional =⟩⟨number⟩` and assigns the number to `parameter`, locally to the current
+    /// group unless `global`. A missing number is treated as zero, as TeX does.
+    fn assign_integer_parameter(&mut self, parameter: IntegerParameter, global: bool) {
+        while self.consume_if(|token| token.is(TokenKind::Space)).is_some() {}
+        self.consume_if(|token| token.is(TokenKind::Other) && token.char() == '=');
+        let value = self.scan_number().unwrap_or(0);
+
+        let old_value = self.integer_parameter(parameter);
+        if global {
+            for saved in &mut self.saved_values {
+                saved.integer_parameters.retain(|(saved_parameter, _)| *saved_parameter != parameter);
+            }
+        } else if let Some(saved) = self.saved_values.last_mut()
+            && !saved.integer_parameters.iter().any(|(saved_parameter, _)| *saved_parameter == parameter) {
+            saved.integer_parameters.push((parameter, old_value));
+        }
+        self.store_integer_parameter(parameter, value);
+    }
+
+    fn store_integer_parameter(&mut self, parameter: IntegerParameter, value: i32) {
+        match parameter {
+            IntegerParameter::EscapeChar => self.escape_char = value,
+            IntegerParameter::EndLineChar => {
+                self.endline_char = value;
+                // As in TeX, the new value applies from the next line the current lexer reads
+                if let Some(lexer) = self.current_lexer() {
+                    lexer.set_endline_char_from_next_line(Self::endline_char_from_value(value));
+                }
+            },
+        }
+    }
+
+    /// Reads the character code after `\catcode` and returns the character, or `None` if the code isn't a valid
+    /// character
+    fn scan_catcode_char(&mut self) -> Option<MaybeChar> {
+        let code = self.scan_number().unwrap_or(0);
+        u32::try_from(code).ok().and_then(char::from_u32).map(MaybeChar::from_char)
+    }
+
+    /// Reads `⟨number⟩⟨equals⟩⟨number⟩` after `\catcode` and sets the category code of the character, locally to the
+    /// current group unless `global`. The category code applies to files entered later too. Invalid characters or
+    /// category codes are ignored.
+    fn assign_catcode(&mut self, global: bool) {
+        let maybe_char = self.scan_catcode_char();
+        while self.consume_if(|token| token.is(TokenKind::Space)).is_some() {}
+        self.consume_if(|token| token.is(TokenKind::Other) && token.char() == '=');
+        let value = self.scan_number().unwrap_or(0);
+
+        let category_code = u8::try_from(value).ok().and_then(CategoryCode::from_u8);
+        let (Some(maybe_char), Some(category_code)) = (maybe_char, category_code) else {
+            return;
+        };
+        if global {
+            for saved in &mut self.saved_values {
+                saved.category_codes.retain(|(saved_char, _)| *saved_char != maybe_char);
+            }
+        } else if let Some(saved) = self.saved_values.last_mut()
+            && !saved.category_codes.iter().any(|(saved_char, _)| *saved_char == maybe_char) {
+            saved.category_codes.push((maybe_char, self.category_code_table.get(maybe_char)));
+        }
+        self.store_category_code(maybe_char, category_code);
+    }
+
+    fn store_category_code(&mut self, maybe_char: MaybeChar, category_code: CategoryCode) {
+        self.category_code_table.set(maybe_char, category_code);
+        if let Some(lexer) = self.current_lexer() {
+            lexer.set_category_code(maybe_char, category_code);
+        }
+    }
+
+    /// Reads the register number after `\toks`, or `None` if it's out of range. Registers 0 to 32767 are available as
+    /// in eTeX.
+    fn scan_toks_register(&mut self) -> Option<u16> {
+        let number = self.scan_number().unwrap_or(0);
+        u16::try_from(number).ok().filter(|&number| number <= 32767)
+    }
+
+    /// Reads `⟨number⟩⟨equals⟩⟨general text⟩` after `\toks` and stores the tokens of the general text in the register,
+    /// locally to the current group unless `global`. Nothing is assigned if the register number is out of range or no
+    /// group follows.
+    fn assign_toks(&mut self, global: bool) {
+        let register = self.scan_toks_register();
+        while self.consume_if(|token| token.is(TokenKind::Space)).is_some() {}
+        self.consume_if(|token| token.is(TokenKind::Other) && token.char() == '=');
+        while self.consume_if(|token| token.is(TokenKind::Space)).is_some() {}
+
+        let (Some(tokens), Some(register)) = (self.read_group(), register) else {
+            return;
+        };
+        if global {
+            for saved in &mut self.saved_values {
+                saved.toks_registers.retain(|(saved_register, _)| *saved_register != register);
+            }
+        } else if let Some(saved) = self.saved_values.last_mut()
+            && !saved.toks_registers.iter().any(|(saved_register, _)| *saved_register == register) {
+            saved.toks_registers.push((register, self.toks_registers.get(&register).cloned().unwrap_or_default()));
+        }
+        self.store_toks_register(register, tokens);
+    }
+
+    fn store_toks_register(&mut self, register: u16, tokens: Vec<Token<'pp>>) {
+        if tokens.is_empty() {
+            self.toks_registers.remove(&register);
+        } else {
+            self.toks_registers.insert(register, tokens);
+        }
+    }
+
+    /// Makes the current lexer read with the category codes and the end-of-line character of the preprocessor, e.g.,
+    /// after entering a file or returning to the including file. The end-of-line character applies from the next line
+    /// the lexer reads, so the rest of the line of the including file keeps its own.
+    fn sync_current_lexer(&mut self) {
+        let category_code_table = self.category_code_table.clone();
+        let endline_char = Self::endline_char_from_value(self.endline_char);
+        if let Some(lexer) = self.current_lexer() {
+            lexer.set_category_code_table(category_code_table);
+            lexer.set_endline_char_from_next_line(endline_char);
+        }
+    }
+
+    /// Converts a value of `\endlinechar` to the character lexers read line breaks as. Out of range values (e.g., -1)
+    /// mean no character is appended to lines, as in TeX.
+    fn endline_char_from_value(value: i32) -> Option<MaybeChar> {
+        u32::try_from(value).ok().and_then(char::from_u32).map(MaybeChar::from_char)
+    }
+
+    /// Expands `\input` by reading a file name and entering the file. The name consists of the characters up to the
+    /// next space (which is absorbed), non-character token or the end of the file.
+    fn expand_input(&mut self, location: SourceLocation) {
+        let depth = self.include_stack.len();
+        while self.consume_in_file_if(depth, |token| token.is(TokenKind::Space)).is_some() {}
+
+        let mut name = String::new();
+        let is_char = |token: &Token<'pp>| token.is_one_of(&[TokenKind::Letter, TokenKind::Other]);
+        while let Some(token) = self.consume_in_file_if(depth, is_char) {
+            name.push(token.char());
+        }
+        self.consume_in_file_if(depth, |token| token.is(TokenKind::Space));
+
+        match self.resolve_input(&name) {
+            Some(file_id) => self.enter_file_at(file_id, location),
+            None => self.diagnostics.push(Diagnostic::new(DiagnosticKind::InputFileNotFound(name), location)),
+        }
+    }
+
+    /// Expands `\the` by reading the internal quantity that follows and returning its value as [TokenKind::Other]
+    /// tokens located at the `\the` token `command`. A token that isn't an internal quantity is left unread.
+    fn expand_the(&mut self, command: &Token<'pp>) -> Vec<Token<'pp>> {
+        let mut token = Token::default();
+        if !self.lex_unexpanded(&mut token) {
+            return Vec::new();
+        }
+
+        let value = match self.meaning(&token) {
+            Some(Meaning::Primitive(Primitive::IntegerParameter(parameter))) => {
+                self.integer_parameter(parameter).to_string()
+            },
+            Some(Meaning::Primitive(Primitive::Catcode)) => {
+                let category_code = self.scan_catcode_char().map_or(CategoryCode::Other, |ch| self.catcode_of(ch));
+                category_code.as_u8().to_string()
+            },
+            Some(Meaning::Primitive(Primitive::Toks)) => {
+                // Token lists are inserted as they are rather than converted to characters
+                return self.scan_toks_register()
+                    .and_then(|register| self.toks_registers.get(&register))
+                    .cloned()
+                    .unwrap_or_default();
+            },
+            _ => {
+                self.push_back(token);
+                return Vec::new();
+            },
+        };
+
+        Self::character_tokens(&value, command)
+    }
+
+    /// Expands `\string` by reading the next token without expansion and returning its characters: the escape
+    /// character (per `\escapechar`) and the name of a command, or the character of a character token. The characters
+    /// are located at the `\string` token `command`.
+    fn expand_string(&mut self, command: &Token<'pp>) -> Vec<Token<'pp>> {
+        let mut token = Token::default();
+        if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+            return Vec::new();
+        }
+
+        let escape_char = u32::try_from(self.escape_char).ok().and_then(char::from_u32);
+        let to_char = |maybe_char: MaybeChar| maybe_char.as_char().unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut text = String::new();
+        match token.kind() {
+            TokenKind::ControlWord => {
+                text.extend(escape_char);
+                text.push_str(&String::from_utf8_lossy(token.command_identifier().as_bytes()));
+            },
+            TokenKind::ControlSymbol => {
+                text.extend(escape_char);
+                text.extend(token.symbol().map(to_char));
+            },
+            TokenKind::ActiveChar => text.push_str(&String::from_utf8_lossy(token.command_identifier().as_bytes())),
+            TokenKind::Letter | TokenKind::Other => text.push(token.char()),
+            TokenKind::Space => text.push(' '),
+            // Other tokens don't carry their character, so read it from the input
+            _ => text.extend(self.char_of_token(&token).map(to_char)),
+        }
+
+        Self::character_tokens(&text, command)
+    }
+
+    /// Returns the name of a command without the escape character, as reported in [TraceEvent::MacroExpanded]
+    fn command_name(token: &Token<'pp>) -> String {
+        match token.kind() {
+            TokenKind::ControlWord | TokenKind::ActiveChar => {
+                String::from_utf8_lossy(token.command_identifier().as_bytes()).into_owned()
+            },
+            TokenKind::ControlSymbol => token.symbol()
+                .map(|symbol| symbol.as_char().unwrap_or(char::REPLACEMENT_CHARACTER).to_string())
+                .unwrap_or_default(),
+            _ => String::new(),
+        }
+    }
+
+    /// Returns the character code of a character token or a single-character command (e.g., `\%`), as used by
+    /// alphabetic constants
+    fn char_code(&self, token: &Token<'pp>) -> Option<u32> {
+        let maybe_char_code = |maybe_char: MaybeChar| match maybe_char.enum_view() {
+            MaybeCharEnumView::Char(ch) => ch as u32,
+            MaybeCharEnumView::NonCharByte(byte) => byte as u32,
+        };
+        match token.kind() {
+            TokenKind::Eof => None,
+            TokenKind::Letter | TokenKind::Other => Some(maybe_char_code(token.maybe_char())),
+            TokenKind::Space => Some(' ' as u32),
+            TokenKind::ControlSymbol => token.symbol().map(maybe_char_code),
+            TokenKind::ControlWord | TokenKind::ActiveChar => {
+                let name = std::str::from_utf8(token.command_identifier().as_bytes()).ok()?;
+                let mut chars = name.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => Some(ch as u32),
+                    _ => None,
+                }
+            },
+            // Other tokens don't carry their character, so read it from the input
+            _ => self.char_of_token(token).map(maybe_char_code),
+        }
+    }
+
+    /// Returns the character a token was formed from by reading it at the token's location in its own file, for tokens
+    /// that don't carry their character (e.g., [TokenKind::Superscript]). The token may come from a file other than the
+    /// current one, e.g., from a macro defined in a file that has ended.
+    fn char_of_token(&self, token: &Token<'pp>) -> Option<MaybeChar> {
+        let file_id = token.file_id().or_else(|| self.source_manager.get_file_id_for_location(token.location()))?;
+        if let Some(entry) = self.include_stack.iter().rev().find(|entry| entry.file_id == file_id) {
+            return entry.lexer.char_at(token.location());
+        }
+        // The file isn't being read anymore, so decode the character with a lexer over it. Reading a character interns
+        // no command identifiers, so the lexer gets a table of its own.
+        let data = self.source_manager.get_buffer_data(file_id)?.data();
+        let start_offset = self.source_manager.get_file(file_id)?.start_offset;
+        let command_identifier_table = CommandIdentifierTable::new();
+        let mut lexer = Lexer::from_bytes_window(data, 0, data.len(), start_offset, &command_identifier_table);
+        lexer.set_category_code_table(self.category_code_table.clone());
+        lexer.char_at(token.location())
+    }
+
+    /// Returns tokens for the characters of `text` located in the file and at the location of `command`, as TeX
+    /// produces them for `\the` and `\string`: [TokenKind::Space] for spaces and [TokenKind::Other] for any other
+    /// character.
+    fn character_tokens(text: &str, command: &Token<'pp>) -> Vec<Token<'pp>> {
+        text.chars().map(|ch| {
+            let mut token = Token::default();
+            token.set_location(command.location());
+            token.set_file_id(command.file_id());
+            if ch == ' ' {
+                token.set_kind(TokenKind::Space);
+            } else {
+                token.set_kind(TokenKind::Other);
+                token.set_token_data(TokenData::Char(ch));
+            }
+            token
+        }).collect()
+    }
+
+    /// Pushes back `tokens` so they are read again in order, e.g., the result of an expansion
+    fn push_back_tokens(&mut self, tokens: Vec<Token<'pp>>) {
+        self.pushback.extend(tokens.into_iter().rev());
+    }
+
+    /// Reads the next token and consumes it if it satisfies `pred`. Otherwise the token is pushed back so it is read
+    /// again by the next call to [Preprocessor::lex].
+    pub fn consume_if(&mut self, pred: impl FnOnce(&Token<'pp>) -> bool) -> Option<Token<'pp>> {
+        let mut token = Token::default();
+        // The token may be pushed back, so it doesn't begin or end a group here
+        if !self.lex_expanded(&mut token, true) {
+            return None;
+        }
+
+        if pred(&token) {
+            Some(token)
+        } else {
+            self.push_back(token);
+            None
+        }
+    }
+
+    /// Same as [Preprocessor::consume_if] but doesn't consume a token read after the end of the file at `depth` in the
+    /// include stack, e.g., to end the file name after `\input` at the end of the file
+    fn consume_in_file_if(&mut self, depth: usize, pred: impl FnOnce(&Token<'pp>) -> bool) -> Option<Token<'pp>> {
+        let token = self.consume_if(pred)?;
+        if self.include_stack.len() < depth {
+            self.push_back(token);
+            return None;
+        }
+        Some(token)
+    }
+
+    /// Consumes tokens without expanding them until a control word named like `target` is read (and consumed). If
+    /// `opener` is given, each control word named like it opens a nested construct whose own `target` is skipped too
+    /// (e.g., `\if...` and `\fi`). Returns `false` if the input ends first.
+    pub fn skip_until(&mut self, target: &CommandIdentifier, opener: Option<&CommandIdentifier>) -> bool {
+        let mut depth = 0usize;
+        let mut token = Token::default();
+        loop {
+            if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+                return false;
+            }
+            if token.is_not(TokenKind::ControlWord) {
+                continue;
+            }
+
+            let name = token.command_identifier().as_bytes();
+            if opener.is_some_and(|opener| opener.as_bytes() == name) {
+                depth += 1;
+            } else if target.as_bytes() == name {
+                if depth == 0 {
+                    return true;
+                }
+                depth -= 1;
+            }
+        }
+    }
+
+    /// Reads a group without expanding it: a [TokenKind::BeginGroup] token, a balanced text and the matching
+    /// [TokenKind::EndGroup] token. Returns the tokens between the braces, or `None` if the next token doesn't begin a
+    /// group (the token is left unread) or the input ends before the group does.
+    pub fn read_group(&mut self) -> Option<Vec<Token<'pp>>> {
+        self.consume_unexpanded_if(|token| token.is(TokenKind::BeginGroup))?;
+
+        let mut depth = 0usize;
+        let mut tokens = Vec::new();
+        let mut token = Token::default();
+        loop {
+            if !self.lex_unexpanded(&mut token) || token.is(TokenKind::Eof) {
+                return None;
+            }
+            match token.kind() {
+                TokenKind::BeginGroup => depth += 1,
+                TokenKind::EndGroup if depth == 0 => return Some(tokens),
+                TokenKind::EndGroup => depth -= 1,
+                _ => (),
+            }
+            tokens.push(token.clone());
+        }
+    }
+
+    /// Same as [Preprocessor::read_group] but expands the tokens of the group. Commands that aren't expandable are read
+    /// as they are rather than carried out.
+    fn read_expanded_group(&mut self) -> Option<Vec<Token<'pp>>> {
+        self.consume_unexpanded_if(|token| token.is(TokenKind::BeginGroup))?;
+
+        let mut depth = 0usize;
+        let mut tokens = Vec::new();
+        let mut token = Token::default();
+        loop {
+            if !self.lex_expanded(&mut token, false) || token.is(TokenKind::Eof) {
+                return None;
+            }
+            match token.kind() {
+                TokenKind::BeginGroup => depth += 1,
+                TokenKind::EndGroup if depth == 0 => return Some(tokens),
+                TokenKind::EndGroup => depth -= 1,
+                _ => (),
+            }
+            tokens.push(token.clone());
+        }
+    }
+
+    /// Same as [Preprocessor::consume_if] but reads the next token without expanding it
+    fn consume_unexpanded_if(&mut self, pred: impl FnOnce(&Token<'pp>) -> bool) -> Option<Token<'pp>> {
+        let mut token = Token::default();
+        if !self.lex_unexpanded(&mut token) {
+            return None;
+        }
+
+        if pred(&token) {
+            Some(token)
+        } else {
+            self.push_back(token);
+            None
+        }
+    }
+
+    /// Scans a TeX number: optional signs and spaces followed by decimal digits or an alphabetic constant (e.g., `` `a``
+    /// or `` `\%``). A single space or `\relax` right after the digits terminates the number and is absorbed (e.g.,
+    /// `\count0=5\relax`); only a space is absorbed after an alphabetic constant. Returns `None` without consuming
+    /// anything (the signs and spaces read are pushed back with the offending token) if no number is found.
+    pub fn scan_number(&mut self) -> Option<i32> {
+        let mut token = Token::default();
+        let mut negative = false;
+        let mut prefix = Vec::new();
+
+        // The token after the number is pushed back, so it doesn't begin or end a group here
+        loop {
+            if !self.lex_expanded(&mut token, true) {
+                self.push_back_tokens(prefix);
+                return None;
+            }
+            match token.kind() {
+                TokenKind::Space => (),
+                TokenKind::Other if token.char() == '+' => (),
+                TokenKind::Other if token.char() == '-' => negative = !negative,
+                _ => break,
+            }
+            prefix.push(token.clone());
+        }
+
+        if token.is(TokenKind::Other) && token.char() == '`' {
+            prefix.push(token);
+            let mut token = Token::default();
+            if !self.lex_unexpanded(&mut token) {
+                self.push_back_tokens(prefix);
+                return None;
+            }
+            let Some(code) = self.char_code(&token) else {
+                prefix.push(token);
+                self.push_back_tokens(prefix);
+                return None;
+            };
+            self.consume_if(|token| token.is(TokenKind::Space));
+
+            let value = code as i32;
+            return Some(if negative { -value } else { value });
+        }
+
+        let mut value: Option<i32> = None;
+        while token.is(TokenKind::Other) && let Some(digit) = token.char().to_digit(10) {
+            value = Some(value.unwrap_or(0).saturating_mul(10).saturating_add(digit as i32));
+            if !self.lex_expanded(&mut token, true) {
+                return value.map(|value| if negative { -value } else { value });
+            }
+        }
+
+        let Some(value) = value else {
+            if token.is_not(TokenKind::Eof) {
+                prefix.push(token);
+            }
+            self.push_back_tokens(prefix);
+            return None;
+        };
+        let is_relax = matches!(self.meaning(&token), Some(Meaning::Primitive(Primitive::Relax)));
+        if !token.is(TokenKind::Space) && !is_relax && token.is_not(TokenKind::Eof) {
+            self.push_back(token);
+        }
+
+        Some(if negative { -value } else { value })
     }
 }
@@ -1,5 +1,5 @@
 use std::num::NonZeroU8;
-use retex_base::{SourceLocation, SourceRange, MaybeChar};
+use retex_base::{FileId, SourceLocation, SourceManager, SourceRange, MaybeChar};
 use crate::command_identifier::CommandIdentifier;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -31,6 +31,17 @@ pub struct TokenFlags(u8);
 impl TokenFlags {
     pub const NONE: Self = Self(0);
     pub const START_OF_LINE: Self = Self(1 << 0);
+    /// The symbol of a [TokenKind::ControlSymbol] token was read through a transformation of the input (e.g., caret
+    /// notation like `\^^@`)
+    pub const TRANSFORMED: Self = Self(1 << 1);
+    /// The token is an implicit character: it was produced by a command `\let` to a character (e.g., `\bgroup` after
+    /// `\let\bgroup={`) and has the kind and data of that character
+    pub const IMPLICIT: Self = Self(1 << 2);
+    /// The token was formed from malformed input in strict mode (see [crate::Lexer::set_strict])
+    pub const ERROR: Self = Self(1 << 3);
+    /// A [TokenKind::Space] formed from the end of a line (a soft break), as opposed to a space character in the input.
+    /// Blank lines (hard breaks) form [TokenKind::Paragraph] instead.
+    pub const SOFT_BREAK: Self = Self(1 << 4);
 
     pub fn new() -> Self {
         Self::NONE
@@ -57,7 +68,7 @@ impl Default for TokenFlags {
 
 
 /// Carries data associated to a token. The actual type depends on token's [TokenKind].
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenData<'token> {
     /// No token data
     ///
@@ -86,6 +97,13 @@ pub enum TokenData<'token> {
     /// [^1]: [Unicode scalar value](https://www.unicode.org/glossary/#unicode_scalar_value)
     Char(char),
 
+    /// A byte that isn't a character by itself (e.g., `^^ff`); see [retex_base::MaybeChar]
+    ///
+    /// [TokenKind]'s associated with this data:
+    /// * [TokenKind::Letter]
+    /// * [TokenKind::Other]
+    NonCharByte(u8),
+
     /// Index of a [TokenKind::Parameter] token that represent a macro parameter; The value range is between 1 and 9
     /// (inclusive) according to TeX specification. It is optional to be lenient on singular parameter character without
     /// specifying any parameter index
@@ -102,6 +120,24 @@ pub enum TokenData<'token> {
     CommandIdentifier(&'token CommandIdentifier<'token>),
 }
 
+/// Data of a token returned by [Token::data_view] to match on, rather than calling the accessors that panic for tokens
+/// of other kinds (e.g., [Token::char])
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenDataView<'token> {
+    /// The token carries no data (e.g., [TokenKind::BeginGroup])
+    None,
+    /// Character of a [TokenKind::Letter] or [TokenKind::Other] token
+    Char(char),
+    /// Non-character byte of a [TokenKind::Letter] or [TokenKind::Other] token
+    NonCharByte(u8),
+    /// Index of a [TokenKind::Parameter] token, if any
+    Parameter(Option<NonZeroU8>),
+    /// Symbol of a [TokenKind::ControlSymbol] token, if any
+    Symbol(Option<MaybeChar>),
+    /// [CommandIdentifier] of a [TokenKind::ControlWord] or [TokenKind::ActiveChar] token
+    Command(&'token CommandIdentifier<'token>),
+}
+
 /// Represent a token output by [Lexer] and [Preprocessor]. Size is not a primary concern because the input is processed
 /// as a stream of tokens and same [Token] instance for previous token is reused for reading the next token.
 #[derive(Debug, Clone)]
@@ -112,6 +148,14 @@ pub struct Token<'token> {
     /// Number of bytes in the input that is accounted by this token
     length: u32,
     data: TokenData<'token>,
+    /// File the token was read from, if known
+    file_id: Option<FileId>,
+    /// [crate::Preprocessor] has begun or ended a group for this [TokenKind::BeginGroup] or [TokenKind::EndGroup] token,
+    /// so reading it again (e.g., after it's pushed back) doesn't begin or end another group
+    group_counted: bool,
+    /// [crate::Preprocessor] produced this token with `\the` while reading the replacement text of `\edef`, where it's
+    /// taken as it is rather than expanded again; cleared once the token is read
+    expansion_suppressed: bool,
 }
 
 impl<'token> Token<'token> {
@@ -122,6 +166,9 @@ impl<'token> Token<'token> {
         self.location = SourceLocation::invalid();
         self.length = 0;
         self.data = TokenData::None;
+        self.file_id = None;
+        self.group_counted = false;
+        self.expansion_suppressed = false;
     }
 
     pub fn kind(&self) -> TokenKind {
@@ -172,6 +219,70 @@ impl<'token> Token<'token> {
         self.length = length;
     }
 
+    /// Returns the number of raw bytes of the input the token was read from, which is the same as [Token::length].
+    /// Together with the logical character (e.g., [Token::maybe_char]) it maps a transformed token back to its source:
+    /// `^^A` is one character read from 3 bytes, and a line break written as "\r\n" is one space read from 2 bytes.
+    pub fn raw_source_len(&self) -> u32 {
+        self.length
+    }
+
+    /// Returns the number of logical characters the token represents, as opposed to [Token::length] that counts the
+    /// bytes it spans in the input. They differ for caret notation (e.g., `^^A` is 3 bytes for one character), line
+    /// breaks read as `\r\n` and multibyte characters in names. A control sequence counts its escape character and the
+    /// characters of its name (e.g., 4 for `\foo`), a parameter counts its digit (e.g., 2 for `#1`), a space token
+    /// counts as one character even if it spans a run of spaces, and [TokenKind::Eof] counts as none.
+    pub fn char_length(&self) -> u32 {
+        match (self.kind, &self.data) {
+            (TokenKind::Eof, _) => 0,
+            (TokenKind::ControlWord, TokenData::CommandIdentifier(id)) => {
+                1 + id.as_bytes().utf8_chunks()
+                    .map(|chunk| chunk.valid().chars().count() + chunk.invalid().len())
+                    .sum::<usize>() as u32
+            },
+            (TokenKind::ControlSymbol, TokenData::Symbol(symbol)) => 1 + symbol.is_some() as u32,
+            (TokenKind::Parameter, TokenData::ParameterIndex(index)) => 1 + index.is_some() as u32,
+            _ => 1,
+        }
+    }
+
+    /// Returns the file the token was read from, if the lexer that formed it knows its file (see
+    /// [crate::Lexer::set_file_id]). This avoids looking up the location in the [retex_base::SourceManager], e.g., for
+    /// tokens read by [crate::Preprocessor] from several files.
+    pub fn file_id(&self) -> Option<FileId> {
+        self.file_id
+    }
+
+    pub fn set_file_id(&mut self, file_id: Option<FileId>) {
+        self.file_id = file_id;
+    }
+
+    pub(crate) fn is_group_counted(&self) -> bool {
+        self.group_counted
+    }
+
+    pub(crate) fn set_group_counted(&mut self) {
+        self.group_counted = true;
+    }
+
+    /// Returns whether expansion of the token is suppressed and clears the mark
+    pub(crate) fn take_expansion_suppressed(&mut self) -> bool {
+        std::mem::take(&mut self.expansion_suppressed)
+    }
+
+    pub(crate) fn set_expansion_suppressed(&mut self) {
+        self.expansion_suppressed = true;
+    }
+
+    /// Returns the exact bytes of the input covered by the token, i.e., the [Token::raw_source_len] bytes at its
+    /// location, e.g., to reconstruct the input or quote it in diagnostics. The bytes are read from the file of the
+    /// token (see [Token::file_id]) or else the file containing its location. Returns `None` if the token isn't located
+    /// in a file of `source_manager`. Tokens that weren't read from input (e.g., ones produced by `\string`) and
+    /// [TokenKind::Eof] cover no bytes.
+    pub fn raw_bytes<'sm>(&self, source_manager: &'sm SourceManager) -> Option<&'sm [u8]> {
+        let file_id = self.file_id.or_else(|| source_manager.get_file_id_for_location(self.location))?;
+        source_manager.get_file(file_id)?.slice_local(self.range())
+    }
+
     pub fn flags(&self) -> TokenFlags {
         self.flags
     }
@@ -188,10 +299,23 @@ impl<'token> Token<'token> {
         self.flags.has(flag)
     }
 
+    /// Returns the character of a [TokenKind::Letter] or [TokenKind::Other] token. A non-character byte is returned as
+    /// U+FFFD (replacement character); use [Token::maybe_char] to tell them apart.
     pub fn char(&self) -> char {
         assert!(matches!(self.kind, TokenKind::Letter | TokenKind::Other));
         match &self.data {
             TokenData::Char(ch) => *ch,
+            TokenData::NonCharByte(_) => char::REPLACEMENT_CHARACTER,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the character or non-character byte of a [TokenKind::Letter] or [TokenKind::Other] token
+    pub fn maybe_char(&self) -> MaybeChar {
+        assert!(matches!(self.kind, TokenKind::Letter | TokenKind::Other));
+        match &self.data {
+            TokenData::Char(ch) => MaybeChar::from_char(*ch),
+            TokenData::NonCharByte(byte) => MaybeChar::from_non_char_byte(*byte),
             _ => unreachable!(),
         }
     }
@@ -212,7 +336,26 @@ impl<'token> Token<'token> {
         }
     }
 
-    pub fn command_identifier(&self) -> &CommandIdentifier<'token> {
+    /// Returns whether the symbol of a [TokenKind::ControlSymbol] token came from transformed input (e.g., caret
+    /// notation) rather than appearing literally
+    pub fn symbol_is_transformed(&self) -> bool {
+        assert_eq!(self.kind, TokenKind::ControlSymbol);
+        self.has_flag(TokenFlags::TRANSFORMED)
+    }
+
+    /// Returns the data of this token as a [TokenDataView], which works for tokens of any kind
+    pub fn data_view(&self) -> TokenDataView<'token> {
+        match self.data {
+            TokenData::None => TokenDataView::None,
+            TokenData::Char(ch) => TokenDataView::Char(ch),
+            TokenData::NonCharByte(byte) => TokenDataView::NonCharByte(byte),
+            TokenData::ParameterIndex(index) => TokenDataView::Parameter(index),
+            TokenData::Symbol(symbol) => TokenDataView::Symbol(symbol),
+            TokenData::CommandIdentifier(id) => TokenDataView::Command(id),
+        }
+    }
+
+    pub fn command_identifier(&self) -> &'token CommandIdentifier<'token> {
         assert!(matches!(self.kind, TokenKind::ControlWord | TokenKind::ActiveChar));
         match &self.data {
             TokenData::CommandIdentifier(id) => id,
@@ -220,10 +363,73 @@ impl<'token> Token<'token> {
         }
     }
 
+    /// Returns whether this token is a [TokenKind::ControlWord] with the given name. Unlike
+    /// [Token::command_identifier], this doesn't panic for tokens of other kinds.
+    pub fn is_control_word(&self, name: &[u8]) -> bool {
+        self.kind == TokenKind::ControlWord
+            && matches!(self.data, TokenData::CommandIdentifier(id) if *id == *name)
+    }
+
+    /// Returns whether this token is a [TokenKind::ActiveChar] for the given character (UTF-8 encoded)
+    pub fn is_active_char(&self, name: &[u8]) -> bool {
+        self.kind == TokenKind::ActiveChar
+            && matches!(self.data, TokenData::CommandIdentifier(id) if *id == *name)
+    }
+
+    /// Returns the character of a [TokenKind::ActiveChar] token, decoded from its command identifier, or `None` for
+    /// tokens of other kinds
+    pub fn active_char(&self) -> Option<MaybeChar> {
+        if self.kind != TokenKind::ActiveChar {
+            return None;
+        }
+        let TokenData::CommandIdentifier(id) = self.data else {
+            return None;
+        };
+        match id.as_bytes() {
+            [byte] if !byte.is_ascii() => Some(MaybeChar::from_non_char_byte(*byte)),
+            bytes => {
+                let mut chars = std::str::from_utf8(bytes).ok()?.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(ch), None) => Some(MaybeChar::from_char(ch)),
+                    _ => None,
+                }
+            },
+        }
+    }
+
+    /// Returns whether this token is a command for explicit spacing: the control symbols `\,`, `\;`, `\!` and `\ `,
+    /// or the control words `\quad` and `\qquad`
+    pub fn is_spacing_command(&self) -> bool {
+        match &self.data {
+            TokenData::Symbol(Some(symbol)) if self.kind == TokenKind::ControlSymbol => {
+                matches!(symbol.as_char(), Some(',' | ';' | '!' | ' '))
+            },
+            TokenData::CommandIdentifier(id) if self.kind == TokenKind::ControlWord => {
+                matches!(id.as_bytes(), b"quad" | b"qquad")
+            },
+            _ => false,
+        }
+    }
+
+    /// Returns whether this token is the LaTeX line break command `\\`, i.e., a control symbol whose symbol is `\`
+    pub fn is_line_break_command(&self) -> bool {
+        self.kind == TokenKind::ControlSymbol
+            && matches!(self.data, TokenData::Symbol(Some(symbol)) if symbol.as_char() == Some('\\'))
+    }
+
+    /// Returns whether this token is the same TeX token as `other`: the same kind and data (character, command, etc.)
+    /// regardless of location and flags. This is how TeX compares tokens, e.g., when matching the delimiters of a
+    /// macro parameter.
+    pub fn is_same_token(&self, other: &Token<'token>) -> bool {
+        self.kind == other.kind && self.data == other.data
+    }
+
     pub fn set_token_data(&mut self, data: TokenData<'token>) {
         match data {
             TokenData::None => (),
-            TokenData::Char(_) => assert!(matches!(self.kind, TokenKind::Letter | TokenKind::Other)),
+            TokenData::Char(_) | TokenData::NonCharByte(_) => {
+                assert!(matches!(self.kind, TokenKind::Letter | TokenKind::Other))
+            },
             TokenData::ParameterIndex(_) => assert_eq!(self.kind, TokenKind::Parameter),
             TokenData::Symbol(_) => assert_eq!(self.kind, TokenKind::ControlSymbol),
             TokenData::CommandIdentifier(_) => assert!(matches!(self.kind, TokenKind::ControlWord | TokenKind::ActiveChar)),
@@ -234,6 +440,12 @@ impl<'token> Token<'token> {
     pub fn at_start_of_line(&self) -> bool {
         self.has_flag(TokenFlags::START_OF_LINE)
     }
+
+    /// Returns whether this token is an implicit character, i.e., it came from a command that was `\let` to a
+    /// character rather than from the character itself
+    pub fn is_implicit_character(&self) -> bool {
+        self.has_flag(TokenFlags::IMPLICIT)
+    }
 }
 
 impl<'token> Default for Token<'token> {
@@ -244,10 +456,20 @@ impl<'token> Default for Token<'token> {
             location: SourceLocation::invalid(),
             length: 0,
             data: TokenData::None,
+            file_id: None,
+            group_counted: false,
+            expansion_suppressed: false,
         }
     }
 }
 
+/// Finds the token whose range contains `loc` in `tokens`, which are ordered by location (e.g., the tokens of a
+/// document from [crate::Lexer]). Empty tokens (e.g., [TokenKind::Eof]) are never found.
+pub fn token_at<'a, 'token>(tokens: &'a [Token<'token>], loc: SourceLocation) -> Option<&'a Token<'token>> {
+    let index = tokens.partition_point(|token| token.end_location().offset() <= loc.offset());
+    tokens.get(index).filter(|token| token.range().contains(loc))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -398,6 +620,39 @@ mod tests {
         assert_eq!(token.parameter_index(), NonZeroU8::new(index));
     }
 
+    #[test]
+    fn test_token_data_view() {
+        use crate::command_identifier::CommandIdentifierTable;
+
+        fn token<'a>(kind: TokenKind, data: TokenData<'a>) -> Token<'a> {
+            let mut token = Token::default();
+            token.set_kind(kind);
+            token.set_token_data(data);
+            token
+        }
+
+        let table = CommandIdentifierTable::new();
+        let describe = |token: &Token| match token.data_view() {
+            TokenDataView::None => "none".to_string(),
+            TokenDataView::Char(ch) => format!("char {ch}"),
+            TokenDataView::NonCharByte(byte) => format!("byte {byte:02x}"),
+            TokenDataView::Parameter(index) => format!("parameter {index:?}"),
+            TokenDataView::Symbol(symbol) => format!("symbol {symbol:?}"),
+            TokenDataView::Command(id) => format!("command {}", String::from_utf8_lossy(id.as_bytes())),
+        };
+
+        assert_eq!(describe(&token(TokenKind::BeginGroup, TokenData::None)), "none");
+        assert_eq!(describe(&token(TokenKind::Letter, TokenData::Char('a'))), "char a");
+        assert_eq!(describe(&token(TokenKind::Other, TokenData::NonCharByte(0xff))), "byte ff");
+        assert_eq!(describe(&token(TokenKind::Parameter, TokenData::ParameterIndex(NonZeroU8::new(2)))),
+            "parameter Some(2)");
+        assert_eq!(describe(&token(TokenKind::ControlSymbol, TokenData::Symbol(None))), "symbol None");
+        assert_eq!(describe(&token(TokenKind::ControlWord, TokenData::CommandIdentifier(table.get_or_insert(b"foo")))),
+            "command foo");
+        assert_eq!(describe(&token(TokenKind::ActiveChar, TokenData::CommandIdentifier(table.get_or_insert(b"~")))),
+            "command ~");
+    }
+
     #[test]
     fn test_token_at_start_of_line() {
         let mut token = Token::default();
@@ -408,6 +663,45 @@ mod tests {
     }
 
 
+    #[test]
+    fn test_token_is_control_word() {
+        use crate::command_identifier::CommandIdentifierTable;
+
+        let table = CommandIdentifierTable::new();
+        let mut foo = Token::default();
+        foo.set_kind(TokenKind::ControlWord);
+        foo.set_token_data(TokenData::CommandIdentifier(table.get_or_insert(b"foo")));
+        let mut bar = Token::default();
+        bar.set_kind(TokenKind::ControlWord);
+        bar.set_token_data(TokenData::CommandIdentifier(table.get_or_insert(b"bar")));
+        let mut letter = Token::default();
+        letter.set_kind(TokenKind::Letter);
+        letter.set_token_data(TokenData::Char('f'));
+
+        assert!(foo.is_control_word(b"foo"));
+        assert!(!bar.is_control_word(b"foo"));
+        assert!(bar.is_control_word(b"bar"));
+        assert!(!letter.is_control_word(b"foo"));
+        assert!(!foo.is_active_char(b"foo"));
+        assert!(!letter.is_active_char(b"f"));
+
+        let mut tilde = Token::default();
+        tilde.set_kind(TokenKind::ActiveChar);
+        tilde.set_token_data(TokenData::CommandIdentifier(table.get_or_insert(b"~")));
+        assert!(tilde.is_active_char(b"~"));
+        assert!(!tilde.is_control_word(b"~"));
+    }
+
+    #[test]
+    fn test_token_is_implicit_character() {
+        let mut token = Token::default();
+        token.set_kind(TokenKind::BeginGroup);
+        assert!(!token.is_implicit_character());
+
+        token.set_flag(TokenFlags::IMPLICIT);
+        assert!(token.is_implicit_character());
+    }
+
     #[test]
     fn test_token_default() {
         let token = Token::default();
@@ -471,6 +765,17 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_token_symbol_is_transformed() {
+        let mut token = Token::default();
+        token.set_kind(TokenKind::ControlSymbol);
+        token.set_token_data(TokenData::Symbol(Some(MaybeChar::from_char('\0'))));
+        assert!(!token.symbol_is_transformed());
+
+        token.set_flag(TokenFlags::TRANSFORMED);
+        assert!(token.symbol_is_transformed());
+    }
+
     #[test]
     fn test_token_with_command_identifier() {
         use crate::command_identifier::CommandIdentifierTable;
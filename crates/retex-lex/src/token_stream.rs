@@ -0,0 +1,92 @@
+use retex_base::{SourceLocation, SourceRange};
+use crate::token::{Token, TokenFlags, TokenKind};
+
+/// Columnar storage for a sequence of tokens. Kinds, offsets, lengths and flags are kept in parallel vectors instead of
+/// a `Vec<Token>` so analysis passes that only look at a few fields of many tokens stay cache friendly. Token data
+/// (characters, command identifiers, etc.) is not stored; re-lex the token's range if it's needed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TokenStream {
+    kinds: Vec<TokenKind>,
+    offsets: Vec<u32>,
+    lengths: Vec<u32>,
+    flags: Vec<TokenFlags>,
+}
+
+impl TokenStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, token: &Token) {
+        self.kinds.push(token.kind());
+        self.offsets.push(token.location().offset());
+        self.lengths.push(token.length());
+        self.flags.push(token.flags());
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    pub fn kind(&self, index: usize) -> TokenKind {
+        self.kinds[index]
+    }
+
+    pub fn location(&self, index: usize) -> SourceLocation {
+        SourceLocation::new(self.offsets[index])
+    }
+
+    pub fn length(&self, index: usize) -> u32 {
+        self.lengths[index]
+    }
+
+    pub fn flags(&self, index: usize) -> TokenFlags {
+        self.flags[index]
+    }
+
+    pub fn range(&self, index: usize) -> SourceRange {
+        let start = self.offsets[index];
+        SourceRange::new(SourceLocation::new(start), SourceLocation::new(start + self.lengths[index]))
+    }
+
+    pub fn kinds(&self) -> &[TokenKind] {
+        &self.kinds
+    }
+
+    pub fn offsets(&self) -> &[u32] {
+        &self.offsets
+    }
+
+    pub fn lengths(&self) -> &[u32] {
+        &self.lengths
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_stream_push() {
+        let mut stream = TokenStream::new();
+        assert!(stream.is_empty());
+
+        let mut token = Token::default();
+        token.set_kind(TokenKind::Space);
+        token.set_location(SourceLocation::new(4));
+        token.set_length(2);
+        token.set_flag(TokenFlags::START_OF_LINE);
+        stream.push(&token);
+
+        assert_eq!(stream.len(), 1);
+        assert_eq!(stream.kind(0), TokenKind::Space);
+        assert_eq!(stream.location(0), SourceLocation::new(4));
+        assert_eq!(stream.length(0), 2);
+        assert_eq!(stream.flags(0), TokenFlags::START_OF_LINE);
+        assert_eq!(stream.range(0), SourceRange::new(SourceLocation::new(4), SourceLocation::new(6)));
+    }
+}
@@ -1,6 +1,7 @@
-use retex_lex::{Lexer, Token, TokenKind, TokenFlags};
-use retex_lex::category_code::CategoryCode;
-use retex_base::{MaybeChar, SourceLocation};
+use retex_lex::{DiagnosticKind, DimensionUnit, Lexer, LexerConfig, Token, TokenKind, TokenFlags, TokenStream, lex_to_strings,
+    split_into_chunks, token_at};
+use retex_lex::category_code::{CategoryCode, CategoryCodeTable};
+use retex_base::{MaybeChar, MemoryBuffer, SourceLocation, SourceManager};
 use retex_lex::token::TokenData;
 use std::num::NonZeroU8;
 use retex_lex::command_identifier::CommandIdentifierTable;
@@ -8,6 +9,7 @@ use retex_lex::command_identifier::CommandIdentifierTable;
 /// Helper constants for common flag combinations
 const NO_FLAGS: TokenFlags = TokenFlags::NONE;
 const START_OF_LINE: TokenFlags = TokenFlags::START_OF_LINE;
+const SOFT_BREAK: TokenFlags = TokenFlags::SOFT_BREAK;
 
 fn assert_tokens_match(input: &str, expected: &[(TokenKind, SourceLocation, u32, TokenFlags, TokenData)]) {
     let command_identifier_table = CommandIdentifierTable::new();
@@ -53,8 +55,13 @@ fn assert_tokens_match_with_lexer(
         // Validate token data based on token kind using matches! with guards
         match exp_kind {
             TokenKind::Letter | TokenKind::Other => {
-                assert!(matches!(exp_data, TokenData::Char(expected_char) if act.char() == *expected_char),
-                    "Token {} data mismatch: expected char {:?}, got char {:?}", i, exp_data, act.char());
+                let expected_char = match exp_data {
+                    TokenData::Char(ch) => Some(MaybeChar::from_char(*ch)),
+                    TokenData::NonCharByte(byte) => Some(MaybeChar::from_non_char_byte(*byte)),
+                    _ => None,
+                };
+                assert_eq!(expected_char, Some(act.maybe_char()),
+                    "Token {} data mismatch: expected {:?}, got {:?}", i, exp_data, act.maybe_char());
             },
             TokenKind::Parameter => {
                 assert!(matches!(exp_data, TokenData::ParameterIndex(expected_index) if act.parameter_index() == *expected_index),
@@ -72,7 +79,7 @@ fn assert_tokens_match_with_lexer(
             _ => {
                 // For tokens with TokenData::None (Eof, Unknown, BeginGroup, EndGroup, etc.)
                 assert!(matches!(exp_data, TokenData::None),
-                    "Token {} data mismatch: expected None data for {:?}, got {:?}", i, exp_kind, exp_data);
+                    "Token {i} data mismatch: expected None data for {exp_kind:?}, got {exp_data:?}");
             }
         }
     }
@@ -136,6 +143,57 @@ fn test_control_word_with_caret_notation_in_middle() {
     ]);
 }
 
+#[test]
+fn test_control_word_ended_by_ignored_caret_char_strict() {
+    let id_table = CommandIdentifierTable::new();
+
+    let mut lexer = Lexer::from_bytes("\\te^^?st".as_bytes(), &id_table);
+    lexer.set_strict(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 3, START_OF_LINE, TokenData::CommandIdentifier(id_table.get_or_insert(b"te"))),
+        (TokenKind::Letter, SourceLocation::new(6), 1, NO_FLAGS, TokenData::Char('s')),
+        (TokenKind::Letter, SourceLocation::new(7), 1, NO_FLAGS, TokenData::Char('t')),
+        (TokenKind::Eof, SourceLocation::new(8), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    assert_eq!(lexer.diagnostics().len(), 1);
+    assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::ControlWordEndedByIgnoredChar);
+    assert_eq!(lexer.diagnostics()[0].location(), SourceLocation::new(3));
+
+    // No diagnostic outside of strict mode
+    let mut lexer = Lexer::from_bytes("\\te^^?st".as_bytes(), &id_table);
+    while lexer.next_token().is_not(TokenKind::Eof) {}
+    assert!(lexer.diagnostics().is_empty());
+}
+
+#[test]
+fn test_control_word_ended_by_caret_space_strict() {
+    let id_table = CommandIdentifierTable::new();
+
+    let mut lexer = Lexer::from_bytes("\\ab^^I".as_bytes(), &id_table);
+    lexer.set_strict(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 3, START_OF_LINE, TokenData::CommandIdentifier(id_table.get_or_insert(b"ab"))),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+    assert_eq!(lexer.diagnostics().len(), 1);
+    assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::ControlWordEndedByCaretSpace);
+    assert_eq!(lexer.diagnostics()[0].location(), SourceLocation::new(3));
+
+    // A space or tab written as it is, or a line break, ends the control word without a diagnostic
+    for input in ["\\ab b", "\\ab\tb", "\\ab\r\nb", "\\ab^^Mb"] {
+        let mut lexer = Lexer::from_bytes(input.as_bytes(), &id_table);
+        lexer.set_strict(true);
+        while lexer.next_token().is_not(TokenKind::Eof) {}
+        assert!(lexer.diagnostics().is_empty(), "{input:?}");
+    }
+
+    // No diagnostic outside of strict mode
+    let mut lexer = Lexer::from_bytes("\\ab^^I".as_bytes(), &id_table);
+    while lexer.next_token().is_not(TokenKind::Eof) {}
+    assert!(lexer.diagnostics().is_empty());
+}
+
 #[test]
 fn test_control_symbol() {
     assert_tokens_match("\\{  ", &[
@@ -268,7 +326,7 @@ fn test_comment() {
 fn test_newline_handling() {
     assert_tokens_match("a\nb", &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')), // a
-        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None), // newline becomes space
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None), // newline becomes space
         (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')), // b
         (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
     ]);
@@ -334,7 +392,7 @@ fn test_lexer_from_bytes() {
 fn test_carriage_return_handling() {
     assert_tokens_match("a\rb", &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')), // a
-        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None), // \r becomes space
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None), // \r becomes space
         (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')), // b
         (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
     ]);
@@ -410,6 +468,54 @@ fn test_unicode_source_locations() {
         (TokenKind::EndGroup, SourceLocation::new(5), 1, NO_FLAGS, TokenData::None), // }
         (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
     ]);
+
+    // In unicode mode, each character is a single token spanning all of its bytes
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("α{β}".as_bytes(), &id_table);
+    lexer.set_unicode_mode(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Other, SourceLocation::new(0), 2, START_OF_LINE, TokenData::Char('α')),
+        (TokenKind::BeginGroup, SourceLocation::new(2), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Other, SourceLocation::new(3), 2, NO_FLAGS, TokenData::Char('β')),
+        (TokenKind::EndGroup, SourceLocation::new(5), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_unicode_mode() {
+    let id_table = CommandIdentifierTable::new();
+
+    // Characters of the BMP take 2 or 3 bytes
+    let mut lexer = Lexer::from_bytes("α{€}".as_bytes(), &id_table);
+    lexer.set_unicode_mode(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Other, SourceLocation::new(0), 2, START_OF_LINE, TokenData::Char('α')),
+        (TokenKind::BeginGroup, SourceLocation::new(2), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Other, SourceLocation::new(3), 3, NO_FLAGS, TokenData::Char('€')),
+        (TokenKind::EndGroup, SourceLocation::new(6), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(7), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Characters outside of the BMP take 4 bytes and follow their category codes
+    let mut lexer = Lexer::from_bytes("\\a𝄞b".as_bytes(), &id_table);
+    lexer.set_unicode_mode(true);
+    lexer.set_category_code(MaybeChar::from_char('𝄞'), CategoryCode::Letter);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 7, START_OF_LINE, TokenData::CommandIdentifier(id_table.get_or_insert("a𝄞b".as_bytes()))),
+        (TokenKind::Eof, SourceLocation::new(7), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // A sequence truncated by the end of input is read byte by byte
+    let mut lexer = Lexer::from_bytes(b"a\xf0\x9d\x84", &id_table);
+    lexer.set_unicode_mode(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Other, SourceLocation::new(1), 1, NO_FLAGS, TokenData::NonCharByte(0xf0)),
+        (TokenKind::Other, SourceLocation::new(2), 1, NO_FLAGS, TokenData::NonCharByte(0x9d)),
+        (TokenKind::Other, SourceLocation::new(3), 1, NO_FLAGS, TokenData::NonCharByte(0x84)),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
 }
 
 #[test]
@@ -522,19 +628,68 @@ fn test_caret_notation_special_chars() {
     // ^^? -> ? is ASCII 63, 63+64=127 (DEL, ignored - no token)
     // ^^@ -> @ is ASCII 64, 64-64=0 (null, ignored - no token)
     // ^^! -> ! is ASCII 33, 33+64=97 ('a', letter)
+    // The ignored characters produce no tokens, so ^^! is still at the start of the line
     assert_tokens_match("^^?^^@^^!", &[
-        // Currently broken - caret notation not working at string boundaries
-        (TokenKind::Letter, SourceLocation::new(6), 3, START_OF_LINE, TokenData::Char('a')), // rest gets combined somehow
+        (TokenKind::Letter, SourceLocation::new(6), 3, START_OF_LINE, TokenData::Char('a')),
         (TokenKind::Eof, SourceLocation::new(9), 0, NO_FLAGS, TokenData::None),
     ]);
 }
 
+#[test]
+fn test_caret_notation_at_end_of_input() {
+    // Exactly three bytes remain for ^^X
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("x^^?".as_bytes(), &id_table);
+    lexer.set_emit_ignored(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::Unknown, SourceLocation::new(1), 3, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+    let mut lexer = Lexer::from_bytes("x^^@".as_bytes(), &id_table);
+    lexer.set_emit_ignored(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::Unknown, SourceLocation::new(1), 3, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+    assert_tokens_match("x^^!", &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::Letter, SourceLocation::new(1), 3, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Exactly four bytes remain for ^^ab
+    assert_tokens_match("x^^41", &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::Letter, SourceLocation::new(1), 4, NO_FLAGS, TokenData::Char('A')),
+        (TokenKind::Eof, SourceLocation::new(5), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_caret_notation_mid_buffer() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("x^^?y^^@z^^!.".as_bytes(), &id_table);
+    lexer.set_emit_ignored(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::Unknown, SourceLocation::new(1), 3, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(4), 1, NO_FLAGS, TokenData::Char('y')),
+        (TokenKind::Unknown, SourceLocation::new(5), 3, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(8), 1, NO_FLAGS, TokenData::Char('z')),
+        (TokenKind::Letter, SourceLocation::new(9), 3, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::Other, SourceLocation::new(12), 1, NO_FLAGS, TokenData::Char('.')),
+        (TokenKind::Eof, SourceLocation::new(13), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
 #[test]
 fn test_caret_notation_hex() {
     assert_tokens_match("^^0f^^1A^^fF", &[
         (TokenKind::Other, SourceLocation::new(0), 4, START_OF_LINE, TokenData::Char(char::from(15))),
         (TokenKind::Other, SourceLocation::new(4), 4, NO_FLAGS, TokenData::Char(char::from(26))),
-        (TokenKind::Other, SourceLocation::new(8), 4, NO_FLAGS, TokenData::Char(char::from(255))),
+        (TokenKind::Other, SourceLocation::new(8), 4, NO_FLAGS, TokenData::NonCharByte(255)),
         (TokenKind::Eof, SourceLocation::new(12), 0, NO_FLAGS, TokenData::None),
     ]);
 }
@@ -566,7 +721,7 @@ fn test_caret_notation_generating_space() {
 fn test_carriage_return_newline_handling() {
     assert_tokens_match("a\r\nb", &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
-        (TokenKind::Space, SourceLocation::new(1), 2, NO_FLAGS, TokenData::None), // \r\n -> space
+        (TokenKind::Space, SourceLocation::new(1), 2, SOFT_BREAK, TokenData::None), // \r\n -> space
         (TokenKind::Letter, SourceLocation::new(3), 1, START_OF_LINE, TokenData::Char('b')),
         (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
     ]);
@@ -576,7 +731,7 @@ fn test_carriage_return_newline_handling() {
 fn test_carriage_return_alone() {
     assert_tokens_match("a\rb", &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
-        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None),
         (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')),
         (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
     ]);
@@ -631,10 +786,28 @@ fn test_comment_end_of_file() {
     ]);
 }
 
+#[test]
+fn test_comment_at_start_of_file() {
+    // The comment on the first line produces no token; the next line starts as usual
+    assert_tokens_match("%header\nreal", &[
+        (TokenKind::Letter, SourceLocation::new(8), 1, START_OF_LINE, TokenData::Char('r')),
+        (TokenKind::Letter, SourceLocation::new(9), 1, NO_FLAGS, TokenData::Char('e')),
+        (TokenKind::Letter, SourceLocation::new(10), 1, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::Letter, SourceLocation::new(11), 1, NO_FLAGS, TokenData::Char('l')),
+        (TokenKind::Eof, SourceLocation::new(12), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Leading spaces of the next line are skipped too
+    assert_tokens_match("%\n  a", &[
+        (TokenKind::Letter, SourceLocation::new(4), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Eof, SourceLocation::new(5), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
 #[test]
 fn test_caret_notation_producing_letters() {
     assert_tokens_match("^^aa", &[
-        (TokenKind::Other, SourceLocation::new(0), 4, START_OF_LINE, TokenData::Char(char::from(170))),
+        (TokenKind::Other, SourceLocation::new(0), 4, START_OF_LINE, TokenData::NonCharByte(170)),
         (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
     ]);
 }
@@ -643,7 +816,7 @@ fn test_caret_notation_producing_letters() {
 fn test_multiple_carriage_returns() {
     assert_tokens_match("a\r\r\rb", &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
-        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None), // first \r
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None), // first \r
         (TokenKind::Paragraph, SourceLocation::new(2), 1, START_OF_LINE, TokenData::None), // first \r + second \r -> paragraph
         (TokenKind::Paragraph, SourceLocation::new(3), 1, START_OF_LINE, TokenData::None), // second \r + third \r -> paragraph
         (TokenKind::Letter, SourceLocation::new(4), 1, START_OF_LINE, TokenData::Char('b')),
@@ -702,6 +875,26 @@ fn test_caret_notation_del_char() {
     ]);
 }
 
+#[test]
+fn test_del_char_category_code() {
+    // A literal DEL is ignored like ^^?
+    assert_tokens_match("a\u{7f}b", &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Both forms follow the category code of DEL when it's changed
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("^^?\u{7f}".as_bytes(), &id_table);
+    lexer.set_category_code(MaybeChar::from_char('\u{7f}'), CategoryCode::Other);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Other, SourceLocation::new(0), 3, START_OF_LINE, TokenData::Char('\u{7f}')),
+        (TokenKind::Other, SourceLocation::new(3), 1, NO_FLAGS, TokenData::Char('\u{7f}')),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
 #[test]
 fn test_custom_category_codes() {
     // Test custom category codes with active character
@@ -772,12 +965,12 @@ fn test_custom_newline_character() {
     lexer.set_category_code(MaybeChar::from_char('\n'), CategoryCode::Other);
     assert_tokens_match_with_lexer(&mut lexer, &[
         (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
-        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None),
         // Everything between | and \r is discarded
         (TokenKind::Letter, SourceLocation::new(4), 1, START_OF_LINE, TokenData::Char('c')),
-        (TokenKind::Space, SourceLocation::new(5), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(5), 1, SOFT_BREAK, TokenData::None),
         (TokenKind::Letter, SourceLocation::new(8), 1, START_OF_LINE, TokenData::Char('e')),
-        (TokenKind::Space, SourceLocation::new(9), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(9), 1, SOFT_BREAK, TokenData::None),
         (TokenKind::Paragraph, SourceLocation::new(13), 1, START_OF_LINE, TokenData::None), // \r\n| -> paragraph
         // Everything between % and \r is considered comment text
         (TokenKind::Letter, SourceLocation::new(38), 1, START_OF_LINE, TokenData::Char('g')),
@@ -794,7 +987,7 @@ fn test_spaces_before_eol_skipped() {
         (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('r')),
         (TokenKind::Letter, SourceLocation::new(3), 1, NO_FLAGS, TokenData::Char('d')),
         // 3 spaces before \n are skipped - no space token generated
-        (TokenKind::Space, SourceLocation::new(7), 1, NO_FLAGS, TokenData::None), // \n becomes space token
+        (TokenKind::Space, SourceLocation::new(7), 1, SOFT_BREAK, TokenData::None), // \n becomes space token
         (TokenKind::Letter, SourceLocation::new(8), 1, START_OF_LINE, TokenData::Char('t')),
         (TokenKind::Letter, SourceLocation::new(9), 1, NO_FLAGS, TokenData::Char('e')),
         (TokenKind::Letter, SourceLocation::new(10), 1, NO_FLAGS, TokenData::Char('x')),
@@ -883,3 +1076,976 @@ fn test_control_word_with_caret_notation_letter_in_middle() {
         (TokenKind::Eof, SourceLocation::new(18), 0, NO_FLAGS, TokenData::None),
     ]);
 }
+
+#[test]
+fn test_tokens_on_line() {
+    let id_table = CommandIdentifierTable::new();
+    let mut sm = SourceManager::new();
+    let file_id = sm.add_buffer(MemoryBuffer::from_str("ab\n\\cd e\nf", "lines.tex".to_string()), None);
+
+    let mut lexer = Lexer::from_memory_buffer(sm.get_buffer_data(file_id).unwrap(), &id_table);
+    let mut tokens = Vec::new();
+    lexer.tokens_on_line(2, &sm, file_id, &mut tokens);
+
+    let actual: Vec<_> = tokens.iter().map(|token| (token.kind(), token.location(), token.flags())).collect();
+    assert_eq!(actual, vec![
+        (TokenKind::ControlWord, SourceLocation::new(3), START_OF_LINE),
+        (TokenKind::Letter, SourceLocation::new(7), NO_FLAGS),
+        (TokenKind::Space, SourceLocation::new(8), SOFT_BREAK), // line terminator belongs to the line
+    ]);
+    assert_eq!(tokens[0].command_identifier().as_bytes(), b"cd");
+    assert_eq!(tokens[1].char(), 'e');
+
+    // Lines out of range collect nothing
+    let mut tokens = Vec::new();
+    lexer.tokens_on_line(4, &sm, file_id, &mut tokens);
+    assert!(tokens.is_empty());
+}
+
+#[test]
+fn test_mid_file_byte_order_mark() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\u{FEFF}b".as_bytes(), &command_identifier_table);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        // The byte order mark is skipped
+        (TokenKind::Letter, SourceLocation::new(4), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(5), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    assert_eq!(lexer.diagnostics().len(), 1);
+    assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::MidFileByteOrderMark);
+    assert_eq!(lexer.diagnostics()[0].location(), SourceLocation::new(1));
+}
+
+#[test]
+fn test_leading_byte_order_mark() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\u{FEFF}a".as_bytes(), &command_identifier_table);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(3), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+    assert!(lexer.diagnostics().is_empty());
+}
+
+#[test]
+fn test_next_token_matches_lex() {
+    let input = "\\foo{a b}%comment\n#1 ^^A\\\\\n\nx";
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &id_table);
+    let mut reference_lexer = Lexer::from_bytes(input.as_bytes(), &id_table);
+
+    let mut token = Token::default();
+    loop {
+        reference_lexer.lex(&mut token);
+        let next_token = lexer.next_token();
+
+        assert_eq!(format!("{next_token:?}"), format!("{token:?}"));
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+    }
+}
+
+#[test]
+fn test_custom_endline_char() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\nb\r\nc".as_bytes(), &command_identifier_table);
+    lexer.set_endline_char(Some(MaybeChar::from_char('+')));
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Other, SourceLocation::new(1), 1, NO_FLAGS, TokenData::Char('+')),
+        (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')),
+        (TokenKind::Other, SourceLocation::new(3), 2, NO_FLAGS, TokenData::Char('+')),
+        (TokenKind::Letter, SourceLocation::new(5), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_custom_endline_char_with_comment() {
+    // The comment discards the rest of the line including the line break, so no end-of-line character is produced
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a%comment\nb  \nc".as_bytes(), &command_identifier_table);
+    lexer.set_endline_char(Some(MaybeChar::from_char('+')));
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Letter, SourceLocation::new(10), 1, START_OF_LINE, TokenData::Char('b')),
+        // Trailing spaces before the line break are still dropped
+        (TokenKind::Other, SourceLocation::new(13), 1, NO_FLAGS, TokenData::Char('+')),
+        (TokenKind::Letter, SourceLocation::new(14), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(15), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_no_endline_char() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\nb%comment\n\nc".as_bytes(), &command_identifier_table);
+    lexer.set_endline_char(None);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')),
+        // Neither the comment line nor the empty line produce a space or a paragraph
+        (TokenKind::Letter, SourceLocation::new(13), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(14), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_par_blank_line_threshold() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\n\nb\n\n\nc".as_bytes(), &command_identifier_table);
+    lexer.set_par_blank_line_threshold(2);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None),
+        // A single blank line doesn't end the paragraph
+        (TokenKind::Letter, SourceLocation::new(3), 1, START_OF_LINE, TokenData::Char('b')),
+        (TokenKind::Space, SourceLocation::new(4), 1, SOFT_BREAK, TokenData::None),
+        // The second of two blank lines does
+        (TokenKind::Paragraph, SourceLocation::new(6), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(7), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(8), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_par_blank_line_threshold_long_run() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\n\n\n\n\nb".as_bytes(), &command_identifier_table);
+    lexer.set_par_blank_line_threshold(2);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None),
+        // A run of four blank lines ends the paragraph once, at its second line
+        (TokenKind::Paragraph, SourceLocation::new(3), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(6), 1, START_OF_LINE, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(7), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_control_symbol_is_transformed() {
+    let id_table = CommandIdentifierTable::new();
+
+    let mut lexer = Lexer::from_bytes("\\^^@".as_bytes(), &id_table);
+    let token = lexer.next_token();
+    assert_eq!(token.kind(), TokenKind::ControlSymbol);
+    assert_eq!(token.length(), 4);
+    assert_eq!(token.symbol(), Some(MaybeChar::from_char('\0')));
+    assert!(token.symbol_is_transformed());
+
+    let mut lexer = Lexer::from_bytes("\\{".as_bytes(), &id_table);
+    let token = lexer.next_token();
+    assert_eq!(token.kind(), TokenKind::ControlSymbol);
+    assert!(!token.symbol_is_transformed());
+}
+
+#[test]
+fn test_is_spacing_command() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\\,\\;\\!\\ \\quad\\qquad\\relax\\{\\quads,".as_bytes(), &command_identifier_table);
+
+    let mut classification = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        classification.push(token.is_spacing_command());
+    }
+
+    assert_eq!(classification, [true, true, true, true, true, true, false, false, false, false]);
+}
+
+#[test]
+fn test_lex_into_stream() {
+    let input = "\\foo{a b}%comment\n#1 ^^A\\\\\n\nx";
+    let id_table = CommandIdentifierTable::new();
+    let stream: TokenStream = Lexer::from_bytes(input.as_bytes(), &id_table).lex_into_stream();
+
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &id_table);
+    let mut token = Token::default();
+    let mut index = 0;
+    loop {
+        lexer.lex(&mut token);
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        assert_eq!(stream.kind(index), token.kind());
+        assert_eq!(stream.location(index), token.location());
+        assert_eq!(stream.length(index), token.length());
+        assert_eq!(stream.flags(index), token.flags());
+        index += 1;
+    }
+    assert_eq!(stream.len(), index);
+    assert_eq!(stream.kinds().len(), stream.offsets().len());
+    assert_eq!(stream.lengths().len(), stream.offsets().len());
+}
+
+#[test]
+fn test_rebind() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("x[".as_bytes(), &command_identifier_table);
+    lexer.set_category_code(MaybeChar::from_char('['), CategoryCode::BeginGroup);
+    lexer.set_category_code(MaybeChar::from_char(']'), CategoryCode::EndGroup);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('x')),
+        (TokenKind::BeginGroup, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(2), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    lexer.rebind("  [a]".as_bytes(), 100);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::BeginGroup, SourceLocation::new(102), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(103), 1, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::EndGroup, SourceLocation::new(104), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(105), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_token_rewriter() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\\emph{a}\\textbf".as_bytes(), &id_table);
+    let (emph, textit) = (id_table.get_or_insert(b"emph"), id_table.get_or_insert(b"textit"));
+    lexer.set_token_rewriter(Box::new(move |token: &mut Token| {
+        if token.is(TokenKind::ControlWord) && token.command_identifier() == emph {
+            token.set_token_data(TokenData::CommandIdentifier(textit));
+        }
+    }));
+
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 5, START_OF_LINE, TokenData::CommandIdentifier(textit)),
+        (TokenKind::BeginGroup, SourceLocation::new(5), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(6), 1, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::EndGroup, SourceLocation::new(7), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::ControlWord, SourceLocation::new(8), 7, NO_FLAGS, TokenData::CommandIdentifier(id_table.get_or_insert(b"textbf"))),
+        (TokenKind::Eof, SourceLocation::new(15), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_with_all_other() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("{\\x{".as_bytes(), &command_identifier_table);
+
+    let verbatim = lexer.with_all_other(|lexer| {
+        [lexer.next_token(), lexer.next_token(), lexer.next_token()].map(|token| (token.kind(), token.char()))
+    });
+    assert_eq!(verbatim, [(TokenKind::Other, '{'), (TokenKind::Other, '\\'), (TokenKind::Other, 'x')]);
+
+    assert_eq!(lexer.next_token().kind(), TokenKind::BeginGroup);
+}
+
+#[test]
+fn test_plain_text_mode() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("${x}$".as_bytes(), &id_table);
+    lexer.set_plain_text_mode(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Other, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('$')),
+        (TokenKind::Other, SourceLocation::new(1), 1, NO_FLAGS, TokenData::Char('{')),
+        (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('x')),
+        (TokenKind::Other, SourceLocation::new(3), 1, NO_FLAGS, TokenData::Char('}')),
+        (TokenKind::Other, SourceLocation::new(4), 1, NO_FLAGS, TokenData::Char('$')),
+        (TokenKind::Eof, SourceLocation::new(5), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Control sequences are kept, and comments are read as text
+    let mut lexer = Lexer::from_bytes("\\emph 5%~".as_bytes(), &id_table);
+    lexer.set_plain_text_mode(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 5, START_OF_LINE, TokenData::CommandIdentifier(id_table.get_or_insert(b"emph"))),
+        (TokenKind::Other, SourceLocation::new(6), 1, NO_FLAGS, TokenData::Char('5')),
+        (TokenKind::Other, SourceLocation::new(7), 1, NO_FLAGS, TokenData::Char('%')),
+        (TokenKind::Other, SourceLocation::new(8), 1, NO_FLAGS, TokenData::Char('~')),
+        (TokenKind::Eof, SourceLocation::new(9), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    lexer.set_plain_text_mode(false);
+    assert_eq!(lexer.category_code(MaybeChar::from_char('%')), CategoryCode::Comment);
+    assert_eq!(lexer.category_code(MaybeChar::from_char('{')), CategoryCode::BeginGroup);
+
+    // The category codes are restored even if the table was replaced or its groups changed in the meantime
+    let mut lexer = Lexer::from_bytes("".as_bytes(), &id_table);
+    lexer.set_category_code(MaybeChar::from_char('@'), CategoryCode::Letter);
+    lexer.set_plain_text_mode(true);
+    lexer.set_category_code_table(CategoryCodeTable::new());
+    lexer.set_plain_text_mode(false);
+    assert_eq!(lexer.category_code(MaybeChar::from_char('@')), CategoryCode::Letter);
+    assert_eq!(lexer.category_code(MaybeChar::from_char('$')), CategoryCode::MathShift);
+}
+
+#[test]
+fn test_obey_spaces() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a  b".as_bytes(), &id_table);
+    lexer.set_obey_spaces(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(2), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(3), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Spaces at the start and end of a line and after a control word are kept too
+    let mut lexer = Lexer::from_bytes(" \\a\t\n".as_bytes(), &id_table);
+    lexer.set_obey_spaces(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Space, SourceLocation::new(0), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::ControlWord, SourceLocation::new(1), 2, NO_FLAGS, TokenData::CommandIdentifier(id_table.get_or_insert(b"a"))),
+        (TokenKind::Space, SourceLocation::new(3), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(4), 1, SOFT_BREAK, TokenData::None),
+        (TokenKind::Eof, SourceLocation::new(5), 0, START_OF_LINE, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_strict_mode_errors() {
+    const ERROR: TokenFlags = TokenFlags::ERROR;
+    let mut start_of_line_error = START_OF_LINE;
+    start_of_line_error.set(ERROR);
+    let command_identifier_table = CommandIdentifierTable::new();
+
+    let mut lexer = Lexer::from_bytes("\\".as_bytes(), &command_identifier_table);
+    lexer.set_strict(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlSymbol, SourceLocation::new(0), 1, start_of_line_error, TokenData::Symbol(None)),
+        (TokenKind::Eof, SourceLocation::new(1), 0, NO_FLAGS, TokenData::None),
+    ]);
+    assert!(lexer.had_errors());
+    assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::EscapeCharAtEndOfInput);
+
+    // Caret notation cut off by the end of the input or of a line, or missing the second hex digit at the end of the
+    // input
+    for input in ["^^", "a^^\nb", "^^\r\n", "a ^^f"] {
+        let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+        lexer.set_strict(true);
+        while lexer.next_token().is_not(TokenKind::Eof) {}
+        assert!(lexer.had_errors(), "{input:?}");
+        assert_eq!(lexer.diagnostics().len(), 1, "{input:?}");
+        assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::IncompleteCaretNotation);
+        assert_eq!(lexer.diagnostics()[0].location(), SourceLocation::new(input.find('^').unwrap() as u32));
+    }
+    for input in ["^^ab", "^^A", "^^f\n", "^^?b"] {
+        let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+        lexer.set_strict(true);
+        while lexer.next_token().is_not(TokenKind::Eof) {}
+        assert!(!lexer.had_errors(), "{input:?}");
+    }
+
+    let mut lexer = Lexer::from_bytes("a^^?b".as_bytes(), &command_identifier_table);
+    lexer.set_category_code(MaybeChar::from_char('\u{7f}'), CategoryCode::Invalid);
+    lexer.set_strict(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Unknown, SourceLocation::new(1), 3, ERROR, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(4), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(5), 0, NO_FLAGS, TokenData::None),
+    ]);
+    assert!(lexer.had_errors());
+    assert_eq!(lexer.diagnostics()[0].kind(), &DiagnosticKind::InvalidCharacter);
+
+    // Outside of strict mode the same input is accepted silently, including DEL with category code Invalid
+    for input in ["\\", "^^", "a^^?b", "a\u{7f}b"] {
+        let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+        lexer.set_category_code(MaybeChar::from_char('\u{7f}'), CategoryCode::Invalid);
+        while lexer.next_token().is_not(TokenKind::Eof) {}
+        assert!(!lexer.had_errors(), "{input:?}");
+    }
+}
+
+#[test]
+fn test_discarded_byte_count() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let input = "\u{7f}a\u{7f}\u{7f} \u{7f}b^^?c@\0";
+
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+    lexer.set_category_code(MaybeChar::from_char('@'), CategoryCode::Invalid);
+    lexer.set_collect_stats(true);
+    while lexer.next_token().is_not(TokenKind::Eof) {}
+    // 4 DEL bytes, a DEL in caret notation (3 bytes), an invalid `@` and a NUL
+    assert_eq!(lexer.discarded_byte_count(), 4 + 3 + 1 + 1);
+
+    // Not counted unless statistics are collected
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+    while lexer.next_token().is_not(TokenKind::Eof) {}
+    assert_eq!(lexer.discarded_byte_count(), 0);
+}
+
+#[test]
+fn test_from_bytes_with_config() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let config = LexerConfig {
+        endline_char: Some(MaybeChar::from_char('+')),
+        skip_spaces: false,
+        par_blank_line_threshold: 1,
+        strict: true,
+        emit_ignored: false,
+        preserve_leading_spaces: false,
+        span_space_runs: false,
+        tab_width: 1,
+        collect_stats: true,
+        unicode_mode: false,
+        obey_spaces: false,
+    };
+    let mut lexer = Lexer::from_bytes_with_config(" a\n\u{7f}\\".as_bytes(), &command_identifier_table, config);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Space);
+    assert_eq!(lexer.next_token().char(), 'a');
+    assert_eq!(lexer.next_token().char(), '+');
+    assert_eq!(lexer.next_token().kind(), TokenKind::ControlSymbol);
+    assert!(lexer.had_errors());
+    assert_eq!(lexer.discarded_byte_count(), 1);
+
+    let config = LexerConfig { par_blank_line_threshold: 2, ..LexerConfig::default() };
+    let mut lexer = Lexer::from_bytes_with_config("a\n\nb\n\n\nc".as_bytes(), &command_identifier_table, config);
+    let mut kinds = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        kinds.push(token.kind());
+    }
+    assert_eq!(kinds, [
+        TokenKind::Letter, TokenKind::Space, TokenKind::Letter, TokenKind::Space, TokenKind::Paragraph,
+        TokenKind::Letter,
+    ]);
+}
+
+#[test]
+fn test_is_line_break_command() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\\\\b\\{\\c".as_bytes(), &command_identifier_table);
+
+    let mut classification = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        classification.push((token.kind(), token.is_line_break_command()));
+    }
+
+    assert_eq!(classification, [
+        (TokenKind::Letter, false),
+        (TokenKind::ControlSymbol, true),
+        (TokenKind::Letter, false),
+        (TokenKind::ControlSymbol, false),
+        (TokenKind::ControlWord, false),
+    ]);
+}
+
+#[test]
+fn test_emit_ignored() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a\u{7f}b\n\0 c".as_bytes(), &command_identifier_table);
+    lexer.set_emit_ignored(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Unknown, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Space, SourceLocation::new(3), 1, SOFT_BREAK, TokenData::None),
+        (TokenKind::Unknown, SourceLocation::new(4), 1, NO_FLAGS, TokenData::None),
+        // Spaces at the start of the line are still skipped after the ignored character
+        (TokenKind::Letter, SourceLocation::new(6), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(7), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_column_with_tab_width() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\tx\na\tb\tc".as_bytes(), &command_identifier_table);
+    lexer.set_tab_width(8);
+
+    let x = lexer.next_token();
+    assert_eq!(x.char(), 'x');
+    assert_eq!(lexer.column(x.location()), Some(9));
+
+    let mut columns = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        if token.is(TokenKind::Letter) {
+            columns.push((token.char(), lexer.column(token.location()).unwrap()));
+        }
+    }
+    assert_eq!(columns, [('a', 1), ('b', 9), ('c', 17)]);
+
+    // Byte-accurate columns by default
+    let lexer = Lexer::from_bytes("\tx".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.column(SourceLocation::new(1)), Some(2));
+    assert_eq!(lexer.column(SourceLocation::new(3)), None);
+
+    // A tab width of 0 from the configuration is clamped like one passed to set_tab_width
+    let config = LexerConfig { tab_width: 0, ..LexerConfig::default() };
+    let lexer = Lexer::from_bytes_with_config("\tx".as_bytes(), &command_identifier_table, config);
+    assert_eq!(lexer.column(SourceLocation::new(1)), Some(2));
+}
+
+#[test]
+fn test_soft_break_flag() {
+    assert_tokens_match("a\nb\n\nc", &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None), // single newline
+        (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('b')),
+        (TokenKind::Space, SourceLocation::new(3), 1, SOFT_BREAK, TokenData::None),
+        (TokenKind::Paragraph, SourceLocation::new(4), 1, START_OF_LINE, TokenData::None), // blank line
+        (TokenKind::Letter, SourceLocation::new(5), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // A literal space is not a soft break
+    assert_tokens_match("a b", &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_preserve_leading_spaces() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("  a".as_bytes(), &command_identifier_table);
+    lexer.set_preserve_leading_spaces(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Space, SourceLocation::new(0), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(1), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(2), 1, NO_FLAGS, TokenData::Char('a')),
+        (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Skipped by default
+    assert_tokens_match("  a", &[
+        (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Eof, SourceLocation::new(3), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // A line of spaces is still blank, and spaces after the start of a line are collapsed as usual
+    let mut lexer = Lexer::from_bytes("a\n  \n b  c".as_bytes(), &command_identifier_table);
+    lexer.set_preserve_leading_spaces(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 1, SOFT_BREAK, TokenData::None),
+        (TokenKind::Paragraph, SourceLocation::new(4), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Space, SourceLocation::new(5), 1, START_OF_LINE, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(6), 1, NO_FLAGS, TokenData::Char('b')),
+        (TokenKind::Space, SourceLocation::new(7), 1, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(9), 1, NO_FLAGS, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(10), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // What follows the spaces is checked again after a category code change in the middle of the run
+    let mut lexer = Lexer::from_bytes("  %".as_bytes(), &command_identifier_table);
+    lexer.set_preserve_leading_spaces(true);
+    lexer.set_category_code(MaybeChar::from_char('%'), CategoryCode::Other);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Space);
+    lexer.set_category_code(MaybeChar::from_char('%'), CategoryCode::Comment);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Eof);
+}
+
+#[test]
+fn test_caret_hex_non_ascii_byte() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("^^ff^^e9^^41".as_bytes(), &command_identifier_table);
+
+    let token = lexer.next_token();
+    assert_eq!(token.kind(), TokenKind::Other);
+    assert_eq!(token.length(), 4);
+    assert_eq!(token.maybe_char(), MaybeChar::from_non_char_byte(0xFF));
+
+    let token = lexer.next_token();
+    assert_eq!(token.maybe_char(), MaybeChar::from_non_char_byte(0xE9));
+
+    // ASCII stays a character
+    let token = lexer.next_token();
+    assert_eq!(token.kind(), TokenKind::Letter);
+    assert_eq!(token.maybe_char(), MaybeChar::from_char('A'));
+}
+
+#[test]
+fn test_logical_chars() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    // `^^Ab` is read as hex caret notation
+    let lexer = Lexer::from_bytes("a^^Ab".as_bytes(), &command_identifier_table);
+    let chars: Vec<_> = lexer.logical_chars().collect();
+    assert_eq!(chars, vec![
+        (SourceLocation::new(0), MaybeChar::from_char('a'), 1),
+        (SourceLocation::new(1), MaybeChar::from_non_char_byte(0xAB), 4),
+    ]);
+
+    let lexer = Lexer::from_bytes("^^Az\r\nc".as_bytes(), &command_identifier_table);
+    let chars: Vec<_> = lexer.logical_chars().collect();
+    assert_eq!(chars, vec![
+        (SourceLocation::new(0), MaybeChar::from_char('\u{1}'), 3),
+        (SourceLocation::new(3), MaybeChar::from_char('z'), 1),
+        (SourceLocation::new(4), MaybeChar::from_char('\r'), 2),
+        (SourceLocation::new(6), MaybeChar::from_char('c'), 1),
+    ]);
+}
+
+#[test]
+fn test_span_space_runs() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("a    b\t \nc  ".as_bytes(), &command_identifier_table);
+    lexer.set_span_space_runs(true);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(0), 1, START_OF_LINE, TokenData::Char('a')),
+        (TokenKind::Space, SourceLocation::new(1), 4, NO_FLAGS, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(5), 1, NO_FLAGS, TokenData::Char('b')),
+        // Spaces before the end of a line are still dropped
+        (TokenKind::Space, SourceLocation::new(8), 1, SOFT_BREAK, TokenData::None),
+        (TokenKind::Letter, SourceLocation::new(9), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Eof, SourceLocation::new(12), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_scan_dimension() {
+    let command_identifier_table = CommandIdentifierTable::new();
+
+    let mut lexer = Lexer::from_bytes("3pt".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), Some((3.0, DimensionUnit::Pt)));
+    assert_eq!(lexer.next_token().kind(), TokenKind::Eof);
+
+    let mut lexer = Lexer::from_bytes("1.5in x".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), Some((1.5, DimensionUnit::In)));
+    let token = lexer.next_token();
+    assert_eq!(token.char(), 'x');
+    assert_eq!(token.location(), SourceLocation::new(6));
+
+    let mut lexer = Lexer::from_bytes(" - -,5 CM".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), Some((0.5, DimensionUnit::Cm)));
+
+    let mut lexer = Lexer::from_bytes("-.25em".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), Some((-0.25, DimensionUnit::Em)));
+
+    // Nothing is consumed if there's no dimension
+    let mut lexer = Lexer::from_bytes("3px".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), None);
+    assert_eq!(lexer.next_token().char(), '3');
+
+    let mut lexer = Lexer::from_bytes("pt".as_bytes(), &command_identifier_table);
+    assert_eq!(lexer.scan_dimension(), None);
+    assert_eq!(lexer.next_token().char(), 'p');
+}
+
+#[test]
+fn test_token_at() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let input = "ab \\foo  c\n";
+    let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        let is_eof = token.is(TokenKind::Eof);
+        tokens.push(token);
+        if is_eof {
+            break;
+        }
+    }
+
+    assert_eq!(token_at(&tokens, SourceLocation::new(0)).map(|token| token.char()), Some('a'));
+    assert_eq!(token_at(&tokens, SourceLocation::new(1)).map(|token| token.char()), Some('b'));
+    // In the middle of the control word
+    let token = token_at(&tokens, SourceLocation::new(5)).unwrap();
+    assert!(token.is_control_word(b"foo"));
+    assert_eq!(token.location(), SourceLocation::new(3));
+    // Spaces skipped after the control word belong to no token
+    assert!(token_at(&tokens, SourceLocation::new(8)).is_none());
+    assert_eq!(token_at(&tokens, SourceLocation::new(9)).map(|token| token.char()), Some('c'));
+    // Past the end of the input
+    assert!(token_at(&tokens, SourceLocation::new(input.len() as u32)).is_none());
+    assert!(token_at(&tokens, SourceLocation::new(100)).is_none());
+    assert!(token_at(&[], SourceLocation::new(0)).is_none());
+}
+
+#[test]
+fn test_active_char() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("@a~^^ff".as_bytes(), &command_identifier_table);
+    lexer.set_category_code(MaybeChar::from_char('@'), CategoryCode::Active);
+    lexer.set_category_code(MaybeChar::from_non_char_byte(0xFF), CategoryCode::Active);
+
+    assert_eq!(lexer.next_token().active_char(), Some(MaybeChar::from_char('@')));
+    assert_eq!(lexer.next_token().active_char(), None);
+    assert_eq!(lexer.next_token().active_char(), Some(MaybeChar::from_char('~')));
+    assert_eq!(lexer.next_token().active_char(), Some(MaybeChar::from_non_char_byte(0xFF)));
+}
+
+#[test]
+fn test_catcode_schedule() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\\a@ \\a@".as_bytes(), &command_identifier_table);
+    lexer.set_catcode_schedule(vec![(3, MaybeChar::from_char('@'), CategoryCode::Letter)]);
+
+    // `@` is still [CategoryCode::Other] before offset 3
+    let token = lexer.next_token();
+    assert!(token.is_control_word(b"a"));
+    let token = lexer.next_token();
+    assert_eq!(token.kind(), TokenKind::Other);
+    assert_eq!(token.char(), '@');
+    assert_eq!(lexer.next_token().kind(), TokenKind::Space);
+
+    let token = lexer.next_token();
+    assert!(token.is_control_word(b"a@"));
+    assert_eq!(token.location(), SourceLocation::new(4));
+    assert_eq!(lexer.category_code(MaybeChar::from_char('@')), CategoryCode::Letter);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Eof);
+
+    // Changes at the same offset are applied in order
+    let mut lexer = Lexer::from_bytes("@@".as_bytes(), &command_identifier_table);
+    lexer.set_catcode_schedule(vec![
+        (1, MaybeChar::from_char('@'), CategoryCode::Active),
+        (0, MaybeChar::from_char('@'), CategoryCode::Letter),
+        (1, MaybeChar::from_char('@'), CategoryCode::Other),
+    ]);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Letter);
+    assert_eq!(lexer.next_token().kind(), TokenKind::Other);
+}
+
+#[test]
+fn test_control_word_ended_by_line_break() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let id_ab = command_identifier_table.get_or_insert(b"ab");
+
+    // The line break ends the control word and is skipped like a space after it
+    let mut lexer = Lexer::from_bytes("\\ab\ncd".as_bytes(), &command_identifier_table);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 3, START_OF_LINE, TokenData::CommandIdentifier(id_ab)),
+        (TokenKind::Letter, SourceLocation::new(4), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Letter, SourceLocation::new(5), 1, NO_FLAGS, TokenData::Char('d')),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Like spaces, the end of the line produces no space token after a control word or a control space, but does
+    // after other control symbols
+    for (input, expected_kinds) in [
+        ("\\ab  \ncd", &[TokenKind::ControlWord, TokenKind::Letter, TokenKind::Letter][..]),
+        ("\\ \ncd", &[TokenKind::ControlSymbol, TokenKind::Letter, TokenKind::Letter][..]),
+        ("\\,\ncd", &[TokenKind::ControlSymbol, TokenKind::Space, TokenKind::Letter, TokenKind::Letter][..]),
+    ] {
+        let mut lexer = Lexer::from_bytes(input.as_bytes(), &command_identifier_table);
+        let kinds: Vec<_> = std::iter::from_fn(|| Some(lexer.next_token()))
+            .take_while(|token| token.is_not(TokenKind::Eof))
+            .map(|token| token.kind())
+            .collect();
+        assert_eq!(kinds, expected_kinds, "{input:?}");
+    }
+
+    // A line break doesn't continue a control word even with the category code of a letter
+    let mut lexer = Lexer::from_bytes("\\ab\ncd".as_bytes(), &command_identifier_table);
+    lexer.set_endline_char(None);
+    lexer.set_category_code(MaybeChar::from_char('\n'), CategoryCode::Letter);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(0), 3, START_OF_LINE, TokenData::CommandIdentifier(id_ab)),
+        (TokenKind::Letter, SourceLocation::new(4), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Letter, SourceLocation::new(5), 1, NO_FLAGS, TokenData::Char('d')),
+        (TokenKind::Eof, SourceLocation::new(6), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // As in TeX, an end-of-line character that is a letter is part of the control word
+    let mut lexer = Lexer::from_bytes("\\ab\ncd".as_bytes(), &command_identifier_table);
+    lexer.set_endline_char(Some(MaybeChar::from_char('x')));
+    let token = lexer.next_token();
+    assert!(token.is_control_word(b"abx"));
+    assert_eq!(token.length(), 4);
+    let token = lexer.next_token();
+    assert_eq!(token.char(), 'c');
+    assert!(token.at_start_of_line());
+}
+
+#[test]
+fn test_token_char_length() {
+    let command_identifier_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("^^Az\\f^^6fo\\^^M#1\r\n".as_bytes(), &command_identifier_table);
+    lexer.set_category_code(MaybeChar::from_char('\u{1}'), CategoryCode::Other);
+
+    let lengths: Vec<_> = std::iter::from_fn(|| Some(lexer.next_token()))
+        .map(|token| (token.kind(), token.length(), token.char_length()))
+        .take(7)
+        .collect();
+    assert_eq!(lengths, vec![
+        (TokenKind::Other, 3, 1),
+        (TokenKind::Letter, 1, 1),
+        (TokenKind::ControlWord, 7, 4),
+        (TokenKind::ControlSymbol, 4, 2),
+        (TokenKind::Parameter, 2, 2),
+        (TokenKind::Space, 2, 1),
+        (TokenKind::Eof, 0, 0),
+    ]);
+}
+
+#[test]
+fn test_lex_to_strings() {
+    let to_pairs = |pairs: &[(TokenKind, &str)]| {
+        pairs.iter().map(|(kind, spelling)| (*kind, spelling.to_string())).collect::<Vec<_>>()
+    };
+
+    assert_eq!(lex_to_strings("\\foo{a}"), to_pairs(&[
+        (TokenKind::ControlWord, "foo"),
+        (TokenKind::BeginGroup, "{"),
+        (TokenKind::Letter, "a"),
+        (TokenKind::EndGroup, "}"),
+    ]));
+    assert_eq!(lex_to_strings("\\%#1 ^^ff"), to_pairs(&[
+        (TokenKind::ControlSymbol, "%"),
+        (TokenKind::Parameter, "#1"),
+        (TokenKind::Space, " "),
+        (TokenKind::Other, "^^ff"),
+    ]));
+    assert!(lex_to_strings("").is_empty());
+}
+
+#[test]
+fn test_collect_control_words() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("\\a \\b \\a".as_bytes(), &id_table);
+    let identifiers = lexer.collect_control_words();
+    assert_eq!(identifiers.len(), 2);
+    assert_eq!(identifiers[0], id_table.get_or_insert(b"a"));
+    assert_eq!(identifiers[1], id_table.get_or_insert(b"b"));
+
+    // Active characters are collected too; control symbols aren't
+    let mut lexer = Lexer::from_bytes("~\\%x\\c~".as_bytes(), &id_table);
+    lexer.set_category_code(MaybeChar::from_char('~'), CategoryCode::Active);
+    let identifiers = lexer.collect_control_words();
+    assert_eq!(identifiers, [id_table.get_or_insert(b"~"), id_table.get_or_insert(b"c")]);
+}
+
+#[test]
+fn test_from_bytes_window() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes_window(b"abcdef", 2, 4, 0, &id_table);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::Letter, SourceLocation::new(2), 1, START_OF_LINE, TokenData::Char('c')),
+        (TokenKind::Letter, SourceLocation::new(3), 1, NO_FLAGS, TokenData::Char('d')),
+        (TokenKind::Eof, SourceLocation::new(4), 0, NO_FLAGS, TokenData::None),
+    ]);
+
+    // Locations are relative to the base offset of the whole input
+    let mut lexer = Lexer::from_bytes_window(b"ab\\cd ef", 2, 6, 100, &id_table);
+    assert_tokens_match_with_lexer(&mut lexer, &[
+        (TokenKind::ControlWord, SourceLocation::new(102), 3, START_OF_LINE, TokenData::CommandIdentifier(id_table.get_or_insert(b"cd"))),
+        (TokenKind::Eof, SourceLocation::new(106), 0, NO_FLAGS, TokenData::None),
+    ]);
+}
+
+#[test]
+fn test_raw_source_len() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes("^^A^^41a\r\nb".as_bytes(), &id_table);
+
+    let token = lexer.next_token();
+    assert_eq!((token.kind(), token.maybe_char()), (TokenKind::Other, MaybeChar::from_u8(1)));
+    assert_eq!(token.raw_source_len(), 3);
+    let token = lexer.next_token();
+    assert_eq!((token.kind(), token.char(), token.raw_source_len()), (TokenKind::Letter, 'A', 4));
+    let token = lexer.next_token();
+    assert_eq!((token.kind(), token.char(), token.raw_source_len()), (TokenKind::Letter, 'a', 1));
+    let token = lexer.next_token();
+    assert_eq!((token.kind(), token.raw_source_len()), (TokenKind::Space, 2));
+    assert!(token.has_flag(SOFT_BREAK));
+    let token = lexer.next_token();
+    assert_eq!((token.char(), token.raw_source_len()), ('b', 1));
+}
+
+#[test]
+fn test_raw_bytes() {
+    let id_table = CommandIdentifierTable::new();
+    let mut sm = SourceManager::new();
+    sm.add_buffer(MemoryBuffer::from_str("x", "first.tex".to_string()), None);
+    let file_id = sm.add_buffer(MemoryBuffer::from_str("\\emph{^^41}  x\r\n", "second.tex".to_string()), None);
+
+    let file = sm.get_file(file_id).unwrap();
+    let input = file.buffer.data();
+    let mut lexer = Lexer::from_bytes_window(input, 0, input.len(), file.start_offset, &id_table);
+    lexer.set_file_id(Some(file_id));
+    let mut raw_bytes = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        raw_bytes.push(token.raw_bytes(&sm));
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+    }
+    let expected: [&[u8]; 8] = [b"\\emph", b"{", b"^^41", b"}", b" ", b"x", b"\r\n", b""];
+    assert_eq!(raw_bytes, expected.map(Some));
+
+    assert_eq!(Token::default().raw_bytes(&sm), None);
+}
+
+#[test]
+fn test_lex_buffers_dropped_before_table() {
+    // One table outlives the short-lived buffers it's used to lex
+    let id_table = CommandIdentifierTable::new();
+    let mut names = Vec::new();
+    for input in ["\\alpha", "\\beta x"] {
+        let buffer = input.as_bytes().to_vec();
+        let token = Lexer::from_bytes(&buffer, &id_table).next_token();
+        names.push(token.command_identifier());
+    }
+    assert_eq!(names, [id_table.get_or_insert(b"alpha"), id_table.get_or_insert(b"beta")]);
+}
+
+#[test]
+fn test_split_into_chunks() {
+    let input = b"ab\ncd\r\nef\rgh\n\n\\ij kl\r\nm";
+    let chunks = split_into_chunks(input, 4);
+    assert_eq!(chunks, [(0, 7), (7, 13), (13, 22), (22, 23)]);
+
+    // The chunks cover the input in order and end at line boundaries without splitting "\r\n"
+    for size in 1..=input.len() + 1 {
+        let chunks = split_into_chunks(input, size);
+        assert_eq!(chunks.first().map(|chunk| chunk.0), Some(0));
+        assert_eq!(chunks.last().map(|chunk| chunk.1), Some(input.len()));
+        for window in chunks.windows(2) {
+            let ((_, end), (start, _)) = (window[0], window[1]);
+            assert_eq!(end, start);
+            assert!(matches!(input[end - 1], b'\r' | b'\n'));
+            assert!(!(input[end - 1] == b'\r' && input[end] == b'\n'));
+        }
+    }
+    assert!(split_into_chunks(b"", 4).is_empty());
+
+    // Lexing the chunks separately gives the tokens of the whole input
+    let id_table = CommandIdentifierTable::new();
+    let collect = |lexer: &mut Lexer, tokens: &mut Vec<_>| loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        tokens.push((token.kind(), token.location(), token.length(), token.flags()));
+    };
+    let mut expected = Vec::new();
+    collect(&mut Lexer::from_bytes(input, &id_table), &mut expected);
+    let mut actual = Vec::new();
+    for (start, end) in split_into_chunks(input, 4) {
+        collect(&mut Lexer::from_bytes_window(input, start, end, 0, &id_table), &mut actual);
+    }
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_category_code_table() {
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes(b"", &id_table);
+    assert!(lexer.category_code_table().snapshot().is_empty());
+
+    lexer.set_category_code(MaybeChar::from_char('@'), CategoryCode::Letter);
+    lexer.set_category_code(MaybeChar::from_char('~'), CategoryCode::Other);
+    let table = lexer.category_code_table();
+    assert_eq!(table.get(MaybeChar::from_char('@')), CategoryCode::Letter);
+    assert_eq!(table.snapshot(), [
+        (MaybeChar::from_char('@'), CategoryCode::Letter),
+        (MaybeChar::from_char('~'), CategoryCode::Other),
+    ]);
+}
@@ -0,0 +1,1164 @@
+use retex_lex::{DiagnosticKind, Lexer, Preprocessor, Token, TokenFlags, TokenKind};
+use retex_lex::command_identifier::CommandIdentifierTable;
+use retex_lex::CategoryCode;
+use retex_base::{MaybeChar, MemoryBuffer, SourceLocation, SourceManager, FileId};
+use retex_lex::preprocessor::{ConditionalKind, TraceEvent};
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+fn add_buffer(sm: &mut SourceManager, input: &str) -> FileId {
+    sm.add_buffer(MemoryBuffer::from_str(input, "test.tex".to_string()), None)
+}
+
+#[test]
+fn test_scan_number_absorbs_relax() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "5\\relax x");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert_eq!(pp.scan_number(), Some(5));
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Letter);
+    assert_eq!(token.char(), 'x');
+}
+
+#[test]
+fn test_scan_number_preserves_next_token() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "5x");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert_eq!(pp.scan_number(), Some(5));
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Letter);
+    assert_eq!(token.char(), 'x');
+}
+
+#[test]
+fn test_scan_number_signs_and_space() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, " - -+42 \\relax");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert_eq!(pp.scan_number(), Some(42));
+
+    // Only the first terminator is absorbed
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::ControlWord);
+    assert_eq!(token.command_identifier().as_bytes(), b"relax");
+}
+
+#[test]
+fn test_scan_number_missing_digits() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "- x");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert_eq!(pp.scan_number(), None);
+
+    // Nothing is consumed, including the sign and the space
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), '-');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Space);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'x');
+}
+
+#[test]
+fn test_consume_if_optional_equals() {
+    let is_equals = |token: &Token| token.is(TokenKind::Other) && token.char() == '=';
+
+    for input in ["=5", "5"] {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+
+        let equals = pp.consume_if(is_equals);
+        assert_eq!(equals.is_some(), input.starts_with('='));
+        // The number is read either way
+        assert_eq!(pp.scan_number(), Some(5));
+    }
+}
+
+#[test]
+fn test_included_file_starts_at_start_of_line() {
+    let included_input = "   \n\n  c d";
+
+    // Lex the input as a top-level file for reference, located after the main file "ab" as in the source manager
+    let mut reference = Vec::new();
+    let id_table = CommandIdentifierTable::new();
+    let mut lexer = Lexer::from_bytes_window(included_input.as_bytes(), 0, included_input.len(), 2, &id_table);
+    loop {
+        let token = lexer.next_token();
+        if token.is(TokenKind::Eof) {
+            break;
+        }
+        reference.push((token.kind(), token.location(), token.flags()));
+    }
+
+    let mut sm = SourceManager::new();
+    let main_file_id = add_buffer(&mut sm, "ab");
+    let included_file_id = add_buffer(&mut sm, included_input);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(main_file_id);
+
+    // Enter the included file in the middle of a line of the main file
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'a');
+    pp.enter_file(included_file_id);
+
+    let mut included = Vec::new();
+    for _ in 0..reference.len() {
+        assert!(pp.lex(&mut token));
+        included.push((token.kind(), token.location(), token.flags()));
+    }
+    assert_eq!(included, reference);
+
+    // Leading spaces and blank lines only produce paragraph breaks; the first real token starts a line
+    let first_letter = included.iter().find(|(kind, _, _)| *kind != TokenKind::Paragraph).unwrap();
+    assert_eq!(first_letter.0, TokenKind::Letter);
+    assert!(first_letter.2.has(TokenFlags::START_OF_LINE));
+}
+
+fn expand_to_string(input: &str) -> String {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, input);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut output = String::new();
+    let mut token = Token::default();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        match token.kind() {
+            TokenKind::Letter | TokenKind::Other => output.push(token.char()),
+            TokenKind::Space => output.push(' '),
+            TokenKind::BeginGroup => output.push('{'),
+            TokenKind::EndGroup => output.push('}'),
+            kind => panic!("unexpected token: {kind:?}"),
+        }
+    }
+    output
+}
+
+#[test]
+fn test_the_escapechar() {
+    assert_eq!(expand_to_string("\\the\\escapechar"), "92");
+    assert_eq!(expand_to_string("\\escapechar=92 \\the\\escapechar"), "92");
+    assert_eq!(expand_to_string("\\escapechar=-1 \\the\\escapechar"), "-1");
+    assert_eq!(expand_to_string("\\escapechar 64\\relax\\the\\escapechar"), "64");
+}
+
+#[test]
+fn test_the_endlinechar() {
+    assert_eq!(expand_to_string("\\the\\endlinechar"), "13");
+    assert_eq!(expand_to_string("\\endlinechar=-1 \\the\\endlinechar"), "-1");
+    // As in TeX, the assignment applies from the next line, so the current line keeps its line break
+    assert_eq!(expand_to_string("\\endlinechar=97\\relax\nb\n"), "ba");
+}
+
+#[test]
+fn test_let_implicit_character() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\let\\bgroup={\\let\\x= a{\\bgroup\\x}");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    assert!(!token.is_implicit_character());
+
+    // \bgroup is an implicit `{` located at the control word
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    assert!(token.is_implicit_character());
+    assert_eq!(token.location().offset(), 23);
+    assert_eq!(token.length(), 7);
+
+    // \x is an implicit letter `a` (the optional space after `=` is skipped)
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Letter);
+    assert_eq!(token.char(), 'a');
+    assert!(token.is_implicit_character());
+
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::EndGroup);
+    assert!(!token.is_implicit_character());
+}
+
+#[test]
+fn test_let_copies_meaning() {
+    // \b keeps the meaning \a had when \b was assigned
+    assert_eq!(expand_to_string("\\let\\a=x\\let\\b=\\a\\let\\a=y\\a\\b"), "yx");
+    // A command can be given the meaning of a primitive
+    assert_eq!(expand_to_string("\\let\\value=\\the\\value\\escapechar"), "92");
+}
+
+/// Creates an empty directory for a test under the system temporary directory
+fn test_directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("retex-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
+fn lex_letters<'source: 'pp, 'pp>(pp: &mut Preprocessor<'source, 'pp>) -> String {
+    let mut output = String::new();
+    let mut token = Token::default();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        if token.is(TokenKind::Letter) {
+            output.push(token.char());
+        }
+    }
+    output
+}
+
+#[test]
+fn test_input_relative_resolution() {
+    let directory = test_directory("input-relative");
+    std::fs::create_dir_all(directory.join("sub")).unwrap();
+    std::fs::write(directory.join("sub/a.tex"), "A\\input b A").unwrap();
+    std::fs::write(directory.join("sub/b.tex"), "B").unwrap();
+    std::fs::write(directory.join("b.tex"), "X").unwrap();
+
+    // Resolved relative to the including file
+    let mut sm = SourceManager::new();
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_relative_input_resolution(true);
+    pp.add_search_path(directory.clone());
+    pp.enter_main_file(directory.join("sub/a.tex")).unwrap();
+    assert_eq!(lex_letters(&mut pp), "ABA");
+    assert!(pp.diagnostics().is_empty());
+
+    // Without relative resolution only the search path has the file
+    let mut sm = SourceManager::new();
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.add_search_path(directory.clone());
+    pp.enter_main_file(directory.join("sub/a.tex")).unwrap();
+    assert_eq!(lex_letters(&mut pp), "AXA");
+
+    // Not found at all
+    let mut sm = SourceManager::new();
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_main_file(directory.join("sub/a.tex")).unwrap();
+    assert_eq!(lex_letters(&mut pp), "AA");
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::InputFileNotFound("b".to_string()));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn test_skip_until() {
+    let id_table = CommandIdentifierTable::new();
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "ab\\relax\\endcsname c\\if\\if x\\fi\\fi\\fi d");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert!(pp.skip_until(id_table.get_or_insert(b"endcsname"), None));
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'c');
+
+    // Nested \if ... \fi pairs are skipped as a whole
+    assert!(pp.skip_until(id_table.get_or_insert(b"fi"), Some(id_table.get_or_insert(b"if"))));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'd');
+
+    assert!(!pp.skip_until(id_table.get_or_insert(b"endcsname"), None));
+}
+
+#[test]
+fn test_string() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\string\\foo");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    for expected in ['\\', 'f', 'o', 'o'] {
+        assert!(pp.lex(&mut token));
+        assert_eq!(token.kind(), TokenKind::Other);
+        assert_eq!(token.char(), expected);
+    }
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+
+    assert_eq!(expand_to_string("\\escapechar=-1 \\string\\foo"), "foo");
+    assert_eq!(expand_to_string("\\escapechar=64 \\string\\{"), "@{");
+    assert_eq!(expand_to_string("\\string a\\string{\\string~"), "a{~");
+
+    // A token that doesn't carry its character is read in its own file, which may have ended
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\input b \\s");
+    sm.add_buffer(MemoryBuffer::from_str("\\def\\s{\\string^}", "b.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Other);
+    assert_eq!(token.char(), '^');
+}
+
+#[test]
+fn test_the_catcode_defaults() {
+    assert_eq!(expand_to_string("\\the\\catcode`0"), "12");
+    assert_eq!(expand_to_string("\\the\\catcode`a"), "11");
+    assert_eq!(expand_to_string("\\the\\catcode`\\%"), "14");
+    assert_eq!(expand_to_string("\\the\\catcode`\\^^M"), "5");
+    assert_eq!(expand_to_string("\\the\\catcode 955"), "12");
+
+    // The character of an alphabetic constant is read in its own file, which may have ended
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\input b \\c");
+    sm.add_buffer(MemoryBuffer::from_str("\\def\\c{\\the\\catcode`^}", "b.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), '7');
+}
+
+#[test]
+fn test_catcode_assignment() {
+    assert_eq!(expand_to_string("\\catcode`\\@=11 \\the\\catcode`\\@"), "11");
+    assert_eq!(expand_to_string("\\catcode`\\^=12 ^"), "^");
+}
+
+#[test]
+fn test_input_in_memory_buffer() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "a\\input macros b");
+    sm.add_buffer(MemoryBuffer::from_str("M", "macros.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    assert_eq!(lex_letters(&mut pp), "aMb");
+    assert!(pp.diagnostics().is_empty());
+}
+
+#[test]
+fn test_input_at_end_of_file() {
+    // `\input` ends the file without a final line break, so the end of the file is read while scanning the name
+    for input in ["xyz\\input b", "xyz\\input b "] {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        sm.add_buffer(MemoryBuffer::from_str("B", "b.tex".to_string()), None);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+        assert_eq!(lex_letters(&mut pp), "xyzB", "{input:?}");
+    }
+
+    // The same at the end of an included file: the tokens read ahead in the including file come after the file
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "a\\input b\nz");
+    sm.add_buffer(MemoryBuffer::from_str("b\\input c", "b.tex".to_string()), None);
+    sm.add_buffer(MemoryBuffer::from_str("c", "c.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    assert_eq!(lex_letters(&mut pp), "abcz");
+    assert!(pp.diagnostics().is_empty());
+}
+
+#[test]
+fn test_catcode_across_input() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\catcode`\\@=11 \\input inner \\make@title\\the\\catcode`\\!");
+    let inner = "\\def\\make@title{T}\\make@title\\catcode`\\!=11 ";
+    sm.add_buffer(MemoryBuffer::from_str(inner, "inner.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    // The included file reads `\make@title` as one control word, and the category code of `!` it sets stays after it
+    // ends
+    let mut output = String::new();
+    let mut token = Token::default();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        output.push(token.char());
+    }
+    assert_eq!(output, "TT11");
+    assert_eq!(pp.catcode_of(MaybeChar::from_char('!')), CategoryCode::Letter);
+    assert!(pp.diagnostics().is_empty());
+}
+
+#[test]
+fn test_read_group() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "{a{b}\\c}d{e");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let tokens = pp.read_group().unwrap();
+    let kinds: Vec<_> = tokens.iter().map(|token| token.kind()).collect();
+    assert_eq!(kinds, [TokenKind::Letter, TokenKind::BeginGroup, TokenKind::Letter, TokenKind::EndGroup,
+        TokenKind::ControlWord]);
+
+    // Not a group: the token is left unread
+    assert!(pp.read_group().is_none());
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'd');
+
+    // Unterminated group
+    assert!(pp.read_group().is_none());
+}
+
+#[test]
+fn test_toks_registers() {
+    assert_eq!(expand_to_string("\\toks5={ab}\\the\\toks5"), "ab");
+    assert_eq!(expand_to_string("\\toks5 = {a b}\\toks6{c}\\the\\toks6\\the\\toks5"), "ca b");
+    assert_eq!(expand_to_string("\\toks5={ab}\\toks5={}x\\the\\toks5\\the\\toks7"), "x");
+    // Register contents are read again, so commands in them are expanded
+    assert_eq!(expand_to_string("\\toks0={\\the\\escapechar}\\the\\toks0"), "92");
+}
+
+#[test]
+fn test_def_macros() {
+    assert_eq!(expand_to_string("\\def\\a{XY}\\a\\a"), "XYXY");
+    assert_eq!(expand_to_string("\\def\\swap#1#2{#2#1}\\swap a{bc}"), "bca");
+    assert_eq!(expand_to_string("\\def\\a#1.#2.{(#2#1)}\\a x.{y}."), "(yx)");
+    assert_eq!(expand_to_string("\\def\\a[#1]{#1}\\a[{]}]"), "]");
+    assert_eq!(expand_to_string("\\def\\a{\\def\\b##1{##1##1}}\\a\\b x"), "xx");
+    // \let copies the meaning of a macro
+    assert_eq!(expand_to_string("\\def\\a{x}\\let\\b=\\a\\def\\a{y}\\a\\b"), "yx");
+}
+
+#[test]
+fn test_macro_use_errors() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a[#1]{#1}\\a x\\a[y");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'x');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+
+    let kinds: Vec<_> = pp.diagnostics().iter().map(|diagnostic| diagnostic.kind().clone()).collect();
+    assert_eq!(kinds, [DiagnosticKind::MacroUseMismatch, DiagnosticKind::RunawayArgument]);
+}
+
+#[test]
+fn test_def_parameter_characters() {
+    // `##1` in the replacement text is a literal `#1` while `#1` is substituted
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a#1{##1#1}\\a x");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Parameter);
+    assert_eq!(token.parameter_index().map(|index| index.get()), Some(1));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'x');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+    assert!(pp.diagnostics().is_empty());
+
+    assert_eq!(expand_to_string("\\def\\a#1#2{#2#1#2}\\a xy"), "yxy");
+}
+
+#[test]
+fn test_def_illegal_parameter_number() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a#1{#2}\\def\\b{#x}\\def\\c{#0}\\def\\d{#}");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+
+    let locations: Vec<_> = pp.diagnostics().iter()
+        .map(|diagnostic| {
+            assert_eq!(diagnostic.kind(), &DiagnosticKind::IllegalParameterNumber);
+            diagnostic.location()
+        })
+        .collect();
+    assert_eq!(locations, [SourceLocation::new(9), SourceLocation::new(19), SourceLocation::new(29), SourceLocation::new(39)]);
+
+    // The parameter character is kept as if it were doubled
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a{#x}\\a");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Parameter);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'x');
+}
+
+#[test]
+fn test_def_parameter_numbering() {
+    let diagnostic_kinds = |input: &str| {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+        let mut token = Token::default();
+        while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {}
+        pp.diagnostics().iter().map(|diagnostic| diagnostic.kind().clone()).collect::<Vec<_>>()
+    };
+
+    assert!(diagnostic_kinds("\\def\\a#1#2.#3{}").is_empty());
+    assert_eq!(diagnostic_kinds("\\def\\a#1#3{}"), [DiagnosticKind::ParametersNotConsecutive]);
+    assert_eq!(diagnostic_kinds("\\def\\a#0{}"), [DiagnosticKind::ParametersNotConsecutive]);
+    assert_eq!(
+        diagnostic_kinds("\\def\\a#2#1{}"),
+        [DiagnosticKind::ParametersNotConsecutive, DiagnosticKind::ParametersNotConsecutive]
+    );
+    assert_eq!(diagnostic_kinds("\\def\\a#1#2#3#4#5#6#7#8#9#9{}"), [DiagnosticKind::TooManyParameters]);
+
+    // A misnumbered parameter is read as if numbered correctly; a tenth one is dropped
+    assert_eq!(expand_to_string("\\def\\a#1#3{#2#1}\\a xy"), "yx");
+    assert_eq!(expand_to_string("\\def\\a#1#2#3#4#5#6#7#8#9#1{#9}\\a 123456789"), "9");
+}
+
+#[test]
+fn test_runaway_definition() {
+    for (input, def_offset) in [("\\def\\foo#1", 0), ("ab\\def\\foo{abc", 2), ("ab\\global\\edef\\foo#1{a{b}", 9)] {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+        let mut token = Token::default();
+        while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {}
+        assert!(token.is(TokenKind::Eof));
+
+        assert_eq!(pp.diagnostics().len(), 1);
+        assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::RunawayDefinition);
+        assert_eq!(pp.diagnostics()[0].location(), SourceLocation::new(def_offset));
+    }
+}
+
+#[test]
+fn test_raw_bytes() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\let\\b={\\b\\string\\b");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    let mut token = Token::default();
+
+    // An implicit character keeps the source of the command
+    assert!(pp.lex(&mut token));
+    assert!(token.is(TokenKind::BeginGroup) && token.is_implicit_character());
+    let implicit = token.clone();
+
+    // The characters produced by `\string` weren't read from the input
+    assert!(pp.lex(&mut token));
+    assert_eq!(implicit.raw_bytes(pp.source_manager()), Some(&b"\\b"[..]));
+    assert_eq!((token.kind(), token.char()), (TokenKind::Other, '\\'));
+    assert_eq!(token.raw_bytes(pp.source_manager()), Some(&b""[..]));
+}
+
+#[test]
+fn test_raw_bytes_in_included_file() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "a\\input b \\c");
+    sm.add_buffer(MemoryBuffer::from_str("xy\\z", "b.tex".to_string()), None);
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    // The included file doesn't start at offset 0, and its tokens are located at its own offsets
+    let mut token = Token::default();
+    let mut raw_bytes = Vec::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        let bytes = token.raw_bytes(pp.source_manager()).map(|bytes| String::from_utf8_lossy(bytes).into_owned());
+        raw_bytes.push((token.location().offset(), bytes));
+    }
+    let expected = [(0, "a"), (12, "x"), (13, "y"), (14, "\\z"), (10, "\\c")];
+    assert_eq!(raw_bytes, expected.map(|(offset, bytes)| (offset, Some(bytes.to_string()))));
+}
+
+#[test]
+fn test_undefined_command() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "a\\undefinedmacro b\\relax\\def\\c{c}\\c");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_report_undefined_commands(true);
+    pp.enter_file(file_id);
+
+    // The undefined command is reported and output as it is; the stream continues after it
+    let mut token = Token::default();
+    let mut kinds = Vec::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        kinds.push(token.kind());
+    }
+    assert_eq!(kinds, [
+        TokenKind::Letter, TokenKind::ControlWord, TokenKind::Letter, TokenKind::ControlWord, TokenKind::Letter,
+    ]);
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::UndefinedCommand);
+    assert_eq!(pp.diagnostics()[0].location(), SourceLocation::new(1));
+}
+
+#[test]
+fn test_undefined_command_read_again() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\a\\b");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_report_undefined_commands(true);
+    pp.enter_file(file_id);
+
+    // Each command is reported once though both are read again after the other
+    let mut first = Token::default();
+    let mut second = Token::default();
+    assert!(pp.lex(&mut first));
+    assert!(pp.lex(&mut second));
+    pp.push_back(second);
+    pp.push_back(first);
+    let mut token = Token::default();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {}
+    let locations: Vec<_> = pp.diagnostics().iter().map(|diagnostic| diagnostic.location()).collect();
+    assert_eq!(locations, [SourceLocation::new(0), SourceLocation::new(2)]);
+}
+
+#[test]
+fn test_trace_hook() {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a#1{#1}\\iffalse x\\else\\a y\\fi");
+    let mut pp = Preprocessor::new(&mut sm);
+    let recorded = Rc::clone(&events);
+    pp.set_trace_hook(move |event| recorded.borrow_mut().push(event.clone()));
+    pp.enter_file(file_id);
+    assert_eq!(lex_letters(&mut pp), "y");
+
+    assert_eq!(*events.borrow(), [
+        TraceEvent::FileEntered { file_id },
+        TraceEvent::ConditionalTaken { kind: ConditionalKind::IfFalse, result: false },
+        TraceEvent::MacroExpanded { name: "a".to_string(), call_site: SourceLocation::new(27) },
+    ]);
+}
+
+#[test]
+fn test_macro_argument_skips_spaces() {
+    assert_eq!(expand_to_string("\\def\\foo#1{(#1)}\\foo  {x}"), "(x)");
+    assert_eq!(expand_to_string("\\def\\foo#1{(#1)}\\foo x"), "(x)");
+    assert_eq!(expand_to_string("\\def\\foo#1#2{(#1#2)}\\foo x {y}"), "(xy)");
+}
+
+#[test]
+fn test_macro_argument_par() {
+    let expand = |input: &str| {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+        let letters = lex_letters(&mut pp);
+        let kinds = pp.diagnostics().iter().map(|diagnostic| diagnostic.kind().clone()).collect::<Vec<_>>();
+        (letters, kinds)
+    };
+
+    // The paragraph ends before the argument of a macro that isn't \long; the text after it is read as usual
+    assert_eq!(expand("\\def\\foo#1{(#1)}\\foo\\par x"), ("x".to_string(), vec![DiagnosticKind::RunawayArgument]));
+    assert_eq!(expand("\\def\\foo#1{(#1)}\\foo\n\nx"), ("x".to_string(), vec![DiagnosticKind::RunawayArgument]));
+    assert_eq!(expand("\\def\\foo#1.{(#1)}\\foo a\\par b."), ("b".to_string(), vec![DiagnosticKind::RunawayArgument]));
+
+    // Arguments of \long macros can contain \par
+    assert_eq!(expand("\\long\\def\\foo#1{#1x}\\foo\\par"), ("x".to_string(), vec![]));
+    assert_eq!(expand("\\global\\long\\def\\foo#1.{#1}\\foo a\\par b."), ("ab".to_string(), vec![]));
+}
+
+#[test]
+fn test_afterassignment() {
+    // The token is read right after the assignment, including the space ending the number
+    assert_eq!(expand_to_string("\\def\\x{X}\\afterassignment\\x \\catcode`\\@=11 @"), "X@");
+    assert_eq!(expand_to_string("\\def\\x{X}\\afterassignment\\x a\\def\\y{Y}b\\y"), "aXbY");
+    // Only the next assignment is followed by the token
+    assert_eq!(expand_to_string("\\def\\x{X}\\afterassignment\\x\\let\\a=b\\let\\c=d"), "X");
+}
+
+#[test]
+fn test_aftergroup() {
+    assert_eq!(expand_to_string("\\def\\x{X}{\\aftergroup\\x a}b"), "{a}Xb");
+    // Tokens are read in the order they were saved, after the group they were saved in
+    assert_eq!(expand_to_string("\\def\\x{X}{\\aftergroup\\x\\aftergroup y{\\aftergroup z}a}"), "{{}za}Xy");
+    // Outside of groups the token is dropped
+    assert_eq!(expand_to_string("\\aftergroup ab"), "b");
+}
+
+#[test]
+fn test_csname() {
+    let expand = |input: &str| {
+        let mut sm = SourceManager::new();
+        let file_id = add_buffer(&mut sm, input);
+        let mut pp = Preprocessor::new(&mut sm);
+        pp.enter_file(file_id);
+        lex_letters(&mut pp)
+    };
+
+    assert_eq!(expand("\\def\\foo{F}\\csname foo\\endcsname"), "F");
+    assert_eq!(expand("\\def\\n{o}\\def\\foo{F}\\csname f\\n o\\endcsname"), "F");
+    assert_eq!(expand("\\def\\&{P}\\csname\\string&\\endcsname"), "P");
+
+    // \csname defines an undefined command as \relax, while \ifcsname leaves it undefined
+    assert_eq!(expand("\\csname bar\\endcsname\\ifdefined\\bar Y\\else N\\fi"), "Y");
+    assert_eq!(expand("\\csname bar\\endcsname\\ifx\\bar\\relax Y\\else N\\fi"), "Y");
+    assert_eq!(expand("\\ifcsname bar\\endcsname Y\\else N\\fi\\ifdefined\\bar Y\\else N\\fi"), "NN");
+    assert_eq!(expand("\\def\\bar{}\\ifcsname bar\\endcsname Y\\else N\\fi"), "Y");
+    assert_eq!(expand("\\ifcsname relax\\endcsname Y\\else N\\fi"), "Y");
+
+    // The \relax meaning is local to the group
+    assert_eq!(expand("{\\csname bar\\endcsname}\\ifdefined\\bar Y\\else N\\fi"), "N");
+}
+
+#[test]
+fn test_ifdefined() {
+    assert_eq!(expand_to_string("\\ifdefined\\undefined Y\\else N\\fi"), "N");
+    assert_eq!(expand_to_string("\\def\\a{}\\ifdefined\\a Y\\else N\\fi"), "Y");
+    assert_eq!(expand_to_string("\\ifdefined\\def Y\\else N\\fi"), "Y");
+    assert_eq!(expand_to_string("\\ifdefined xY\\else N\\fi"), "Y");
+}
+
+#[test]
+fn test_csname_missing_endcsname() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\ab{X}\\csname ab{c}");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+    assert_eq!(lex_letters(&mut pp), "Xc");
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::MissingEndCsname);
+    assert_eq!(pp.diagnostics()[0].location(), SourceLocation::new(20));
+}
+
+#[test]
+fn test_expand_once_trace() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\a\\b\\def\\a{XY}\\def\\b#1{#1\\a}\\relax z\\relax");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    // The commands are read before they are defined, so they aren't expanded
+    let mut a = Token::default();
+    assert!(pp.lex(&mut a));
+    assert!(a.is_control_word(b"a"));
+    let mut b = Token::default();
+    assert!(pp.lex(&mut b));
+    assert!(b.is_control_word(b"b"));
+
+    // Definitions are carried out while reading the next token
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert!(token.is_control_word(b"relax"));
+
+    // \a expands to its replacement text; the tokens are not read again
+    let expansion = pp.expand_once_trace(&a);
+    let chars: Vec<_> = expansion.iter().map(|token| token.char()).collect();
+    assert_eq!(chars, ['X', 'Y']);
+
+    // Only one level: \a in the expansion of \b is left as it is
+    let expansion = pp.expand_once_trace(&b);
+    assert_eq!(expansion.len(), 2);
+    assert_eq!(expansion[0].char(), 'z');
+    assert!(expansion[1].is_control_word(b"a"));
+
+    // Unexpandable tokens stand for themselves
+    assert!(pp.lex(&mut token));
+    let expansion = pp.expand_once_trace(&token);
+    assert_eq!(expansion.len(), 1);
+    assert!(expansion[0].is_control_word(b"relax"));
+}
+
+#[test]
+fn test_edef_expands_replacement_text() {
+    assert_eq!(expand_to_string("\\def\\foo{F}\\edef\\x{\\foo}\\def\\foo{G}\\x"), "F");
+    assert_eq!(expand_to_string("\\edef\\x#1{#1\\the\\escapechar}\\escapechar=64 \\x a"), "a92");
+    // Assignments are not carried out while expanding
+    assert_eq!(expand_to_string("\\def\\a{x}\\edef\\x{\\def\\noexpand\\a{y}}\\a\\x\\a"), "xy");
+    // The contents of a token list register are inserted without being expanded, including from a macro
+    assert_eq!(expand_to_string("\\def\\x{X}\\toks0={\\x}\\edef\\y{\\the\\toks0}\\def\\x{Z}\\y"), "Z");
+    assert_eq!(expand_to_string("\\def\\x{X}\\toks0={\\x}\\def\\t{\\the\\toks0}\\edef\\y{\\t}\\def\\x{Z}\\y"), "Z");
+    // Outside of \edef, the contents are read again and expanded
+    assert_eq!(expand_to_string("\\def\\x{X}\\toks0={\\x}\\the\\toks0"), "X");
+}
+
+#[test]
+fn test_noexpand_in_edef() {
+    // \noexpand\foo stores \foo rather than its expansion
+    assert_eq!(expand_to_string("\\def\\foo{F}\\edef\\x{\\noexpand\\foo}\\def\\foo{G}\\x"), "G");
+    assert_eq!(expand_to_string("\\def\\foo{F}\\edef\\x{\\noexpand\\foo\\foo}\\def\\foo{G}\\x"), "GF");
+}
+
+#[test]
+fn test_noexpand_on_unexpandable_token() {
+    assert_eq!(expand_to_string("\\noexpand x"), "x");
+    assert_eq!(expand_to_string("\\let\\y=z\\noexpand\\y"), "z");
+    assert_eq!(expand_to_string("\\edef\\x{\\noexpand a}\\x"), "a");
+}
+
+#[test]
+fn test_noexpand_outside_edef() {
+    // Only the next token is kept from expansion; it's returned as the command itself
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a{x}\\noexpand\\a\\a");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert!(token.is_control_word(b"a"));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Letter);
+    assert_eq!(token.char(), 'x');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+}
+
+#[test]
+fn test_catcode_of() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\catcode`\\@=11 x");
+    let mut pp = Preprocessor::new(&mut sm);
+    assert_eq!(pp.catcode_of(MaybeChar::from_char('@')), CategoryCode::Other);
+
+    pp.enter_file(file_id);
+    assert_eq!(pp.catcode_of(MaybeChar::from_char('@')), CategoryCode::Other);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'x');
+    assert_eq!(pp.catcode_of(MaybeChar::from_char('@')), CategoryCode::Letter);
+    assert_eq!(pp.catcode_of(MaybeChar::from_char('\\')), CategoryCode::Escape);
+}
+
+#[test]
+fn test_ifx() {
+    assert_eq!(expand_to_string("\\ifx aaT\\else F\\fi"), "T");
+    assert_eq!(expand_to_string("\\ifx abT\\else F\\fi"), "F");
+    assert_eq!(expand_to_string("\\ifx abT\\fi x"), "x");
+    assert_eq!(expand_to_string("\\def\\a{x}\\def\\b{x}\\def\\c{y}\\ifx\\a\\b T\\fi\\ifx\\a\\c\\else F\\fi"), "TF");
+    // Nested conditionals in a skipped branch are skipped as a whole
+    assert_eq!(expand_to_string("\\ifx ab\\ifx aaX\\else Y\\fi Z\\else W\\fi"), "W");
+    assert_eq!(expand_to_string("\\ifx aa\\ifx abX\\else Y\\fi Z\\else W\\fi"), "YZ");
+}
+
+#[test]
+fn test_ifx_let_aliases() {
+    assert_eq!(expand_to_string("\\def\\b{x}\\let\\a=\\b\\ifx\\a\\b T\\else F\\fi"), "T");
+    assert_eq!(expand_to_string("\\let\\a=x\\ifx\\a xT\\else F\\fi"), "T");
+    assert_eq!(expand_to_string("\\let\\a=\\ifx\\ifx\\a\\ifx T\\else F\\fi"), "T");
+    // Aliases of undefined commands are undefined, so a cycle of \let's doesn't loop
+    assert_eq!(expand_to_string("\\let\\a=\\b\\let\\b=\\a\\ifx\\a\\b T\\else F\\fi"), "T");
+    assert_eq!(expand_to_string("\\let\\a=\\b\\let\\b=\\a\\ifx\\a\\undefined T\\else F\\fi"), "T");
+}
+
+#[test]
+fn test_endlinechar_minus_one_suppresses_line_ends() {
+    // The assignment applies from the next line, so the line it is on still ends with a space
+    assert_eq!(expand_to_string("\\endlinechar=-1 a\nb\r\nc"), "a bc");
+    assert_eq!(expand_to_string("\\endlinechar=-1\\relax a\n\nb"), "a b");
+    // Restoring the default applies from the next line too
+    assert_eq!(expand_to_string("\\endlinechar=-1 a\n\\endlinechar=13 b\nc"), "a bc");
+    assert_eq!(expand_to_string("\\endlinechar=-1 a\n\\endlinechar=13 b\nc\nd"), "a bc d");
+}
+
+#[test]
+fn test_process_conditionals() {
+    assert_eq!(expand_to_string("\\iftrue a\\else b\\fi"), "a");
+    assert_eq!(expand_to_string("\\iffalse a\\else b\\fi"), "b");
+
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\iftrue a\\else b\\fi");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_process_conditionals(false);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert!(token.is_control_word(b"iftrue"));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'a');
+    assert!(pp.lex(&mut token));
+    assert!(token.is_control_word(b"else"));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'b');
+    assert!(pp.lex(&mut token));
+    assert!(token.is_control_word(b"fi"));
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Eof);
+}
+
+#[test]
+fn test_format_include_stack() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("a\n\n\\input b x", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    sm.add_buffer(MemoryBuffer::from_str("b\n\\input c\n", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    sm.add_buffer(MemoryBuffer::from_str("c", "c.tex".to_string()), Some(PathBuf::from("c.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    assert_eq!(pp.format_include_stack(), "");
+    pp.enter_file(main_file_id);
+    assert_eq!(pp.format_include_stack(), "");
+
+    let mut token = Token::default();
+    for expected in ['a', 'b', 'c'] {
+        while pp.lex(&mut token) && token.is_not(TokenKind::Letter) {}
+        assert_eq!(token.char(), expected);
+    }
+    assert_eq!(pp.format_include_stack(), "In file included from b.tex:2:\n                 from a.tex:3:\n");
+
+    // Back in the main file after both included files end
+    while pp.lex(&mut token) && token.is_not(TokenKind::Letter) {}
+    assert_eq!(token.char(), 'x');
+    assert_eq!(pp.format_include_stack(), "");
+}
+
+#[test]
+fn test_include_chain() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("a \\input b\n", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    let included_file_id = sm.add_buffer(MemoryBuffer::from_str("b", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(main_file_id);
+    assert_eq!(pp.include_chain(), vec![(main_file_id, SourceLocation::invalid())]);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'a');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::Space);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'b');
+    // The included file is recorded at the location of `\input`
+    let input_location = SourceLocation::new(2);
+    assert_eq!(pp.include_chain(), vec![(main_file_id, SourceLocation::invalid()), (included_file_id, input_location)]);
+}
+
+#[test]
+fn test_token_file_id() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("a\\input b c", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    let included_file_id = sm.add_buffer(MemoryBuffer::from_str("b", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(main_file_id);
+
+    let mut token = Token::default();
+    let mut letters = Vec::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        if token.is(TokenKind::Letter) {
+            letters.push((token.char(), token.file_id()));
+        }
+    }
+    assert_eq!(letters, [('a', Some(main_file_id)), ('b', Some(included_file_id)), ('c', Some(main_file_id))]);
+}
+
+#[test]
+fn test_implicit_character_file_id() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("\\let\\x=a\\input b \\x", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    let included_file_id = sm.add_buffer(MemoryBuffer::from_str("\\x", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(main_file_id);
+
+    // The implicit character is in the file of the command, not of the `\let`
+    let mut token = Token::default();
+    let mut letters = Vec::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        assert!(token.is_implicit_character());
+        letters.push((token.char(), token.file_id()));
+    }
+    assert_eq!(letters, [('a', Some(included_file_id)), ('a', Some(main_file_id))]);
+}
+
+#[test]
+fn test_the_and_string_file_id() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("\\input b \\string\\y", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    let included = "\\the\\catcode`a\\string\\x";
+    let included_file_id = sm.add_buffer(MemoryBuffer::from_str(included, "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(main_file_id);
+
+    let mut token = Token::default();
+    let mut chars = Vec::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        chars.push((token.char(), token.file_id()));
+    }
+    let b = Some(included_file_id);
+    let a = Some(main_file_id);
+    assert_eq!(chars, [('1', b), ('1', b), ('\\', b), ('x', b), ('\\', a), ('y', a)]);
+}
+
+#[test]
+fn test_max_file_size() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("\\input b\n\\input c\n", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    sm.add_buffer(MemoryBuffer::from_str("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    sm.add_buffer(MemoryBuffer::from_str("c", "c.tex".to_string()), Some(PathBuf::from("c.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_max_file_size(Some(20));
+    pp.enter_file(main_file_id);
+
+    // b.tex is skipped but c.tex is read
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'c');
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::FileTooLarge("b.tex".to_string()));
+    assert_eq!(pp.diagnostics()[0].location(), SourceLocation::new(0));
+
+    // The main file is checked too
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "abc");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.set_max_file_size(Some(2));
+    pp.enter_file(file_id);
+    assert_eq!(pp.current_file_id(), None);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::FileTooLarge("test.tex".to_string()));
+}
+
+#[test]
+fn test_source_budget() {
+    let mut sm = SourceManager::new();
+    let main_file_id = sm.add_buffer(MemoryBuffer::from_str("\\input b \\input b \\input b x", "a.tex".to_string()), Some(PathBuf::from("a.tex")));
+    sm.add_buffer(MemoryBuffer::from_str("bbbbbbbbbb", "b.tex".to_string()), Some(PathBuf::from("b.tex")));
+    let mut pp = Preprocessor::new(&mut sm);
+    // Room for the main file (28 bytes) and two copies of b.tex
+    pp.set_source_budget(Some(50));
+    pp.enter_file(main_file_id);
+
+    let mut text = String::new();
+    let mut token = Token::default();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        if token.is(TokenKind::Letter) {
+            text.push(token.char());
+        }
+    }
+    assert_eq!(text, "bbbbbbbbbbbbbbbbbbbbx");
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::SourceBudgetExceeded("b.tex".to_string()));
+    assert_eq!(pp.diagnostics()[0].location(), SourceLocation::new(18));
+}
+
+#[test]
+fn test_global_and_local_definitions() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\z{Z}{\\def\\x{X}\\global\\def\\y{Y}\\def\\z{z}\\x\\z}\\x\\y\\z");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    let mut output = String::new();
+    while pp.lex(&mut token) && token.is_not(TokenKind::Eof) {
+        match token.kind() {
+            TokenKind::Letter => output.push(token.char()),
+            TokenKind::BeginGroup => output.push('{'),
+            TokenKind::EndGroup => output.push('}'),
+            // \x is undefined after the group
+            TokenKind::ControlWord => output.push_str(&token.command_identifier().as_utf8().unwrap()),
+            kind => panic!("unexpected token {kind:?}"),
+        }
+    }
+    assert_eq!(output, "{Xz}xYZ");
+
+    // Nested groups, \let and \global\let
+    assert_eq!(expand_to_string("\\let\\a=A\\let\\b=B{\\let\\a=a{\\global\\let\\b=b\\a}\\a}\\a\\b"), "{{a}a}Ab");
+    // \global applies to \edef and expandable tokens after it are expanded
+    assert_eq!(expand_to_string("\\def\\g{\\global}{\\g\\edef\\a{x}}\\a"), "{}x");
+}
+
+#[test]
+fn test_local_assignments_to_internal_quantities() {
+    // \catcode, \toks, \escapechar and \endlinechar are restored at the end of the group
+    assert_eq!(expand_to_string("{\\catcode`\\@=11 \\the\\catcode`\\@}\\the\\catcode`\\@"), "{11}12");
+    assert_eq!(expand_to_string("\\toks1={a}{\\toks1={b}\\toks2={c}\\the\\toks1}\\the\\toks1\\the\\toks2"), "{b}a");
+    assert_eq!(expand_to_string("{\\escapechar=64 \\escapechar=65 }\\the\\escapechar"), "{}92");
+    // The line after the assignment is read without a line end, the one after the group with one again
+    assert_eq!(expand_to_string("{\\endlinechar=-1 a\nb}c\nd\ne"), "{a b}cd e");
+    // Nested groups restore the value of the enclosing group
+    assert_eq!(expand_to_string("{\\escapechar=64 {\\escapechar=65 }\\the\\escapechar}"), "{{}64}");
+
+    // \global assignments persist after the group, including groups that assigned them locally before
+    assert_eq!(expand_to_string("{\\global\\catcode`\\@=11 }\\the\\catcode`\\@"), "{}11");
+    assert_eq!(expand_to_string("{\\toks1={a}{\\global\\toks1={b}}}\\the\\toks1"), "{{}}b");
+    assert_eq!(expand_to_string("{\\escapechar=64 \\global\\escapechar=65 }\\the\\escapechar"), "{}65");
+    assert_eq!(expand_to_string("{\\global\\endlinechar=-1 }a\nb\nc"), "{}a bc");
+}
+
+#[test]
+fn test_group_token_read_again() {
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\x{X}{\\def\\x{Y}}\\x");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    // Pushing back the group tokens doesn't begin or end another group
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    pp.push_back(token.clone());
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::EndGroup);
+    pp.push_back(token.clone());
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::EndGroup);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'X');
+}
+
+#[test]
+fn test_futurelet() {
+    // \next means `{` while `\a{X` remain to be read
+    let mut sm = SourceManager::new();
+    let file_id = add_buffer(&mut sm, "\\def\\a{}\\futurelet\\next\\a{X}\\next");
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.enter_file(file_id);
+
+    let mut token = Token::default();
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    assert!(!token.is_implicit_character());
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.char(), 'X');
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::EndGroup);
+    assert!(pp.lex(&mut token));
+    assert_eq!(token.kind(), TokenKind::BeginGroup);
+    assert!(token.is_implicit_character());
+
+    // Lookahead with \ifx; the token in between is read before the token looked at
+    assert_eq!(expand_to_string("\\futurelet\\next ab\\ifx\\next bT\\else F\\fi"), "abT");
+    assert_eq!(expand_to_string("\\def\\a{\\futurelet\\next\\b}\\def\\b{\\ifx\\next xT\\else F\\fi}\\a x\\a y"), "TxFy");
+}
+
+#[test]
+fn test_input_tex_extension_fallback() {
+    let directory = test_directory("input-extension");
+    std::fs::write(directory.join("foo.tex"), "F").unwrap();
+    std::fs::write(directory.join("bar"), "B").unwrap();
+    std::fs::write(directory.join("bar.tex"), "X").unwrap();
+    std::fs::write(directory.join("main.tex"), "\\input foo \\input bar \\input foo.tex \\input baz ").unwrap();
+
+    // `foo` resolves to foo.tex, while the literal name wins when both exist
+    let mut sm = SourceManager::new();
+    let mut pp = Preprocessor::new(&mut sm);
+    pp.add_search_path(directory.clone());
+    pp.enter_main_file(directory.join("main.tex")).unwrap();
+    assert_eq!(lex_letters(&mut pp), "FBF");
+    assert_eq!(pp.diagnostics().len(), 1);
+    assert_eq!(pp.diagnostics()[0].kind(), &DiagnosticKind::InputFileNotFound("baz".to_string()));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}